@@ -0,0 +1,43 @@
+#![feature(test)]
+extern crate test;
+extern crate roadsim2dlib;
+
+use roadsim2dlib::*;
+use test::Bencher;
+
+fn query_points() -> Vec<Vec2f32> {
+    (0..100).map(|i| {
+        let t = i as f32 * 0.37;
+        Vec2f32::new(400.0 * t.cos() * (i as f32 / 100.0), 400.0 * t.sin() * (i as f32 / 100.0))
+    }).collect()
+}
+
+#[bench]
+fn find_free_space_close_to_indexed(b: &mut Bencher) {
+    let gridmap = make_random_town_gridmap(1);
+    let points = query_points();
+    b.iter(|| {
+        for point in &points {
+            test::black_box(find_free_space_close_to(&gridmap, *point));
+        }
+    });
+}
+
+#[bench]
+fn find_free_space_close_to_without_index(b: &mut Bencher) {
+    let gridmap = make_random_town_gridmap(1);
+    let points = query_points();
+    b.iter(|| {
+        for point in &points[..5] {
+            test::black_box(find_free_space_close_to_unindexed(&gridmap, *point));
+        }
+    });
+}
+
+#[bench]
+fn build_nearest_free_index(b: &mut Bencher) {
+    let mut gridmap = make_random_town_gridmap(1);
+    b.iter(|| {
+        gridmap.build_nearest_free_index();
+    });
+}
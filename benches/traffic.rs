@@ -0,0 +1,69 @@
+#![feature(test)]
+extern crate test;
+extern crate roadsim2dlib;
+
+use roadsim2dlib::*;
+use test::Bencher;
+use std::time::Instant;
+
+const VEHICLES : usize = 2000;
+// one update at 60 Hz, a step has to take less than this to keep up with real time
+const DT : f64 = 1.0 / 60.0;
+
+fn make_city_traffic() -> TrafficState {
+    let params = TownGeneratorParams {
+        layout: TownLayout::Grid,
+        size: 2000.0,
+        resolution: 2.0,
+        ..TownGeneratorParams::default()
+    };
+    let (network, _) = generate_town(&params, 1);
+    let traffic_params = TrafficParams {vehicles: VEHICLES, ..TrafficParams::default()};
+    TrafficState::new(LaneGraph::from_road_network(&network), traffic_params, 1)
+}
+
+// what TrafficSys and the physics step do in one update
+fn traffic_update(traffic: &mut TrafficState, physics_world: &mut PhysicsWorld) {
+    physics_world.set_timestep(DT);
+    physics_world.step();
+    traffic.step(DT);
+    traffic.update_proxies(physics_world);
+}
+
+#[bench]
+fn traffic_step_2000_vehicles(b: &mut Bencher) {
+    let mut traffic = make_city_traffic();
+    let mut physics_world = PhysicsWorld::new();
+    assert!(traffic.agents.len() >= VEHICLES);
+    traffic.update_proxies(&mut physics_world);
+
+    let steps = 100;
+    let start = Instant::now();
+    for _ in 0..steps {
+        traffic_update(&mut traffic, &mut physics_world);
+    }
+    let elapsed = start.elapsed();
+    let step_time = (elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9) / steps as f64;
+    println!("traffic update of {} vehicles: {:.2} ms, update dt {:.2} ms", VEHICLES, step_time * 1e3, DT * 1e3);
+    assert!(step_time < DT, "the traffic update does not keep up with real time");
+
+    b.iter(|| {
+        traffic_update(&mut traffic, &mut physics_world);
+    });
+}
+
+#[bench]
+fn traffic_spatial_hash_query(b: &mut Bencher) {
+    let traffic = make_city_traffic();
+    let mut hash = SpatialHash::new(60.0);
+    for (i, agent) in traffic.agents.iter().enumerate() {
+        hash.insert(traffic.agent_pose(agent).center, i);
+    }
+    let mut result = Vec::new();
+    b.iter(|| {
+        for agent in &traffic.agents[..100] {
+            hash.query(traffic.agent_pose(agent).center, 60.0, &mut result);
+            test::black_box(result.len());
+        }
+    });
+}
@@ -37,7 +37,8 @@ use specs::{System, DispatcherBuilder, World, Builder, ReadStorage, WriteStorage
  Read, ReadExpect, WriteExpect, RunNow, Entities, LazyUpdate, Join, VecStorage, Component};
 use nalgebra::Vector2;
 use opengl_graphics::GlyphCache;
-use nphysics2d::world::World as PWorld;
+
+const PATH_PLANNER_WORKERS : usize = 4;
 
 fn print_commands() {
     let commands = r#"
@@ -49,67 +50,160 @@ a:	spawn vehicle that goes up/down
 p:	spawn vehicle that goes down/up
 g:      hide/show grid
 arrows: move camera in 'move' mode
+
+run 'roadsim2d export <scenario.yaml|random> <out_dir>' to export the town
+run 'roadsim2d [scenario.yaml] --socket <host:port|unix:/path>' to drive it with JSON messages
 "#;
     println!("{}", commands);
 }
 
-fn evaluate_protagonist_car_init_pose()->Pose2DF64{
-    let mut scenario : Option<Scenario> = None;
+fn read_scenario(fname: &str) -> Result<Scenario, String> {
+    println!("Loading scenario from {}", fname);
+    ScenarioLoader::read_from_file(fname).map_err(|error| format!("Loading scenario {} failed: {}", fname, error))
+}
 
-    let all_args : Vec<String> = env::args().collect();
-    if all_args.len() > 1 {
-        let fname = all_args.get(1).unwrap();
-        println!("Loading scenario from {} for car_init_pose", fname);
-        let scenario_res = ScenarioLoader::read_from_file(fname);
+// what load_scenario leaves to the main loop: the town texture and the latched topics
+struct LoadedScenario {
+    gridmap: TownGridMap,
+    sensor_rig: SensorRig,
+}
 
-        if scenario_res.is_ok() {
-            scenario = Some(scenario_res.unwrap());
-        }
+// fills the world with the town, the sensors and the vehicles of the scenario, the
+// entities and resources of a previously loaded scenario are replaced. On errors the
+// world is left untouched
+fn load_scenario(world: &mut World, scenario: Option<&Scenario>, id_provider: &Rc<RefCell<IdProvider>>) -> Result<LoadedScenario, String> {
+    let sensor_rig = scenario.and_then(|scenario| scenario.sensor_rig.clone()).unwrap_or_default();
+    sensor_rig.validate().map_err(|error| format!("Invalid sensor rig: {}", error))?;
+    let town = load_town_for_scenario(scenario).map_err(|error| format!("Loading the town failed: {}", error))?;
+
+    world.delete_all();
+    world.maintain();
+    world.add_resource(PhysicsWorld::new());
+
+    let gridmap = town.gridmap;
+    if let Some(town_seed) = town.seed {
+        println!("Town seed: {}", town_seed);
+    }
+    let seed = town.seed.unwrap_or(0);
+
+    let scenario_count = world.res.try_fetch::<UpdateDeltaTime>().map_or(0, |update_delta_time| update_delta_time.scenario_count + 1);
+    world.add_resource(UpdateDeltaTime { dt: 1.0, sim_time: 0.0, scenario_count: scenario_count });
+    world.add_resource(IbeoSensorState::new(sensor_rig.ibeos(), seed));
+    let deterministic = scenario.map_or(false, |scenario| scenario.deterministic);
+    world.add_resource(PathPlanner::new(Arc::new(gridmap.clone()), PATH_PLANNER_WORKERS, deterministic));
+    world.add_resource(gridmap.clone());
+
+    let mut junctions = town.road_network.junctions.clone();
+    if let Some(scenario) = scenario {
+        junctions.extend(scenario.junctions.iter().cloned());
+    }
+    world.add_resource(JunctionMap { junctions: junctions });
+
+    let traffic = match scenario.and_then(|scenario| scenario.traffic.clone()) {
+        Some(traffic_params) => {
+            let lane_graph = LaneGraph::from_road_network(&town.road_network);
+            if lane_graph.is_empty() {
+                println!("The town has no road network, no traffic will be spawned");
+            }
+            TrafficState::new(lane_graph, traffic_params, seed)
+        },
+        None => TrafficState::empty()
+    };
+    println!("Traffic vehicles: {}", traffic.agents.len());
+    world.add_resource(traffic);
+
+    world.add_resource(LidarState::new(sensor_rig.lidars(), seed));
+    world.add_resource(RadarState::new(sensor_rig.radars(), seed));
+    world.add_resource(GnssState::new(sensor_rig.gnss_receivers(), seed));
+    world.add_resource(ImuState::new(sensor_rig.imus(), seed));
+    let wheel_odometry_params = scenario.and_then(|scenario| scenario.wheel_odometry.clone()).unwrap_or_default();
+    world.add_resource(WheelOdometry::new(wheel_odometry_params, seed));
+    world.add_resource(SpawnRng::new(seed));
+    world.add_resource(scenario.and_then(|scenario| scenario.local_costmap.clone()));
+    world.add_resource(scenario.and_then(|scenario| scenario.protagonist_control.clone()).unwrap_or_default());
+
+    let speed_limit_map = match scenario {
+        Some(scenario) => scenario.speed_limits.clone().unwrap_or_default(),
+        None => SpeedLimitMap::default()
     };
+    world.add_resource(speed_limit_map);
+    world.add_resource(ProtagonistMetrics::default());
 
-    let init_pose = if scenario.is_some() {
-	let protagonist_car_init_pose = scenario.unwrap().protagonist_car_init.unwrap().pose;
-        let pose = Pose2DF64{
-	    center: Point2f64{
-		  x: protagonist_car_init_pose.center.x,
-		  y: protagonist_car_init_pose.center.y
-	    },
-	    yaw: protagonist_car_init_pose.yaw
-	};
-	println!("Pose x: {}, y: {}, yaw: {}", pose.center.x, pose.center.y, pose.yaw);
-	pose
+    let scenario_start = ScenarioStart::from_scenario(scenario);
+    let protagonist_car = protagonist_car(id_provider.borrow_mut().next());
+    let protagonist_physics = make_physics_for_car(&mut world.write_resource::<PhysicsWorld>(),
+        &protagonist_car, &scenario_start.protagonist_pose);
+
+    world.create_entity()
+        .with(Node{pose: scenario_start.protagonist_pose.clone()})
+        .with(protagonist_physics)
+        .with(protagonist_car)
+        .with(ProtagonistTag{}).build();
+
+    {
+        let entities = world.entities();
+        let updater = world.read_resource::<LazyUpdate>();
+        let mut physics_world = world.write_resource::<PhysicsWorld>();
+        for car in scenario_start.cars.iter().cloned() {
+            CarCmdListController::create_car(&entities, &mut physics_world, &updater, id_provider.clone(),
+            car.pose, car.cmds, car.rgb);
+        }
     }
-    else {
-	println!("Default pose");
-        Pose2DF64::default()
-    };
-    init_pose    
+    world.maintain();
+    world.add_resource(scenario_start);
+
+    Ok(LoadedScenario {gridmap: gridmap, sensor_rig: sensor_rig})
 }
 
+fn export_main(args: &[String]) {
+    if args.len() < 2 {
+        println!("usage: roadsim2d export <scenario.yaml|random> <out_dir>");
+        return;
+    }
+    let scenario = if args[0] == "random" {
+        None
+    } else {
+        Some(ScenarioLoader::read_from_file(&args[0]).expect("Loading scenario failed"))
+    };
+    let town = load_town_for_scenario(scenario.as_ref()).expect("Loading town failed");
+    let out_dir = std::path::Path::new(&args[1]);
+    export_town(out_dir, &town.gridmap, &town.road_network.roads, town.seed).expect("Exporting town failed");
+    println!("Town exported to {:?}", out_dir);
+}
 
 fn main() {
+    let all_args : Vec<String> = env::args().collect();
+    if all_args.len() > 1 && all_args[1] == "export" {
+        export_main(&all_args[2..]);
+        return;
+    }
+
     let id_provider = Rc::new(RefCell::new(IdProvider::new()));
 
-    let mut vehicle_mgr = VehicleManager::new(id_provider.clone());
+    let vehicle_mgr = VehicleManager::new(id_provider.clone());
     
-    let mut vehicle_state_listeners : Vec<Box<VehicleStatesListener>> = Vec::new();
-
-    let ibeo_publisher = IbeoPublisher::try_new(); 
-    if ibeo_publisher.is_some() {
-        vehicle_state_listeners.push(Box::new(ibeo_publisher.unwrap()));
-        println!("Added ROS publisher");
-    } else {
-        println!("Could not start ROS publisher");
+    let mut scenario_path = None;
+    let mut socket_address = None;
+    let mut args = all_args.iter().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--socket" {
+            socket_address = Some(args.next().expect("--socket needs an address").clone());
+        } else {
+            scenario_path = Some(arg.clone());
+        }
     }
 
-    let mut target_protagonist_twist = Arc::new(Mutex::new(Twist2D::default()));
-    let mut target_protagonist_twist_clone = target_protagonist_twist.clone();
+    let mut outputs = SimOutputs::default();
+    let mut command_sources : Vec<Box<CommandSource>> = Vec::new();
+    #[cfg(feature = "ros")]
+    add_ros_backend(&mut outputs, &mut command_sources);
+    if let Some(ref address) = socket_address {
+        let socket_source = SocketCommandSource::bind(address)
+            .unwrap_or_else(|error| panic!("Failed to listen on {}: {}", address, error));
+        add_socket_backend(socket_source, &mut outputs, &mut command_sources);
+    }
 
-    let twist_subscriber = TwistSubscriber::new( move |x, z_rot| {
-        let mut target_protagonist_twist_locked = target_protagonist_twist_clone.lock().unwrap();
-        target_protagonist_twist_locked.x = x;
-        target_protagonist_twist_locked.z_rot = z_rot;
-    });
+    let protagonist_commands : ProtagonistCommandHandle = Arc::new(Mutex::new(ProtagonistCommandState::new()));
 
     let mut previous_frame_end_timestamp = time::Instant::now();
     let previous_msg_stamp = time::Instant::now();
@@ -139,41 +233,6 @@ fn main() {
 
     let mut world = World::new();
 
-    let mut physics_world = PWorld::new();
-    physics_world.set_gravity(Vector2::new(0.0, 0.0));
-
-
-    let mut scenario : Option<Scenario> = None;
-
-    let all_args : Vec<String> = env::args().collect();
-    if all_args.len() > 1 {
-        let fname = all_args.get(1).unwrap();
-        println!("Loading scenario from {} for gridmap", fname);
-        let scenario_res = ScenarioLoader::read_from_file(fname);
-
-        if scenario_res.is_ok() {
-            scenario = Some(scenario_res.unwrap());
-        }
-    }
-
-    let gridmap = if scenario.is_some() {
-        let town_image = &scenario.as_ref().unwrap().town_image;
-        if  town_image.is_some() {
-	    println!("Loading image from scenario");
-            load_town_from_file(&town_image.as_ref().unwrap().as_str())
-        } else {
-	    println!("Generating random image");
-            make_random_town_gridmap(0)
-        }
-    } else {
-        println!("Generating random image because scenario not ok");
-        make_random_town_gridmap(0)
-    };
-
-    let gridmap_texture = town_gridmap_to_texture(&mut fps_window, &gridmap);
-
-    let mut simulation_time = 0.0f64;
-
     world.register::<Car>();
     world.register::<Camera>();
     world.register::<Grid>();
@@ -184,23 +243,26 @@ fn main() {
     world.register::<CarHighLevelControllerState>();
     world.register::<CarPathControllerState>();
     world.register::<CarCmdListState>();
+    world.register::<CarJunctionState>();
+    world.register::<CarSpeedLimit>();
+    world.register::<BodyCommand>();
 
     world.add_resource(InputEvents::new());
     world.add_resource(InputState::new());
-    world.add_resource(UpdateDeltaTime { dt: 1.0, sim_time: 0.0 });
     world.add_resource(SimInfo::default());
-    world.add_resource(IbeoSensorState::new());
     world.add_resource(grid);
-    world.add_resource(gridmap);
 
+    // the scenario to reload, replaced when /roadsim2d/load_scenario loads another one
+    let scenario = scenario_path.as_ref().and_then(|fname| read_scenario(fname).map_err(|error| println!("{}", error)).ok());
 
-    let protagonist_car = vehicle_mgr.make_protagonist_car();
+    let loaded_scenario = load_scenario(&mut world, scenario.as_ref(), &id_provider).expect("Loading the scenario failed");
+    let mut gridmap_texture = town_gridmap_to_texture(&mut fps_window, &loaded_scenario.gridmap);
+    // run by the main loop after the simulation steps, while paused as well
+    let mut state_sink_sys = StateSinkSys::new(outputs.state_sinks);
+    state_sink_sys.on_scenario(&loaded_scenario.gridmap, &loaded_scenario.sensor_rig);
+
+    let mut simulation_time = 0.0f64;
 
-    world.create_entity()
-        .with(Node{pose: evaluate_protagonist_car_init_pose()})
-        .with(make_physics_for_car(&mut physics_world, &protagonist_car, &evaluate_protagonist_car_init_pose()))
-        .with(protagonist_car)
-        .with(ProtagonistTag{}).build();
     world.add_resource(camera);
 
 
@@ -215,25 +277,6 @@ fn main() {
     let mut fonts = GlyphCache::new(font, (), TextureSettings::new()).expect("unable to load font");
     let mut fps_counter = FPSCounter::new();
 
-    let all_args : Vec<String> = env::args().collect();
-    if all_args.len() > 1 {
-        let fname = all_args.get(1).unwrap();
-        println!("Loading scenario from {} for cars", fname);
-        let scenario_res = ScenarioLoader::read_from_file(fname);
-
-        if scenario_res.is_ok() {
-            scenario = Some(scenario_res.unwrap());
-        }
-    }
-
-    if scenario.is_some() {
-	println!("Scenario ok, taking the cars..");
-        for car in scenario.unwrap().cars {
-            CarCmdListController::create_car(&mut world, &mut physics_world, id_provider.clone(), 
-            car.pose, car.cmds, car.rgb);
-        }
-    }
-
     // if all_args.len() > 1 {
         // let fname = all_args.get(1).unwrap();
         // println!("Loading scenario from {}", fname);
@@ -250,6 +293,53 @@ fn main() {
 
     // }
 
+    // systems touching the physics world get it as a resource, specs runs in parallel
+    // the ones whose storages and resources do not conflict
+    let sim_command_queue : SimCommandQueue = Arc::new(Mutex::new(VecDeque::new()));
+    // run outside of the dispatcher, the commands are executed while paused as well
+    let mut sim_command_sys = SimCommandSys {
+        commands: sim_command_queue.clone(),
+        id_provider: id_provider.clone(),
+        protagonist_commands: protagonist_commands.clone(),
+    };
+
+    let sim_control : SimControlHandle = Arc::new(Mutex::new(SimControl::new()));
+
+    let command_targets = CommandTargets {
+        protagonist: protagonist_commands.clone(),
+        sim_commands: sim_command_queue.clone(),
+        sim_control: sim_control.clone(),
+    };
+
+    let mut update_dispatcher = DispatcherBuilder::new()
+        .with(CarPathControllerSys{}, "car_path_controller", &[])
+        .with(CarCmdListSys{}, "car_cmd_list", &[])
+        .with(JunctionRightOfWaySys, "junction_right_of_way", &[])
+        .with(SpeedLimitSys, "speed_limit", &[])
+        .with(CarControllerSys, "car_controller", &["car_path_controller", "car_cmd_list", "junction_right_of_way", "speed_limit"])
+        .with(ControlProtagonistSys{commands: protagonist_commands.clone()}, "control_protagonist", &[])
+        .with(PhysicsStepSys, "physics_step", &["car_controller", "control_protagonist"])
+        .with(PhysicsUpdateNodeSys, "physics_update_node", &["physics_step"])
+        .with(SpeedingMetricsSys, "speeding_metrics", &["physics_update_node"])
+        .with(UpdateCarsSys, "update_cars", &["physics_update_node"])
+        .with(TrafficSys, "traffic", &["physics_step"])
+        .with(LidarSys, "lidar", &["physics_update_node", "traffic"])
+        .with(RadarSys, "radar", &["physics_update_node", "traffic"])
+        .with(WheelOdometrySys, "wheel_odometry", &["physics_update_node"])
+        .with(GnssSys, "gnss", &["physics_update_node"])
+        .with(ImuSys, "imu", &["physics_update_node"])
+        // thread local systems run after the parallel ones
+        .with_thread_local(SimClockSys::new(outputs.clocks))
+        .with_thread_local(SpawnNewCarSys{vehicle_mgr: vehicle_mgr})
+        .with_thread_local(IbeoSensorSys::new(outputs.vehicle_states))
+        .with_thread_local(SensorOutputSys::<LidarState>::new(outputs.lidar_scans))
+        .with_thread_local(SensorOutputSys::<RadarState>::new(outputs.radar_scans))
+        .with_thread_local(SensorOutputSys::<GnssState>::new(outputs.gnss_fixes))
+        .with_thread_local(SensorOutputSys::<ImuState>::new(outputs.imu_samples))
+        .with_thread_local(GroundTruthObjectsSys::new(outputs.ground_truth))
+        .with_thread_local(LocalCostmapSys::new(outputs.local_costmaps))
+        .build();
+
 
     while let Some(e) = fps_window.next() {
         if let Some(args) = e.press_args() {
@@ -265,33 +355,59 @@ fn main() {
         }
 
         if let Some(args) = e.update_args() {
-            let () = {
-                let mut update_delta_time = world.write_resource::<UpdateDeltaTime>();
-                simulation_time += args.dt;
-                update_delta_time.dt = args.dt;
-                update_delta_time.sim_time = simulation_time;
-            };
             let window_size = fps_window.draw_size();
 
-            CarPathControllerSys{}.run_now(&mut world.res);
-            CarCmdListSys{}.run_now(&mut world.res);
-            CarControllerSys{physics_world: &mut physics_world}.run_now(&mut world.res);
-            let target_protagonist_twist_locked = target_protagonist_twist.lock().unwrap();
-            ControlProtagonistSys{physics_world: &mut physics_world, target_protagonist_twist: &target_protagonist_twist_locked}.run_now(&mut world.res);
-
-            physics_world.set_timestep(args.dt);
-            physics_world.step();
+            for source in command_sources.iter_mut() {
+                source.update(&command_targets);
+            }
 
-            PhysicsUpdateNodeSys{physics_world:  &physics_world}.run_now(&mut world.res);
+            // input and camera handling stay on the main thread, before the simulation update
             UpdateInputStateSys{}.run_now(&mut world.res);
-
             UpdateCameraSys{window_size, camera_key_mapping: &mut camera_key_mapping}.run_now(&mut world.res);
             UpdateGridSys{}.run_now(&mut world.res);
 
-            SpawnNewCarSys{physics_world: &mut physics_world, vehicle_mgr: &mut vehicle_mgr}.run_now(&mut world.res);
-            UpdateCarsSys{physics_world: &mut physics_world}.run_now(&mut world.res);
-            IbeoSensorSys::new(&mut vehicle_state_listeners, &mut physics_world).run_now(&mut world.res);
+            // every step lasts the update period, the real time factor changes how many run
+            let steps = sim_control.lock().unwrap().steps_for_update();
+            for _ in 0..steps {
+                let () = {
+                    let mut update_delta_time = world.write_resource::<UpdateDeltaTime>();
+                    simulation_time += args.dt;
+                    update_delta_time.dt = args.dt;
+                    update_delta_time.sim_time = simulation_time;
+                };
+                update_dispatcher.dispatch(&mut world.res);
+                // vehicles spawned and deleted during the update
+                world.maintain();
+            }
+            sim_control.lock().unwrap().on_steps_done(simulation_time);
+
+            sim_command_sys.run_now(&mut world.res);
+            world.maintain();
 
+            let load_request = sim_control.lock().unwrap().load_request.take();
+            if let Some(load_request) = load_request {
+                let path = load_request.path.or_else(|| scenario_path.clone());
+                let result = match path {
+                    Some(ref path) => read_scenario(path).map(Some),
+                    // no scenario file, a new random town
+                    None => Ok(None)
+                }.and_then(|scenario| load_scenario(&mut world, scenario.as_ref(), &id_provider));
+                match result {
+                    Ok(loaded_scenario) => {
+                        scenario_path = path;
+                        simulation_time = 0.0;
+                        gridmap_texture = town_gridmap_to_texture(&mut fps_window, &loaded_scenario.gridmap);
+                        state_sink_sys.on_scenario(&loaded_scenario.gridmap, &loaded_scenario.sensor_rig);
+                        load_request.reply.send(Ok(())).ok();
+                    },
+                    Err(error) => {
+                        println!("{}", error);
+                        load_request.reply.send(Err(error)).ok();
+                    }
+                }
+            }
+
+            state_sink_sys.run_now(&mut world.res);
         }
 
         if let Some(_args) = e.render_args() {
@@ -299,6 +415,13 @@ fn main() {
                 let mut sim_info = world.write_resource::<SimInfo>();
                 sim_info.sim_time = simulation_time;
                 sim_info.fps = fps_counter.tick() as f32;
+                let sim_control = sim_control.lock().unwrap();
+                sim_info.paused = sim_control.paused;
+                sim_info.real_time_factor = sim_control.real_time_factor;
+                #[cfg(feature = "ros")]
+                {
+                    sim_info.ros_status = RosBridgeStatus::current();
+                }
             };
 
             fps_window.draw_2d(&e, |context, graphics| {
@@ -309,6 +432,7 @@ fn main() {
             RenderTownSys{fps_window: &mut fps_window, town_gridmap_texture: &gridmap_texture, render_event: &e, render_args: _args}.run_now(&mut world.res);
             RenderGridSys{fps_window: &mut fps_window, render_event: &e, render_args: _args}.run_now(&mut world.res);
             RendererCarHighLevelControllerSys{fps_window: &mut fps_window, render_event: &e, render_args: _args}.run_now(&mut world.res);
+            RenderTrafficSys{fps_window: &mut fps_window, render_event: &e, render_args: _args}.run_now(&mut world.res);
             RenderCarSys{fps_window: &mut fps_window, render_event: &e, render_args: _args}.run_now(&mut world.res);
             RenderInfoSys{render_args: _args, font_glyphs: &mut fonts, opengl: &mut gl}.run_now(&mut world.res);
             world.maintain();
@@ -6,11 +6,6 @@ extern crate piston;
 extern crate rand;
 extern crate euclid;
 extern crate conrod;
-extern crate rosrust;
-#[macro_use]
-extern crate rosrust_codegen;
-
-rosmsg_include!();
 
 extern crate roadsim2dlib;
 extern crate nalgebra;
@@ -126,17 +126,15 @@ impl<'a, 'b> System<'a> for RenderCarSys<'b> {
     }
 }
 
-pub struct UpdateCarsSys<'a> {
-    pub physics_world: &'a mut PWorld<f64>
-}
+pub struct UpdateCarsSys;
 
-impl<'a, 'b> System<'a> for UpdateCarsSys<'b> {
-    type SystemData = (ReadExpect<'a, UpdateDeltaTime>, WriteStorage<'a, PhysicsComponent>, ReadStorage<'a, Car>);
+impl<'a> System<'a> for UpdateCarsSys {
+    type SystemData = (ReadExpect<'a, UpdateDeltaTime>, WriteExpect<'a, PhysicsWorld>, WriteStorage<'a, PhysicsComponent>, ReadStorage<'a, Car>);
 
-    fn run(&mut self, (update_delta_time, mut physics_components, mut cars): Self::SystemData) {
+    fn run(&mut self, (update_delta_time, mut physics_world, mut physics_components, mut cars): Self::SystemData) {
         for (physics_component, car) in (&mut physics_components, & cars).join() {
 
-            let mut rigid_body = self.physics_world.rigid_body_mut(physics_component.body_handle).expect("car rigid body not found");
+            let mut rigid_body = physics_world.rigid_body_mut(physics_component.body_handle).expect("car rigid body not found");
             let pos = rigid_body.position().translation.vector;
             let rot = rigid_body.position().rotation;
             let vel = rigid_body.velocity();
@@ -1,4 +1,5 @@
-use specs::{World, Builder, System, VecStorage, Component, ReadStorage, WriteStorage, ReadExpect, Join};
+use specs::{Builder, System, LazyUpdate, VecStorage, Component, ReadStorage, WriteStorage, ReadExpect, Join};
+use specs::world::EntitiesRes;
 
 use std::rc::Rc;
 use std::cell::RefCell;
@@ -20,11 +21,11 @@ use cgmath::InnerSpace;
 use cgmath::MetricSpace;
 use cgmath::EuclideanSpace;
 use super::scenario::*;
+use super::junction::*;
 use piston_window::*;
 use rand::Rng;
 use rand;
 use std::collections::VecDeque;
-use nphysics2d::world::World as PWorld;
 
 #[derive(Component, Debug)]
 #[storage(VecStorage)]
@@ -38,6 +39,12 @@ impl CarCmdListState {
             cmd_states: VecDeque::new()
         }
     }
+
+    pub fn with_cmds(cmd_states: VecDeque<CarActionState>) -> CarCmdListState {
+        CarCmdListState {
+            cmd_states: cmd_states
+        }
+    }
 }
 
 pub struct CarCmdListSys {
@@ -76,7 +83,8 @@ pub struct CarCmdListController {
 impl CarCmdListController {
 
 
-pub fn create_car(world: &mut World, mut physics_world: &mut PWorld<f64>, 
+// the entity is built lazily, it exists after the next world.maintain()
+pub fn create_car(entities: &EntitiesRes, physics_world: &mut PhysicsWorld, updater: &LazyUpdate,
     mut id_provider: Rc<RefCell<IdProvider>>, first_pose: Pose2DF64, 
     mut cmd_states : VecDeque<CarActionState>, car_rgb: (f32, f32, f32)) {
 
@@ -88,10 +96,7 @@ pub fn create_car(world: &mut World, mut physics_world: &mut PWorld<f64>,
             color: rgb(car_rgb.0, car_rgb.1, car_rgb.2),
     };
 
-    let mut hl_control_state = CarHighLevelControllerState {
-        target_yaw: 0f32,
-        target_long_speed: 0f32
-    };
+    let mut hl_control_state = CarHighLevelControllerState::new();
 
     if cmd_states.len() > 0 {
         let first_state = cmd_states.pop_front().unwrap();
@@ -99,13 +104,16 @@ pub fn create_car(world: &mut World, mut physics_world: &mut PWorld<f64>,
         hl_control_state.target_long_speed = first_state.lon_vel;
     }
 
-    world.create_entity()
-        .with(make_physics_for_car(&mut physics_world, &new_car, &first_pose))
+    let physics_component = make_physics_for_car(physics_world, &new_car, &first_pose);
+
+    updater.create_entity(entities)
+        .with(physics_component)
         .with(Node{pose: first_pose})
         .with(new_car)
         .with(CarController{})
         .with(CarCmdListState{cmd_states: cmd_states})
         .with(hl_control_state)
+        .with(CarJunctionState::new())
         .build();
 
 }
@@ -1,6 +1,8 @@
 use super::primitives::*;
 use super::physics::*;
 use super::car_hl_controller::*;
+use super::junction::CarJunctionState;
+use super::speed_limit::CarSpeedLimit;
 use super::node::Node;
 use super::car::Car;
 use super::global_resources::*;
@@ -19,34 +21,41 @@ pub struct CarController {
 }
 
 
-pub struct CarControllerSys<'a> {
-   pub physics_world: &'a mut PWorld<f64>
-}
+pub struct CarControllerSys;
 
 const CAR_ACC : f64 = 10.0f64;
 
-impl <'a, 'b> System<'a> for CarControllerSys<'b> {
+impl <'a> System<'a> for CarControllerSys {
     type SystemData = (
+        Entities<'a>,
         ReadExpect<'a, UpdateDeltaTime>, 
+        ReadExpect<'a, PhysicsWorld>,
         ReadStorage<'a, CarController>,
         ReadStorage<'a, CarHighLevelControllerState>,
+        ReadStorage<'a, CarPathControllerState>,
+        ReadStorage<'a, CarJunctionState>,
+        ReadStorage<'a, CarSpeedLimit>,
         ReadStorage<'a, Node>,
-        WriteStorage<'a, PhysicsComponent>,
+        ReadStorage<'a, PhysicsComponent>,
         WriteStorage<'a, Car>,
+        WriteStorage<'a, BodyCommand>,
     );
 
-    fn run(&mut self, (update_delta_time, car_controllers, car_high_level_controller_states, 
-            nodes, mut physics_components, mut cars): Self::SystemData) {
+    fn run(&mut self, (entities, update_delta_time, physics_world, car_controllers, car_high_level_controller_states,
+            path_controller_states, junction_states, speed_limits, nodes, physics_components, mut cars,
+            mut body_commands): Self::SystemData) {
         let dt = update_delta_time.dt;
 
-        for (car_controller, physics_component, node, car, car_high_level_controller_state) in 
-                (&car_controllers, &mut physics_components, &nodes, &mut cars, &car_high_level_controller_states).join() {
-            let mut rigid_body = self.physics_world.rigid_body_mut(physics_component.body_handle).expect("car rigid body not found");
+        for (entity, car_controller, physics_component, node, car, car_high_level_controller_state) in 
+                (&entities, &car_controllers, &physics_components, &nodes, &mut cars, &car_high_level_controller_states).join() {
+            let rigid_body = physics_world.rigid_body(physics_component.body_handle).expect("car rigid body not found");
             let current_speed = rigid_body.velocity();
 
             let current_speed_mag = current_speed.linear.norm();
 
-            let target_yaw_diff = car_high_level_controller_state.target_yaw - node.pose.yaw as f32;
+            let path_controller_state = path_controller_states.get(entity);
+            let target_yaw = path_controller_state.map_or(car_high_level_controller_state.target_yaw, |state| state.target_yaw);
+            let target_yaw_diff = target_yaw - node.pose.yaw as f32;
             let correct_direction_yaw_diff = if target_yaw_diff < 0.0f32 {
                 target_yaw_diff  + std::f32::consts::PI * 2f32
             } else {
@@ -60,15 +69,25 @@ impl <'a, 'b> System<'a> for CarControllerSys<'b> {
             car.wheel_yaw = num::clamp(correct_direction_yaw_diff, 0.0f32, 0.5f32) * sign_mul as f32;
 
             let yaw_increment = current_speed_mag as f32 / (car.bb_size.height as f32 / 2.0f32)  *  car.wheel_yaw;
-            rigid_body.set_angular_velocity(yaw_increment as f64);
 
 
-            let target_long_speed = car_high_level_controller_state.target_long_speed;
+            let junction_hold = junction_states.get(entity).map_or(false, |state| state.hold);
+            let waiting_for_path = path_controller_state.map_or(false, |state| state.waiting_for_path);
+            let target_long_speed = path_controller_state.and_then(|state| state.target_long_speed)
+                .unwrap_or(car_high_level_controller_state.target_long_speed);
+            let target_long_speed = if junction_hold || waiting_for_path {
+                0f32
+            } else {
+                match speed_limits.get(entity).and_then(|speed_limit| speed_limit.speed_limit) {
+                    Some(speed_limit) => f32::min(target_long_speed, speed_limit),
+                    None => target_long_speed
+                }
+            };
             let speed_increment = CAR_ACC * dt * (target_long_speed as f64 - current_speed_mag).signum();
 
             let mut car_velocity = Vector2::new(current_speed_mag + speed_increment, 0.0);
             rigid_body.position().rotation.rotate(&mut car_velocity);
-            rigid_body.set_linear_velocity(car_velocity);
+            body_commands.insert(entity, BodyCommand {linear_velocity: car_velocity, angular_velocity: yaw_increment as f64}).ok();
         }
     }
 
@@ -1,11 +1,12 @@
-use specs::{System, VecStorage, Component, ReadStorage, WriteStorage, ReadExpect, Join};
-use std::collections::VecDeque;
+use specs::{System, VecStorage, Component, ReadStorage, WriteStorage, ReadExpect, WriteExpect, Join};
+use std::collections::{HashSet, VecDeque};
 
 use super::primitives::*;
 use super::car::*;
 use super::node::*;
 use super::time::*;
 use super::town::*;
+use super::path_planner::*;
 use super::camera::*;
 use super::global_resources::*;
 use super::color_utils::*;
@@ -21,7 +22,7 @@ use conrod::color::*;
 #[storage(VecStorage)]
 pub struct CarHighLevelControllerState {
     pub target_yaw: f32,
-    pub target_long_speed: f32
+    pub target_long_speed: f32,
 }
 
 #[derive(Component, Debug)]
@@ -29,13 +30,19 @@ pub struct CarHighLevelControllerState {
 pub struct CarPathControllerState {
     pub destination_point: Vec2f32,
     pub path: VecDeque<Vec2f32>,
+    pub pending_request: Option<u64>,
+    // override the targets of CarHighLevelControllerState, kept apart so that
+    // the path and the command list controllers can run in parallel
+    pub target_yaw: f32,
+    pub target_long_speed: Option<f32>,
+    pub waiting_for_path: bool,
 }
 
 impl CarHighLevelControllerState {
     pub fn new() -> CarHighLevelControllerState {
         CarHighLevelControllerState {
             target_yaw: 0f32,
-            target_long_speed: 0f32
+            target_long_speed: 0f32,
         }
     }
 }
@@ -45,6 +52,10 @@ impl CarPathControllerState {
         CarPathControllerState {
             destination_point: Vec2f32::new(0f32, 0f32),
             path: VecDeque::new(),
+            pending_request: None,
+            target_yaw: 0f32,
+            target_long_speed: None,
+            waiting_for_path: false,
         }
     }
 }
@@ -59,28 +70,49 @@ impl <'a> System<'a> for CarPathControllerSys {
     type SystemData = (
         ReadExpect<'a, UpdateDeltaTime>, 
         ReadExpect<'a, TownGridMap>, 
+        WriteExpect<'a, PathPlanner>, 
         ReadStorage<'a, Node>,
         ReadStorage<'a, Car>,
-        WriteStorage<'a, CarHighLevelControllerState>,
         WriteStorage<'a, CarPathControllerState>
     );
 
-    fn run(&mut self, (update_delta_time, town_gridmap, nodes, 
-            cars, mut controller_states, mut car_path_controller): Self::SystemData) {
+    fn run(&mut self, (update_delta_time, town_gridmap, mut path_planner, nodes, 
+            cars, mut car_path_controller): Self::SystemData) {
         let dt = update_delta_time.dt;
+        path_planner.poll();
 
-        for (node, car, controller_state, car_path_controller) in 
-                (&nodes, &cars, &mut controller_states, &mut car_path_controller).join() {
+        for (node, car, car_path_controller) in 
+                (&nodes, &cars, &mut car_path_controller).join() {
 
             let mut destination_point = &mut car_path_controller.destination_point;
             let mut controller_state_path = &mut car_path_controller.path;
+            let mut pending_request = &mut car_path_controller.pending_request;
             let car_center = vec2f64_2_vec2f32(node.pose.center.to_vec());
 
+            if let Some(request_id) = *pending_request {
+                match path_planner.take_result(request_id) {
+                    Some(Some(shortest_path)) => {
+                        println!("found shortest path");
+                        *controller_state_path = shortest_path;
+                        *pending_request = None;
+                    },
+                    Some(None) => {
+                        car_path_controller.target_long_speed = Some(0f32);
+                        *destination_point = Vec2f32::new(0.0f32, 0.0f32);
+                        *pending_request = None;
+                    },
+                    None => {}
+                }
+            }
+
+            // hold the last plan while the new one is computed, stop if nothing is left of it
+            car_path_controller.waiting_for_path = pending_request.is_some() && controller_state_path.is_empty();
+
             let distance2_target = destination_point.distance2(car_center);
 
             let mut rng = rand::thread_rng();
 
-            if(distance2_target < 21f32 || *destination_point == Vec2f32::new(0f32, 0f32) ) {
+            if pending_request.is_none() && (distance2_target < 21f32 || *destination_point == Vec2f32::new(0f32, 0f32)) {
 
                 let random_destination_point = if(car.color == rgb(0.9, 0.9, 0.1) && node.pose.yaw > 0.0){
 			Vec2f32::new(
@@ -115,19 +147,7 @@ impl <'a> System<'a> for CarPathControllerSys {
                     *destination_point = Vec2f32::new(0.0f32, 0.0f32)
                 } else {
                     *destination_point = end_point.unwrap();
-                    let shortest_path_opt = find_shortest_path(&town_gridmap, start_point.unwrap(), *destination_point);
-                    match shortest_path_opt {
-                        Some(shortest_path) => {
-                            println!("found shortest path");
-                            *controller_state_path = shortest_path;
-                        },
-                        None => {
-                            controller_state.target_long_speed = 0f32;
-                            *destination_point = Vec2f32::new(0.0f32, 0.0f32)
-
-                        }
-                    }
-
+                    *pending_request = Some(path_planner.submit(start_point.unwrap(), *destination_point));
                 }
 
 
@@ -150,9 +170,14 @@ impl <'a> System<'a> for CarPathControllerSys {
             };
 
 
-            controller_state.target_yaw = direction_yaw;
+            car_path_controller.target_yaw = direction_yaw;
             // controller_state.destination_point = destination_point;
         }
+
+        let waiting_requests : HashSet<u64> = (&car_path_controller).join()
+            .filter_map(|car_path_controller| car_path_controller.pending_request)
+            .collect();
+        path_planner.drop_unclaimed_results(&waiting_requests);
     }
 
 }
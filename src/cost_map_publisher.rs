@@ -1,64 +1,326 @@
-// use super::town::*;
-// use super::msg;
-// use rosrust::api::raii::Publisher;
-// use std::time;
+use specs::{System, ReadStorage, ReadExpect, Join};
 
+use super::primitives::*;
+use super::car::*;
+use super::node::*;
+use super::protagonist::*;
+use super::global_resources::*;
+use super::town::*;
+use super::traffic::*;
 
-// // fn publish_tf_trasl_euler(tf_pub: &mut Publisher<msg::tf2_msgs::TFMessage>, frame: &str, child_frame: &str, 
-// //     x: f64, y: f64, z: f64, roll: f64, pitch: f64, yaw: f64, time: &rosrust::Time) {
+// nav_msgs/OccupancyGrid values
+const OCCUPANCY_FREE : i8 = 0;
+const OCCUPANCY_LETHAL : i8 = 100;
+const OCCUPANCY_UNKNOWN : i8 = -1;
+// highest cost of the inflated area, below lethal
+const OCCUPANCY_INFLATED_MAX : f64 = 99.0;
 
-// //     let mut msg = msg::tf2_msgs::TFMessage::default();
-// //     let mut transform = msg::geometry_msgs::TransformStamped::default();
+fn town_cell_value(cell: TownCell) -> i8 {
+    match cell {
+        TownCell::Free => OCCUPANCY_FREE,
+        TownCell::Occupied => OCCUPANCY_LETHAL,
+        TownCell::Unknown => OCCUPANCY_UNKNOWN,
+    }
+}
 
-// //     transform.header.stamp = time.clone();
-// //     transform.header.frame_id = String::from(frame);
-// //     transform.child_frame_id = String::from(child_frame);
+// row major from the cell at the origin, as the town grid itself
+pub fn town_occupancy_data(gridmap: &TownGridMap) -> Vec<i8> {
+    let info = &gridmap.info;
+    let mut data = Vec::with_capacity(info.width * info.height);
+    for y in 0..info.height {
+        for x in 0..info.width {
+            data.push(town_cell_value(gridmap.cell(x, y)));
+        }
+    }
+    data
+}
 
-// //     // let car_center = protagonist.pose.center;
-// //     transform.transform.translation.x = x;
-// //     transform.transform.translation.y = y;
-// //     transform.transform.translation.z = z;
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LocalCostmapParams {
+    // side of the square window centered on the protagonist, in meters
+    pub size: f64,
+    pub resolution: f64,
+    // obstacles are surrounded by a cost decreasing to zero at this distance
+    pub inflation_radius: f64,
+    // updates per second
+    pub rate: f64,
+}
 
-// //     assert!(roll == 0.0);
-// //     assert!(pitch == 0.0);
+impl Default for LocalCostmapParams {
+    fn default() -> Self {
+        LocalCostmapParams {
+            size: 40.0,
+            resolution: 0.2,
+            inflation_radius: 1.5,
+            rate: 5.0,
+        }
+    }
+}
 
-// //     transform.transform.rotation.w = (yaw / 2.0).cos();
-// //     transform.transform.rotation.z = (yaw / 2.0).sin();
+// window aligned with the map axes, `origin` is the map position of its lower-left corner
+#[derive(Debug, Clone, Serialize)]
+pub struct LocalCostmap {
+    #[serde(with = "Vec2f64Serde")]
+    pub origin: Vec2f64,
+    pub resolution: f64,
+    pub width: usize,
+    pub height: usize,
+    pub data: Vec<i8>,
+}
 
-// //     msg.transforms.push(transform);
-// //     tf_pub.send(msg).unwrap();
-// // }
+impl LocalCostmap {
+    pub fn value(&self, x: usize, y: usize) -> i8 {
+        self.data[y * self.width + x]
+    }
+}
 
-// pub fn make_costmap_msg_from_town_gridmap(town_gridmap: &TownGridMap) -> msg::cost_map_msgs::CostMap {
-//     let mut msg = msg::cost_map_msgs::CostMap::default();
-//     msg.header.stamp = rosrust::now();
-//     msg.header.frame_id = String::from("map");
+fn inside_box(point: Point2f64, pose: &Pose2DF64, size: Size2f64) -> bool {
+    let (sin, cos) = pose.yaw.sin_cos();
+    let (dx, dy) = (point.x - pose.center.x, point.y - pose.center.y);
+    let (lon, lat) = (cos * dx + sin * dy, -sin * dx + cos * dy);
+    lon.abs() <= size.height / 2.0 && lat.abs() <= size.width / 2.0
+}
 
-//     msg.layers.push_back(String::from("costmap"));
-//     for x in 0..town_gridmap.width {
-//         for y in 0..town_gridmap.height {
-//             gridmap.has_vertex( &(x as usize, y as usize) ) as u8;
+// the static town and the footprints of `vehicles` are lethal, the cells around them inflated
+pub fn make_local_costmap(params: &LocalCostmapParams, center: Point2f64, gridmap: &TownGridMap,
+                          vehicles: &[(Pose2DF64, Size2f64)]) -> LocalCostmap {
+    let cells = (params.size / params.resolution).round() as usize;
+    let origin = Vec2f64::new(
+        ((center.x - params.size / 2.0) / params.resolution).floor() * params.resolution,
+        ((center.y - params.size / 2.0) / params.resolution).floor() * params.resolution);
+    let cell_center = |x: usize, y: usize| Point2f64::new(origin.x + (x as f64 + 0.5) * params.resolution,
+                                                           origin.y + (y as f64 + 0.5) * params.resolution);
+    let town_info = &gridmap.info;
 
-//         }
-//     }
+    let mut data = Vec::with_capacity(cells * cells);
+    for y in 0..cells {
+        for x in 0..cells {
+            let point = cell_center(x, y);
+            let town_x = ((point.x - town_info.origin.x) / town_info.resolution).floor();
+            let town_y = ((point.y - town_info.origin.y) / town_info.resolution).floor();
+            let value = if town_x < 0.0 || town_y < 0.0 || town_x >= town_info.width as f64 || town_y >= town_info.height as f64 {
+                OCCUPANCY_UNKNOWN
+            } else if vehicles.iter().any(|(pose, size)| inside_box(point, pose, *size)) {
+                OCCUPANCY_LETHAL
+            } else {
+                town_cell_value(gridmap.cell(town_x as usize, town_y as usize))
+            };
+            data.push(value);
+        }
+    }
 
-//     msg
-// }
+    // every lethal cell raises the cost of its neighbourhood
+    let radius = (params.inflation_radius / params.resolution).ceil() as i64;
+    let mut kernel = Vec::new();
+    for dy in -radius..=radius {
+        for dx in -radius..=radius {
+            let distance = ((dx * dx + dy * dy) as f64).sqrt() * params.resolution;
+            if distance > 0.0 && distance < params.inflation_radius {
+                kernel.push((dx, dy, (OCCUPANCY_INFLATED_MAX * (1.0 - distance / params.inflation_radius)).ceil() as i8));
+            }
+        }
+    }
+    let mut inflated = data.clone();
+    for y in 0..cells as i64 {
+        for x in 0..cells as i64 {
+            if data[(y as usize) * cells + x as usize] != OCCUPANCY_LETHAL {
+                continue;
+            }
+            for (dx, dy, cost) in &kernel {
+                let (nx, ny) = (x + dx, y + dy);
+                if nx < 0 || ny < 0 || nx >= cells as i64 || ny >= cells as i64 {
+                    continue;
+                }
+                let value = &mut inflated[(ny as usize) * cells + nx as usize];
+                if *value != OCCUPANCY_LETHAL && *value < *cost {
+                    *value = *cost;
+                }
+            }
+        }
+    }
 
-// pub struct CostMapPublisher {
-//     cost_map_pub: Publisher<msg::cost_map_msgs::CostMap>,
-// }
+    LocalCostmap {origin: origin, resolution: params.resolution, width: cells, height: cells, data: inflated}
+}
 
-// impl CostMapPublisher {
-//     pub fn try_new() -> Option<CostMapPublisher> {
-//         let cost_map_pub = rosrust::publish("/roadsim2d/costmap").expect(ros_not_available_error_msg);
-//         let cost_map_publisher = CostMapPublisher {
-//             cost_map_pub: cost_map_pub,
-//         };
-//         Some(cost_map_publisher)
-//     }
+pub trait LocalCostmapListener {
+    fn on_local_costmap(&mut self, costmap: &LocalCostmap);
+}
 
-//     pub fn publish_town_gridmap_as_costmap(town_gridmap: &TownGridMap) {
+// the costmap is computed only if the scenario has LocalCostmapParams, stored as resource
+pub struct LocalCostmapSys {
+    pub listeners: Vec<Box<LocalCostmapListener>>,
+    rate_limiter: RateLimiter,
+}
 
-//     }
-// }
\ No newline at end of file
+impl LocalCostmapSys {
+    pub fn new(listeners: Vec<Box<LocalCostmapListener>>) -> LocalCostmapSys {
+        LocalCostmapSys {listeners: listeners, rate_limiter: RateLimiter::new()}
+    }
+}
+
+impl <'a> System<'a> for LocalCostmapSys {
+    type SystemData = (
+        ReadExpect<'a, UpdateDeltaTime>,
+        ReadExpect<'a, Option<LocalCostmapParams>>,
+        ReadExpect<'a, TownGridMap>,
+        ReadExpect<'a, TrafficState>,
+        ReadStorage<'a, Car>,
+        ReadStorage<'a, Node>,
+        ReadStorage<'a, ProtagonistTag>,
+    );
+
+    fn run(&mut self, (update_delta_time, params, town_gridmap, traffic, cars, nodes, protagonists): Self::SystemData) {
+        let params = match *params {
+            Some(ref params) => params,
+            None => return
+        };
+        if !self.rate_limiter.ready(&update_delta_time, params.rate) {
+            return;
+        }
+
+        let center = match (&nodes, &protagonists).join().next() {
+            Some((node, _protagonist)) => node.pose.center,
+            None => return
+        };
+        let window = params.size / 2.0 + params.inflation_radius;
+        let in_window = |pose: &Pose2DF64, size: Size2f64| {
+            let reach = window + size.height.max(size.width);
+            (pose.center.x - center.x).abs() < reach && (pose.center.y - center.y).abs() < reach
+        };
+
+        let mut vehicles : Vec<(Pose2DF64, Size2f64)> = (&cars, &nodes, !&protagonists).join()
+            .map(|(car, node, ())| (node.pose.clone(), car.bb_size))
+            .filter(|(pose, size)| in_window(pose, *size))
+            .collect();
+        let traffic_size = traffic.vehicle_size();
+        vehicles.extend(traffic.agents.iter()
+            .map(|agent| (traffic.agent_pose(agent), traffic_size))
+            .filter(|(pose, size)| in_window(pose, *size)));
+
+        let costmap = make_local_costmap(params, center, &town_gridmap, &vehicles);
+        for listener in self.listeners.iter_mut() {
+            listener.on_local_costmap(&costmap);
+        }
+    }
+}
+
+#[cfg(feature = "ros")]
+pub use self::ros::*;
+
+#[cfg(feature = "ros")]
+mod ros {
+    use super::*;
+    use crate::ros_bridge::*;
+    use crate::msg;
+
+    fn make_map_meta_data(resolution: f64, width: usize, height: usize, origin: Vec2f64) -> msg::nav_msgs::MapMetaData {
+        let mut info = msg::nav_msgs::MapMetaData::default();
+        info.resolution = resolution as f32;
+        info.width = width as u32;
+        info.height = height as u32;
+        info.origin.position.x = origin.x;
+        info.origin.position.y = origin.y;
+        info.origin.orientation.w = 1.0;
+        info
+    }
+
+    pub fn make_occupancy_grid_msg(gridmap: &TownGridMap, frame_id: &str, stamp: rosrust::Time) -> msg::nav_msgs::OccupancyGrid {
+        let info = &gridmap.info;
+        let mut msg = msg::nav_msgs::OccupancyGrid::default();
+        msg.header.frame_id = String::from(frame_id);
+        msg.header.stamp = stamp;
+        msg.info = make_map_meta_data(info.resolution, info.width, info.height, info.origin);
+        msg.data = town_occupancy_data(gridmap);
+        msg
+    }
+
+    // publishes the town on the latched /map, the publisher has to be kept alive
+    pub struct MapPublisher {
+        map_pub: RosTopic<msg::nav_msgs::OccupancyGrid>,
+        map: Option<msg::nav_msgs::OccupancyGrid>,
+    }
+
+    impl MapPublisher {
+        pub fn new() -> MapPublisher {
+            MapPublisher {map_pub: RosTopic::latched("/map"), map: None}
+        }
+
+        pub fn publish(&mut self, gridmap: &TownGridMap) {
+            self.map = Some(make_occupancy_grid_msg(gridmap, "map", rosrust::Time::default()));
+            self.send();
+        }
+
+        // to be called periodically, sends the map again after a master restart
+        pub fn refresh(&mut self) {
+            if self.map_pub.needs_advertising() {
+                self.send();
+            }
+        }
+
+        fn send(&mut self) {
+            let stamp = match ros_now() {
+                Some(time) => time,
+                None => return
+            };
+            if let Some(ref mut map) = self.map {
+                map.header.stamp = stamp;
+                self.map_pub.send(map.clone());
+            }
+        }
+    }
+
+    // publishes the local costmap on /roadsim2d/local_costmap, in the map frame
+    pub struct LocalCostmapPublisher {
+        costmap_pub: RosTopic<msg::nav_msgs::OccupancyGrid>,
+    }
+
+    impl LocalCostmapPublisher {
+        pub fn new() -> LocalCostmapPublisher {
+            LocalCostmapPublisher {costmap_pub: RosTopic::new("/roadsim2d/local_costmap")}
+        }
+    }
+
+    impl LocalCostmapListener for LocalCostmapPublisher {
+        fn on_local_costmap(&mut self, costmap: &LocalCostmap) {
+            let stamp = match ros_now() {
+                Some(time) => time,
+                None => return
+            };
+            let mut msg = msg::nav_msgs::OccupancyGrid::default();
+            msg.header.frame_id = String::from("map");
+            msg.header.stamp = stamp;
+            msg.info = make_map_meta_data(costmap.resolution, costmap.width, costmap.height, costmap.origin);
+            msg.data = costmap.data.clone();
+            self.costmap_pub.send(msg);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vehicles_are_lethal_and_inflated() {
+        let mut gridmap = TownGridMap::new(TownMapInfo::centered(100, 100, 1.0));
+        for x in 0..100 {
+            for y in 0..100 {
+                gridmap.set_cell(x, y, TownCell::Free);
+            }
+        }
+        let params = LocalCostmapParams {size: 20.0, resolution: 0.5, inflation_radius: 2.0, rate: 1.0};
+        let vehicle = (Pose2DF64 {center: Point2f64::new(5.0, 0.0), yaw: 0.0}, Size2f64::new(2.0, 4.0));
+        let costmap = make_local_costmap(&params, Point2f64::new(0.0, 0.0), &gridmap, &[vehicle]);
+        assert_eq!(40, costmap.width);
+
+        let cell = |x: f64, y: f64| costmap.value(((x - costmap.origin.x) / costmap.resolution) as usize,
+                                                  ((y - costmap.origin.y) / costmap.resolution) as usize);
+        assert_eq!(OCCUPANCY_LETHAL, cell(5.1, 0.1));
+        // 1 m and 1.5 m from the closest lethal cell
+        let near = cell(7.6, 0.1);
+        let far = cell(8.1, 0.1);
+        assert!(near > far && far > OCCUPANCY_FREE && near < OCCUPANCY_LETHAL);
+        assert_eq!(OCCUPANCY_FREE, cell(-5.1, 0.1));
+    }
+}
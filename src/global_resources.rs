@@ -2,4 +2,52 @@
 pub struct UpdateDeltaTime {
     pub dt:       f64,
     pub sim_time: f64,
+    // incremented by every scenario load, the sim time starts again from zero
+    pub scenario_count: usize,
+}
+
+// lets an output through at most `rate` times per simulated second, starting over
+// when a scenario is loaded
+pub struct RateLimiter {
+    next_time: f64,
+    scenario_count: usize,
+}
+
+impl RateLimiter {
+    pub fn new() -> RateLimiter {
+        RateLimiter {next_time: 0.0, scenario_count: 0}
+    }
+
+    pub fn ready(&mut self, update_delta_time: &UpdateDeltaTime, rate: f64) -> bool {
+        if update_delta_time.scenario_count != self.scenario_count {
+            self.scenario_count = update_delta_time.scenario_count;
+            self.next_time = 0.0;
+        }
+        if update_delta_time.sim_time < self.next_time {
+            return false;
+        }
+        self.next_time = update_delta_time.sim_time + 1.0 / rate;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_limiter_starts_over_with_the_scenario() {
+        let mut limiter = RateLimiter::new();
+        let mut time = UpdateDeltaTime {dt: 0.1, sim_time: 5.0, scenario_count: 0};
+        assert!(limiter.ready(&time, 2.0));
+        time.sim_time = 5.1;
+        assert!(!limiter.ready(&time, 2.0));
+        time.sim_time = 5.5;
+        assert!(limiter.ready(&time, 2.0));
+
+        time = UpdateDeltaTime {dt: 0.1, sim_time: 0.1, scenario_count: 1};
+        assert!(limiter.ready(&time, 2.0));
+        time.sim_time = 0.2;
+        assert!(!limiter.ready(&time, 2.0));
+    }
 }
@@ -0,0 +1,256 @@
+use specs::{System, ReadStorage, ReadExpect, WriteExpect, Join};
+use rand::{Rng, SeedableRng, StdRng};
+
+use super::primitives::*;
+use super::node::*;
+use super::protagonist::*;
+use super::global_resources::*;
+use super::raycast::*;
+use super::sensor_noise::*;
+use super::sensor_output::*;
+
+// WGS84
+const EARTH_SEMI_MAJOR_AXIS : f64 = 6378137.0;
+const EARTH_ECCENTRICITY_SQUARED : f64 = 6.69437999014e-3;
+
+// geodetic position of the origin of the map frame, x points east and y north
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct GeoReference {
+    // degrees
+    pub latitude: f64,
+    pub longitude: f64,
+    // meters
+    pub altitude: f64,
+}
+
+impl Default for GeoReference {
+    fn default() -> Self {
+        GeoReference {latitude: 45.0, longitude: 9.0, altitude: 100.0}
+    }
+}
+
+impl GeoReference {
+    // local tangent plane approximation, good enough for the size of a town
+    pub fn to_geodetic(&self, point: Point2f64) -> (f64, f64) {
+        let latitude = self.latitude.to_radians();
+        let sin_squared = latitude.sin().powi(2);
+        let denominator = 1.0 - EARTH_ECCENTRICITY_SQUARED * sin_squared;
+        let meridian_radius = EARTH_SEMI_MAJOR_AXIS * (1.0 - EARTH_ECCENTRICITY_SQUARED) / denominator.powf(1.5);
+        let normal_radius = EARTH_SEMI_MAJOR_AXIS / denominator.sqrt();
+        (self.latitude + (point.y / meridian_radius).to_degrees(),
+         self.longitude + (point.x / (normal_radius * latitude.cos())).to_degrees())
+    }
+}
+
+// sim time interval without fix
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct GnssOutage {
+    pub start: f64,
+    pub end: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GnssParams {
+    pub frame_id: String,
+    // position of the antenna
+    pub mount: SensorMount,
+    // fixes per second
+    pub rate: f64,
+    pub geo_reference: GeoReference,
+    // meters, horizontal. The vertical error is twice as large
+    pub position_stddev: f64,
+    pub outages: Vec<GnssOutage>,
+    // random outages per second and their mean length in seconds
+    pub outage_rate: f64,
+    pub mean_outage_duration: f64,
+}
+
+impl Default for GnssParams {
+    fn default() -> Self {
+        GnssParams {
+            frame_id: String::from("gnss"),
+            mount: SensorMount::default(),
+            rate: 10.0,
+            geo_reference: GeoReference::default(),
+            position_stddev: 1.5,
+            outages: Vec::new(),
+            outage_rate: 0.0,
+            mean_outage_duration: 5.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GnssFix {
+    pub frame_id: String,
+    pub sim_time: f64,
+    // false during outages, the position is then meaningless
+    pub fix: bool,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub altitude: f64,
+    pub position_stddev: f64,
+}
+
+pub struct SimulatedGnss {
+    pub params: GnssParams,
+    next_fix_time: f64,
+    random_outage_end: f64,
+    rng: StdRng,
+}
+
+impl SimulatedGnss {
+    fn in_outage(&mut self, sim_time: f64) -> bool {
+        if sim_time >= self.random_outage_end && self.params.outage_rate > 0.0 &&
+            self.rng.gen_range(0.0, 1.0) < self.params.outage_rate / self.params.rate {
+            let duration = -self.params.mean_outage_duration * self.rng.gen_range(std::f64::EPSILON, 1.0).ln();
+            self.random_outage_end = sim_time + duration;
+        }
+        sim_time < self.random_outage_end ||
+            self.params.outages.iter().any(|outage| outage.start <= sim_time && sim_time < outage.end)
+    }
+
+    pub fn measure(&mut self, sim_time: f64, protagonist_pose: &Pose2DF64) -> GnssFix {
+        let fix = !self.in_outage(sim_time);
+        let antenna = self.params.mount.world_pose(protagonist_pose).center;
+        let noisy_antenna = Point2f64::new(antenna.x + gaussian_noise(&mut self.rng, self.params.position_stddev),
+                                           antenna.y + gaussian_noise(&mut self.rng, self.params.position_stddev));
+        let (latitude, longitude) = self.params.geo_reference.to_geodetic(noisy_antenna);
+        GnssFix {
+            frame_id: self.params.frame_id.clone(),
+            sim_time: sim_time,
+            fix: fix,
+            latitude: latitude,
+            longitude: longitude,
+            altitude: self.params.geo_reference.altitude + gaussian_noise(&mut self.rng, 2.0 * self.params.position_stddev),
+            position_stddev: self.params.position_stddev,
+        }
+    }
+}
+
+pub struct GnssState {
+    pub receivers: Vec<SimulatedGnss>,
+    pending_fixes: Vec<GnssFix>,
+}
+
+impl GnssState {
+    pub fn new(receivers: Vec<GnssParams>, seed: u32) -> GnssState {
+        GnssState {
+            receivers: receivers.into_iter().enumerate().map(|(i, params)| SimulatedGnss {
+                params: params,
+                next_fix_time: 0.0,
+                random_outage_end: 0.0,
+                rng: SeedableRng::from_seed(&[seed as usize, i][..]),
+            }).collect(),
+            pending_fixes: Vec::new(),
+        }
+    }
+}
+
+impl SensorOutputs for GnssState {
+    type Measurement = GnssFix;
+
+    fn pop_ready(&mut self, _sim_time: f64, ready: &mut Vec<GnssFix>) {
+        ready.extend(self.pending_fixes.drain(..));
+    }
+}
+
+// measures the receivers mounted on the protagonist
+pub struct GnssSys;
+
+impl <'a> System<'a> for GnssSys {
+    type SystemData = (
+        ReadExpect<'a, UpdateDeltaTime>,
+        ReadStorage<'a, Node>,
+        ReadStorage<'a, ProtagonistTag>,
+        WriteExpect<'a, GnssState>,
+    );
+
+    fn run(&mut self, (update_delta_time, nodes, protagonists, mut gnss_state): Self::SystemData) {
+        let sim_time = update_delta_time.sim_time;
+        let gnss_state = &mut *gnss_state;
+        for (node, _protagonist) in (&nodes, &protagonists).join() {
+            for receiver in gnss_state.receivers.iter_mut() {
+                if sim_time < receiver.next_fix_time {
+                    continue;
+                }
+                receiver.next_fix_time = sim_time + 1.0 / receiver.params.rate;
+                gnss_state.pending_fixes.push(receiver.measure(sim_time, &node.pose));
+            }
+        }
+    }
+}
+
+#[cfg(feature = "ros")]
+pub use self::ros::*;
+
+#[cfg(feature = "ros")]
+mod ros {
+    use super::*;
+    use crate::ros_bridge::*;
+    use crate::msg;
+
+    // publishes every receiver on /roadsim2d/<frame_id>/fix
+    pub struct GnssPublisher {
+        fix_pubs: FrameTopics<msg::sensor_msgs::NavSatFix>,
+    }
+
+    impl GnssPublisher {
+        pub fn new() -> GnssPublisher {
+            GnssPublisher {fix_pubs: FrameTopics::new("fix")}
+        }
+    }
+
+    // sensor_msgs/NavSatStatus and NavSatFix constants
+    const NAV_SAT_STATUS_NO_FIX : i8 = -1;
+    const NAV_SAT_STATUS_FIX : i8 = 0;
+    const NAV_SAT_SERVICE_GPS : u16 = 1;
+    const NAV_SAT_COVARIANCE_TYPE_DIAGONAL_KNOWN : u8 = 2;
+
+    impl SensorListener<GnssFix> for GnssPublisher {
+        fn on_measurement(&mut self, fix: &GnssFix) {
+            let stamp = match sim_time_stamp(fix.sim_time) {
+                Some(time) => time,
+                None => return
+            };
+
+            let mut msg = msg::sensor_msgs::NavSatFix::default();
+            msg.header.frame_id = fix.frame_id.clone();
+            msg.header.stamp = stamp;
+            msg.status.status = if fix.fix { NAV_SAT_STATUS_FIX } else { NAV_SAT_STATUS_NO_FIX };
+            msg.status.service = NAV_SAT_SERVICE_GPS;
+            msg.latitude = fix.latitude;
+            msg.longitude = fix.longitude;
+            msg.altitude = fix.altitude;
+            let variance = fix.position_stddev.powi(2);
+            msg.position_covariance = [variance, 0.0, 0.0, 0.0, variance, 0.0, 0.0, 0.0, 4.0 * variance];
+            msg.position_covariance_type = NAV_SAT_COVARIANCE_TYPE_DIAGONAL_KNOWN;
+            self.fix_pubs.send(&fix.frame_id, msg);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn map_offsets_to_geodetic() {
+        let reference = GeoReference {latitude: 45.0, longitude: 9.0, altitude: 0.0};
+        assert_eq!((45.0, 9.0), reference.to_geodetic(Point2f64::new(0.0, 0.0)));
+        // about 111.1 km per degree of latitude and 78.8 km per degree of longitude at 45 degrees
+        let (latitude, longitude) = reference.to_geodetic(Point2f64::new(1000.0, 1000.0));
+        assert!((latitude - 45.0 - 1000.0 / 111_132.0).abs() < 1e-5);
+        assert!((longitude - 9.0 - 1000.0 / 78_847.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn no_fix_during_scheduled_outages() {
+        let params = GnssParams {outages: vec![GnssOutage {start: 1.0, end: 2.0}], ..GnssParams::default()};
+        let mut gnss = GnssState::new(vec![params], 0).receivers.remove(0);
+        assert!(gnss.measure(0.5, &Pose2DF64::default()).fix);
+        assert!(!gnss.measure(1.5, &Pose2DF64::default()).fix);
+        assert!(gnss.measure(2.0, &Pose2DF64::default()).fix);
+    }
+}
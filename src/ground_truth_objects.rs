@@ -0,0 +1,303 @@
+use specs::{System, ReadStorage, ReadExpect, Join};
+
+use super::primitives::*;
+use super::car::*;
+use super::node::*;
+use super::physics::*;
+use super::protagonist::*;
+use super::global_resources::*;
+use super::traffic::*;
+use super::ibeo::IbeoClassification;
+
+const GROUND_TRUTH_RATE : f64 = 10.0;
+
+#[derive(Debug, Clone)]
+pub struct GroundTruthObject {
+    pub id: u64,
+    pub classification: IbeoClassification,
+    pub pose: Pose2DF64,
+    pub bb_size: Size2f64,
+    pub velocity: Vec2f64,
+    pub yaw_rate: f64,
+}
+
+impl GroundTruthObject {
+    // corners of the box, counterclockwise from the front left one
+    pub fn footprint(&self) -> Vec<Point2f64> {
+        let (sin, cos) = self.pose.yaw.sin_cos();
+        let (half_length, half_width) = (self.bb_size.height / 2.0, self.bb_size.width / 2.0);
+        [(1.0, 1.0), (-1.0, 1.0), (-1.0, -1.0), (1.0, -1.0)].iter().map(|(lon, lat)| {
+            Point2f64::new(self.pose.center.x + cos * lon * half_length - sin * lat * half_width,
+                           self.pose.center.y + sin * lon * half_length + cos * lat * half_width)
+        }).collect()
+    }
+
+    // the same object seen from the protagonist, the velocity stays absolute
+    pub fn to_base_link(&self, protagonist_pose: &Pose2DF64) -> GroundTruthObject {
+        let (sin, cos) = protagonist_pose.yaw.sin_cos();
+        let (dx, dy) = (self.pose.center.x - protagonist_pose.center.x, self.pose.center.y - protagonist_pose.center.y);
+        GroundTruthObject {
+            pose: Pose2DF64 {
+                center: Point2f64::new(cos * dx + sin * dy, -sin * dx + cos * dy),
+                yaw: self.pose.yaw - protagonist_pose.yaw
+            },
+            velocity: Vec2f64::new(cos * self.velocity.x + sin * self.velocity.y, -sin * self.velocity.x + cos * self.velocity.y),
+            ..self.clone()
+        }
+    }
+}
+
+pub trait GroundTruthListener {
+    // objects are in the map frame
+    fn on_ground_truth_objects(&mut self, protagonist_pose: &Pose2DF64, objects: &[GroundTruthObject]);
+}
+
+// every vehicle but the protagonist and every traffic agent, in the map frame
+pub fn collect_ground_truth_objects(physics_world: &PhysicsWorld, traffic: &TrafficState, cars: &ReadStorage<Car>, nodes: &ReadStorage<Node>,
+    physics_components: &ReadStorage<PhysicsComponent>, protagonists: &ReadStorage<ProtagonistTag>) -> Vec<GroundTruthObject> {
+    let mut objects : Vec<GroundTruthObject> = (cars, nodes, physics_components, !protagonists).join()
+        .map(|(car, node, physics_component, ())| {
+            let rigid_body = physics_world.rigid_body(physics_component.body_handle).expect("car rigid body not found");
+            let velocity = rigid_body.velocity();
+            GroundTruthObject {
+                id: car.id,
+                classification: IbeoClassification::CAR,
+                pose: node.pose.clone(),
+                bb_size: car.bb_size,
+                velocity: Vec2f64::new(velocity.linear.x, velocity.linear.y),
+                yaw_rate: velocity.angular,
+            }
+        }).collect();
+
+    let traffic_size = traffic.vehicle_size();
+    objects.extend(traffic.agents.iter().map(|agent| {
+        GroundTruthObject {
+            id: traffic.object_id(agent),
+            classification: IbeoClassification::CAR,
+            velocity: traffic.agent_velocity(agent),
+            pose: traffic.agent_pose(agent),
+            bb_size: traffic_size,
+            yaw_rate: 0.0,
+        }
+    }));
+    objects
+}
+
+pub struct GroundTruthObjectsSys {
+    pub listeners: Vec<Box<GroundTruthListener>>,
+    rate_limiter: RateLimiter,
+}
+
+impl GroundTruthObjectsSys {
+    pub fn new(listeners: Vec<Box<GroundTruthListener>>) -> GroundTruthObjectsSys {
+        GroundTruthObjectsSys {listeners: listeners, rate_limiter: RateLimiter::new()}
+    }
+}
+
+impl <'a> System<'a> for GroundTruthObjectsSys {
+    type SystemData = (
+        ReadExpect<'a, UpdateDeltaTime>,
+        ReadExpect<'a, PhysicsWorld>,
+        ReadExpect<'a, TrafficState>,
+        ReadStorage<'a, Car>,
+        ReadStorage<'a, Node>,
+        ReadStorage<'a, PhysicsComponent>,
+        ReadStorage<'a, ProtagonistTag>,
+    );
+
+    fn run(&mut self, (update_delta_time, physics_world, traffic, cars, nodes, physics_components, protagonists): Self::SystemData) {
+        if !self.rate_limiter.ready(&update_delta_time, GROUND_TRUTH_RATE) {
+            return;
+        }
+
+        let protagonist_pose = match (&nodes, &protagonists).join().next() {
+            Some((node, _protagonist)) => node.pose.clone(),
+            None => return
+        };
+
+        let objects = collect_ground_truth_objects(&physics_world, &traffic, &cars, &nodes, &physics_components, &protagonists);
+
+        for listener in self.listeners.iter_mut() {
+            listener.on_ground_truth_objects(&protagonist_pose, &objects);
+        }
+    }
+}
+
+#[cfg(feature = "ros")]
+pub use self::ros::*;
+
+#[cfg(feature = "ros")]
+mod ros {
+    use super::*;
+    use crate::ros_bridge::*;
+    use crate::msg;
+
+    const OBJECT_MARKER_HEIGHT : f64 = 1.5;
+
+    // derived_object_msgs/Object constants
+    const OBJECT_TRACKED : u8 = 1;
+    const OBJECT_CLASSIFICATION_UNKNOWN : u8 = 0;
+    const OBJECT_CLASSIFICATION_UNKNOWN_SMALL : u8 = 1;
+    const OBJECT_CLASSIFICATION_UNKNOWN_BIG : u8 = 3;
+    const OBJECT_CLASSIFICATION_PEDESTRIAN : u8 = 4;
+    const OBJECT_CLASSIFICATION_BIKE : u8 = 5;
+    const OBJECT_CLASSIFICATION_CAR : u8 = 6;
+    const OBJECT_CLASSIFICATION_TRUCK : u8 = 7;
+
+    // shape_msgs/SolidPrimitive
+    const SOLID_PRIMITIVE_BOX : u8 = 1;
+
+    // visualization_msgs/Marker
+    const MARKER_CUBE : i32 = 1;
+    const MARKER_TEXT_VIEW_FACING : i32 = 9;
+    const MARKER_ADD : i32 = 0;
+    const MARKER_DELETEALL : i32 = 3;
+
+    fn object_classification(classification: IbeoClassification) -> u8 {
+        match classification {
+            IbeoClassification::UNKNOWN_SMALL => OBJECT_CLASSIFICATION_UNKNOWN_SMALL,
+            IbeoClassification::UNKNOWN_BIG => OBJECT_CLASSIFICATION_UNKNOWN_BIG,
+            IbeoClassification::PEDESTRIAN => OBJECT_CLASSIFICATION_PEDESTRIAN,
+            IbeoClassification::BIKE => OBJECT_CLASSIFICATION_BIKE,
+            IbeoClassification::CAR => OBJECT_CLASSIFICATION_CAR,
+            IbeoClassification::TRUCK => OBJECT_CLASSIFICATION_TRUCK,
+            _ => OBJECT_CLASSIFICATION_UNKNOWN,
+        }
+    }
+
+    fn make_pose_msg(pose: &Pose2DF64) -> msg::geometry_msgs::Pose {
+        let mut msg = msg::geometry_msgs::Pose::default();
+        msg.position.x = pose.center.x;
+        msg.position.y = pose.center.y;
+        msg.orientation.w = (pose.yaw / 2.0).cos();
+        msg.orientation.z = (pose.yaw / 2.0).sin();
+        msg
+    }
+
+    pub fn make_object_array_msg(frame_id: &str, stamp: &rosrust::Time, objects: &[GroundTruthObject]) -> msg::derived_object_msgs::ObjectArray {
+        let mut msg = msg::derived_object_msgs::ObjectArray::default();
+        msg.header.frame_id = String::from(frame_id);
+        msg.header.stamp = stamp.clone();
+        for object in objects {
+            let mut object_msg = msg::derived_object_msgs::Object::default();
+            object_msg.header = msg.header.clone();
+            object_msg.id = object.id as u32;
+            object_msg.detection_level = OBJECT_TRACKED;
+            object_msg.object_classified = true;
+            object_msg.pose = make_pose_msg(&object.pose);
+            object_msg.twist.linear.x = object.velocity.x;
+            object_msg.twist.linear.y = object.velocity.y;
+            object_msg.twist.angular.z = object.yaw_rate;
+            for corner in object.footprint() {
+                object_msg.polygon.points.push(msg::geometry_msgs::Point32 {x: corner.x as f32, y: corner.y as f32, z: 0.0});
+            }
+            object_msg.shape.type_ = SOLID_PRIMITIVE_BOX;
+            object_msg.shape.dimensions = vec![object.bb_size.height, object.bb_size.width, OBJECT_MARKER_HEIGHT];
+            object_msg.classification = object_classification(object.classification);
+            object_msg.classification_certainty = 255;
+            msg.objects.push(object_msg);
+        }
+        msg
+    }
+
+    pub fn make_object_markers_msg(frame_id: &str, stamp: &rosrust::Time, objects: &[GroundTruthObject]) -> msg::visualization_msgs::MarkerArray {
+        let mut msg = msg::visualization_msgs::MarkerArray::default();
+        // the objects that left the scene lose their marker
+        let mut clear = msg::visualization_msgs::Marker::default();
+        clear.header.frame_id = String::from(frame_id);
+        clear.action = MARKER_DELETEALL;
+        msg.markers.push(clear);
+
+        for object in objects {
+            let mut marker = msg::visualization_msgs::Marker::default();
+            marker.header.frame_id = String::from(frame_id);
+            marker.header.stamp = stamp.clone();
+            marker.ns = String::from("objects");
+            marker.id = object.id as i32;
+            marker.type_ = MARKER_CUBE;
+            marker.action = MARKER_ADD;
+            marker.pose = make_pose_msg(&object.pose);
+            marker.pose.position.z = OBJECT_MARKER_HEIGHT / 2.0;
+            marker.scale.x = object.bb_size.height;
+            marker.scale.y = object.bb_size.width;
+            marker.scale.z = OBJECT_MARKER_HEIGHT;
+            marker.color = msg::std_msgs::ColorRGBA {r: 0.2, g: 0.4, b: 1.0, a: 0.6};
+
+            let mut label = marker.clone();
+            label.ns = String::from("object_ids");
+            label.type_ = MARKER_TEXT_VIEW_FACING;
+            label.pose.position.z = OBJECT_MARKER_HEIGHT + 0.5;
+            label.scale.z = 1.0;
+            label.color = msg::std_msgs::ColorRGBA {r: 1.0, g: 1.0, b: 1.0, a: 1.0};
+            label.text = format!("{}", object.id);
+
+            msg.markers.push(marker);
+            msg.markers.push(label);
+        }
+        msg
+    }
+
+    // publishes on /roadsim2d/ground_truth/{objects,markers}_{map,base_link}
+    pub struct GroundTruthPublisher {
+        objects_map_pub: RosTopic<msg::derived_object_msgs::ObjectArray>,
+        objects_base_link_pub: RosTopic<msg::derived_object_msgs::ObjectArray>,
+        markers_map_pub: RosTopic<msg::visualization_msgs::MarkerArray>,
+        markers_base_link_pub: RosTopic<msg::visualization_msgs::MarkerArray>,
+    }
+
+    impl GroundTruthPublisher {
+        pub fn new() -> GroundTruthPublisher {
+            GroundTruthPublisher {
+                objects_map_pub: RosTopic::new("/roadsim2d/ground_truth/objects_map"),
+                objects_base_link_pub: RosTopic::new("/roadsim2d/ground_truth/objects_base_link"),
+                markers_map_pub: RosTopic::new("/roadsim2d/ground_truth/markers_map"),
+                markers_base_link_pub: RosTopic::new("/roadsim2d/ground_truth/markers_base_link"),
+            }
+        }
+    }
+
+    impl GroundTruthListener for GroundTruthPublisher {
+        fn on_ground_truth_objects(&mut self, protagonist_pose: &Pose2DF64, objects: &[GroundTruthObject]) {
+            let stamp = match ros_now() {
+                Some(time) => time,
+                None => return
+            };
+            let base_link_objects : Vec<GroundTruthObject> = objects.iter()
+                .map(|object| object.to_base_link(protagonist_pose))
+                .collect();
+
+            self.objects_map_pub.send(make_object_array_msg("map", &stamp, objects));
+            self.objects_base_link_pub.send(make_object_array_msg("base_link", &stamp, &base_link_objects));
+            self.markers_map_pub.send(make_object_markers_msg("map", &stamp, objects));
+            self.markers_base_link_pub.send(make_object_markers_msg("base_link", &stamp, &base_link_objects));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn object_in_base_link() {
+        let object = GroundTruthObject {
+            id: 3,
+            classification: IbeoClassification::CAR,
+            pose: Pose2DF64 {center: Point2f64::new(10.0, 12.0), yaw: 1.0},
+            bb_size: Size2f64::new(2.0, 4.0),
+            velocity: Vec2f64::new(0.0, 5.0),
+            yaw_rate: 0.0,
+        };
+        let protagonist_pose = Pose2DF64 {center: Point2f64::new(10.0, 2.0), yaw: std::f64::consts::PI / 2.0};
+        let seen = object.to_base_link(&protagonist_pose);
+        // 10 m straight ahead, driving in the same direction
+        assert!((seen.pose.center.x - 10.0).abs() < 1e-9 && seen.pose.center.y.abs() < 1e-9);
+        assert!((seen.velocity.x - 5.0).abs() < 1e-9 && seen.velocity.y.abs() < 1e-9);
+        assert!((seen.pose.yaw - (1.0 - std::f64::consts::PI / 2.0)).abs() < 1e-9);
+
+        let footprint = object.footprint();
+        assert_eq!(4, footprint.len());
+        let centroid_x = footprint.iter().map(|p| p.x).sum::<f64>() / 4.0;
+        assert!((centroid_x - 10.0).abs() < 1e-9);
+    }
+}
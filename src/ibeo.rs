@@ -3,17 +3,25 @@ use super::protagonist::*;
 use super::primitives::*;
 use super::physics::*;
 use super::node::*;
+use super::speed_limit::*;
+use super::town::*;
+use super::raycast::*;
+use super::sensor_noise::*;
+use super::odometry::*;
+use super::traffic::*;
+use super::global_resources::*;
+use rand::SeedableRng;
 
-use super::msg;
-use rosrust::api::raii::Publisher;
 use cgmath::*;
 use std::collections::HashMap;
 use nphysics2d::world::World as PWorld;
+use nphysics2d::object::BodyHandle;
 use specs::{System, DispatcherBuilder, World, Builder, ReadStorage, WriteStorage,
  Read, ReadExpect, WriteExpect, RunNow, Entities, LazyUpdate, Join, VecStorage, Component};
 use std::time;
 
-enum IbeoClassification {
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum IbeoClassification {
     UNCLASSIFIED,
     UNKNOWN_SMALL,
     UNKNOWN_BIG,
@@ -24,210 +32,326 @@ enum IbeoClassification {
     UNDERIVABLE,
 }
 
-struct IbeoVehicleState {
-    id: i32,
-    pose: Pose2DF64,
-    bb_size: Size2f64,
-    longitudinal_speed: f64,
-    age: i32
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct IbeoParams {
+    pub frame_id: String,
+    pub mount: SensorMount,
+    // object lists per second
+    pub rate: f64,
+    pub max_range: f64,
+    // horizontal field of view, centered on the mount yaw
+    pub fov: f64,
+    // spacing of the rays used to find the visible part of the objects
+    pub angular_resolution: f64,
+    pub noise: ObjectNoiseParams,
 }
 
-fn publish_tf_trasl_euler(tf_pub: &mut Publisher<msg::tf2_msgs::TFMessage>, frame: &str, child_frame: &str, 
-    x: f64, y: f64, z: f64, roll: f64, pitch: f64, yaw: f64, time: &rosrust::Time) {
-
-    let mut msg = msg::tf2_msgs::TFMessage::default();
-    let mut transform = msg::geometry_msgs::TransformStamped::default();
-
-    transform.header.stamp = time.clone();
-    transform.header.frame_id = String::from(frame);
-    transform.child_frame_id = String::from(child_frame);
-
-    // let car_center = protagonist.pose.center;
-    transform.transform.translation.x = x;
-    transform.transform.translation.y = y;
-    transform.transform.translation.z = z;
-
-    assert!(roll == 0.0);
-    assert!(pitch == 0.0);
-
-    transform.transform.rotation.w = (yaw / 2.0).cos();
-    transform.transform.rotation.z = (yaw / 2.0).sin();
-
-    msg.transforms.push(transform);
-    tf_pub.send(msg).unwrap();
-}
-
-
-pub struct IbeoPublisher {
-    ibeo_vehicle_pub: Publisher<msg::ibeo_msgs::ObjectListEcu>,
-    tf_pub: Publisher<msg::tf2_msgs::TFMessage>,
-    protagonist_odom_pub: Publisher<msg::nav_msgs::Odometry>,
-    protagonist_pose_pub: Publisher<msg::geometry_msgs::Pose>,
-}
-
-impl IbeoPublisher {
-    pub fn try_new() -> Option<IbeoPublisher> {
-        let ros_not_available_error_msg = "roscore not started or it is not possible to connect to it";
-        let ros_init_result = rosrust::try_init("roadsim2d");
-        if ros_init_result.is_err() {
-            None            
-        } else {
-            let ibeo_vehicle_pub = rosrust::publish("/roadsim2d/vehicle_ibeo").expect(ros_not_available_error_msg);
-            let tf_pub = rosrust::publish("/tf").expect(ros_not_available_error_msg);
-            let protagonist_odom_pub = rosrust::publish("/odom").expect(ros_not_available_error_msg);
-            let protagonist_pose_pub = rosrust::publish("/roadsim2d/pose").expect(ros_not_available_error_msg);
-            let ibeo_publisher = IbeoPublisher {
-                ibeo_vehicle_pub: ibeo_vehicle_pub,
-                tf_pub: tf_pub,
-                protagonist_odom_pub: protagonist_odom_pub,
-                protagonist_pose_pub: protagonist_pose_pub
-            };
-            Some(ibeo_publisher)
+impl Default for IbeoParams {
+    fn default() -> Self {
+        IbeoParams {
+            frame_id: String::from("ibeo"),
+            mount: SensorMount::default(),
+            rate: 30.0,
+            max_range: 200.0,
+            fov: 110.0f64.to_radians(),
+            angular_resolution: 0.25f64.to_radians(),
+            noise: ObjectNoiseParams::default(),
         }
     }
 }
 
-pub trait VehicleStatesListener { 
-    fn on_protagonist_state<'a>(&'a mut self, protagonist_pose: &'a Pose2DF64, protagonist_speed : f64, protagonist_yaw_rate: f64);
-    fn on_vehicle_states<'a>(&'a mut self, protagonist_pose: &'a Pose2DF64, vehicle_states : &'a Vec<IbeoVehicleState>);
-}
-
-impl VehicleStatesListener for IbeoPublisher {
-
-    fn on_protagonist_state<'a>(&'a mut self, protagonist_pose: &'a Pose2DF64, protagonist_speed : f64, protagonist_yaw_rate: f64) {
-        // transform.header.stamp = rosrust::now();
-        // transform.header.frame_id = String::from("odom");
-        // transform.child_frame_id = String::from("base_link");
-
-        let car_center = protagonist_pose.center;
-        // transform.transform.translation.x = car_center.x;
-        // transform.transform.translation.y = car_center.y;
-
-        // transform.transform.rotation.w = (protagonist.pose.yaw / 2.0).cos();
-        // transform.transform.rotation.z = (protagonist.pose.yaw / 2.0).sin();
-
-        // msg.transforms.push(transform);
-        // self.tf_pub.send(msg).unwrap();
-
-        let publish_time = rosrust::now();
-
-        publish_tf_trasl_euler(&mut self.tf_pub, "map", "odom", 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, &publish_time);
-        publish_tf_trasl_euler(&mut self.tf_pub, "odom", "base_link", car_center.x, car_center.y, 0.0, 0.0, 0.0, protagonist_pose.yaw, &publish_time);
-        publish_tf_trasl_euler(&mut self.tf_pub, "base_link", "ibeo", 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, &publish_time);
-
-        {
-            let mut msg = msg::nav_msgs::Odometry {
-                header: msg::std_msgs::Header {
-                    stamp: publish_time,
-                    frame_id: String::from("map"),
-                    seq: 0,
-                },
-                child_frame_id: String::from("odom"),
-                pose: msg::geometry_msgs::PoseWithCovariance {
-                    pose: msg::geometry_msgs::Pose::default(),
-                    covariance: [0.0; 36]
-                },
-                twist: msg::geometry_msgs::TwistWithCovariance {
-                    twist: msg::geometry_msgs::Twist::default(),
-                    covariance: [0.0; 36]
-                }
-            };
-            msg.pose.pose.position.x = car_center.x;
-            msg.pose.pose.position.y = car_center.y;
+// objects seen on less than this extent along an axis keep their full size along it
+const IBEO_MIN_VISIBLE_EXTENT : f64 = 0.2;
+const IBEO_MIN_RAYS_PER_OBJECT : usize = 3;
 
-            msg.pose.pose.orientation.w = (protagonist_pose.yaw / 2.0).cos();
-            msg.pose.pose.orientation.z = (protagonist_pose.yaw / 2.0).sin();
+fn normalize_angle(angle: f64) -> f64 {
+    angle.sin().atan2(angle.cos())
+}
 
-            msg.twist.twist.linear.x = protagonist_speed;
-            msg.twist.twist.angular.z = protagonist_yaw_rate;
+// casts rays over the angular extent of the box of the car, the car is reported only if
+// some of them hit it. A partially visible box is truncated to the extent of the visible
+// points along each axis of the car, as a real sensor fitting the box to its contour does
+fn visible_box(params: &IbeoParams, sensor_pose: &Pose2DF64, gridmap: &TownGridMap, physics_world: &PWorld<f64>,
+               sensor_body: BodyHandle, car_body: BodyHandle, car_pose: &Pose2DF64, bb_size: Size2f64) -> Option<(Pose2DF64, Size2f64)> {
+    let (half_length, half_width) = (bb_size.height / 2.0, bb_size.width / 2.0);
+    let (sin, cos) = car_pose.yaw.sin_cos();
+    let corners : Vec<f64> = [(1.0, 1.0), (1.0, -1.0), (-1.0, -1.0), (-1.0, 1.0)].iter().map(|(lon, lat)| {
+        let x = car_pose.center.x + cos * lon * half_length - sin * lat * half_width;
+        let y = car_pose.center.y + sin * lon * half_length + cos * lat * half_width;
+        normalize_angle((y - sensor_pose.center.y).atan2(x - sensor_pose.center.x) - sensor_pose.yaw)
+    }).collect();
+
+    let car_distance = (car_pose.center.x - sensor_pose.center.x).hypot(car_pose.center.y - sensor_pose.center.y);
+    if car_distance > params.max_range + half_length.max(half_width) {
+        return None;
+    }
 
-            self.protagonist_odom_pub.send(msg).unwrap();
+    // the corners are measured from the direction of the center, the box never spans more than PI
+    let center_bearing = normalize_angle((car_pose.center.y - sensor_pose.center.y).atan2(car_pose.center.x - sensor_pose.center.x) - sensor_pose.yaw);
+    let offsets : Vec<f64> = corners.iter().map(|corner| normalize_angle(corner - center_bearing)).collect();
+    let min_bearing = center_bearing + offsets.iter().cloned().fold(0.0, f64::min);
+    let max_bearing = center_bearing + offsets.iter().cloned().fold(0.0, f64::max);
+
+    let rays = (((max_bearing - min_bearing) / params.angular_resolution).ceil() as usize + 1).max(IBEO_MIN_RAYS_PER_OBJECT);
+    let mut visible_points = Vec::new();
+    for i in 0..rays {
+        let bearing = min_bearing + (max_bearing - min_bearing) * i as f64 / (rays - 1) as f64;
+        if normalize_angle(bearing).abs() > params.fov / 2.0 {
+            continue;
+        }
+        let yaw = sensor_pose.yaw + bearing;
+        if let Some((distance, RayHit::Vehicle(body))) = raycast(gridmap, physics_world, sensor_pose.center, yaw, params.max_range, Some(sensor_body)) {
+            if body == car_body {
+                visible_points.push(Point2f64::new(sensor_pose.center.x + distance * yaw.cos(), sensor_pose.center.y + distance * yaw.sin()));
+            }
         }
-
-       {
-            let mut msg = msg::geometry_msgs::Pose::default();
-            msg.position.x = car_center.x;
-            msg.position.y = car_center.y;
-
-            self.protagonist_pose_pub.send(msg).unwrap();
-       }
-
     }
 
-    fn on_vehicle_states<'a>(&'a mut self, protagonist_pose: &'a Pose2DF64, vehicle_states : &'a Vec<IbeoVehicleState>) {
-        let mut msg = msg::ibeo_msgs::ObjectListEcu::default();
-        msg.header.frame_id = String::from("ibeo");
-        msg.header.stamp = rosrust::now();
+    if visible_points.is_empty() {
+        return None;
+    }
+    if visible_points.len() == rays {
+        return Some((car_pose.clone(), bb_size));
+    }
 
-        let protagonist_rot : Basis2<_> = Rotation2::<f64>::from_angle(Rad(-protagonist_pose.yaw));
+    // visible points in the car frame
+    let local : Vec<(f64, f64)> = visible_points.iter().map(|point| {
+        let (dx, dy) = (point.x - car_pose.center.x, point.y - car_pose.center.y);
+        (cos * dx + sin * dy, -sin * dx + cos * dy)
+    }).collect();
+    let truncate = |values: Vec<f64>, half_extent: f64| {
+        let min = values.iter().cloned().fold(std::f64::INFINITY, f64::min);
+        let max = values.iter().cloned().fold(std::f64::NEG_INFINITY, f64::max);
+        if max - min < IBEO_MIN_VISIBLE_EXTENT {
+            (-half_extent, half_extent)
+        } else {
+            (min.max(-half_extent), max.min(half_extent))
+        }
+    };
+    let (lon_min, lon_max) = truncate(local.iter().map(|p| p.0).collect(), half_length);
+    let (lat_min, lat_max) = truncate(local.iter().map(|p| p.1).collect(), half_width);
+    let (lon_center, lat_center) = ((lon_min + lon_max) / 2.0, (lat_min + lat_max) / 2.0);
+    let center = Point2f64::new(car_pose.center.x + cos * lon_center - sin * lat_center,
+                                car_pose.center.y + sin * lon_center + cos * lat_center);
+    Some((Pose2DF64 {center: center, yaw: car_pose.yaw}, Size2f64::new(lat_max - lat_min, lon_max - lon_min)))
+}
 
-        for vehicle_state in vehicle_states {
-            let mut object_msg = msg::ibeo_msgs::ObjectListEcuObj::default();
-            // note: id is cut to i32 here
-            object_msg.id = vehicle_state.id;
+// world frame quantities, velocity and acceleration are vectors
+#[derive(Debug, Clone)]
+pub struct IbeoVehicleState {
+    pub id: i32,
+    pub pose: Pose2DF64,
+    pub bb_size: Size2f64,
+    pub velocity: Vec2f64,
+    pub acceleration: Vec2f64,
+    pub yaw_rate: f64,
+    pub velocity_sigma: f64,
+    pub acceleration_sigma: f64,
+    pub yaw_rate_sigma: f64,
+    pub age: i32,
+    pub classification: IbeoClassification,
+    pub classification_certainty: f32,
+}
 
-            let rel_center = vehicle_state.pose.center - protagonist_pose.center;
-            let rotated_rel_center = protagonist_rot.rotate_vector(rel_center);
+// state of the protagonist carrying the sensor, in the world frame
+#[derive(Debug, Clone)]
+pub struct IbeoEgoState {
+    // when the objects were measured
+    pub sim_time: f64,
+    pub pose: Pose2DF64,
+    pub velocity: Vec2f64,
+    pub yaw_rate: f64,
+}
 
-            object_msg.classification = IbeoClassification::CAR as i32;
-            object_msg.age = vehicle_state.age;
-            object_msg.class_age = vehicle_state.age;
-            object_msg.classification_certainty = 1.0f32;
-            object_msg.bounding_box.pose.x = rotated_rel_center.x;
-            object_msg.bounding_box.pose.y = rotated_rel_center.y;
-            object_msg.bounding_box.pose.theta = vehicle_state.pose.yaw - std::f64::consts::PI / 2.0 -protagonist_pose.yaw;
-            object_msg.bounding_box.size.width = vehicle_state.bb_size.width;
-            object_msg.bounding_box.size.height = vehicle_state.bb_size.height;
+// an object as reported in the `ibeo` frame
+#[derive(Debug, Clone)]
+pub struct IbeoSensorFrameObject {
+    pub center: Vec2f64,
+    pub box_orientation: f64,
+    pub abs_vel: Vec2f64,
+    pub rel_vel: Vec2f64,
+    pub acceleration: Vec2f64,
+}
 
-            object_msg.abs_vel.x = vehicle_state.longitudinal_speed;
+// The Ibeo box size is (width, height) measured along the box x and y axes, while the
+// car length (bb_size.height) lies along its heading. The box is therefore rotated so
+// that its y axis, not its x axis, points along the heading of the car
+pub fn ibeo_box_orientation(object_yaw: f64, sensor_yaw: f64) -> f64 {
+    object_yaw - std::f64::consts::PI / 2.0 - sensor_yaw
+}
 
-            msg.objects.push(object_msg);
-        }
-        self.ibeo_vehicle_pub.send(msg).unwrap();
+// The absolute velocity is the world velocity of the object expressed in the sensor frame,
+// the relative one is the difference with the velocity of the mounting point (rotation of
+// the sensor frame itself is not taken into account)
+pub fn to_sensor_frame(sensor_mount: &SensorMount, ego: &IbeoEgoState, state: &IbeoVehicleState) -> IbeoSensorFrameObject {
+    let sensor_pose = sensor_mount.world_pose(&ego.pose);
+    let world_to_sensor : Basis2<_> = Rotation2::<f64>::from_angle(Rad(-sensor_pose.yaw));
+
+    let mount_offset = sensor_pose.center - ego.pose.center;
+    let sensor_velocity = ego.velocity + Vec2f64::new(-ego.yaw_rate * mount_offset.y, ego.yaw_rate * mount_offset.x);
+
+    IbeoSensorFrameObject {
+        center: world_to_sensor.rotate_vector(state.pose.center - sensor_pose.center),
+        box_orientation: ibeo_box_orientation(state.pose.yaw, sensor_pose.yaw),
+        abs_vel: world_to_sensor.rotate_vector(state.velocity),
+        rel_vel: world_to_sensor.rotate_vector(state.velocity - sensor_velocity),
+        acceleration: world_to_sensor.rotate_vector(state.acceleration),
     }
-
 }
 
+pub trait VehicleStatesListener { 
+    // ground truth of the protagonist in the map frame, and what its wheel odometry measures
+    fn on_protagonist_state<'a>(&'a mut self, protagonist_pose: &'a Pose2DF64, protagonist_speed : f64, protagonist_yaw_rate: f64,
+                                odometry: &'a OdometryEstimate);
+    // vehicle states are in world coordinates, `sensor_mount` places the sensor `frame_id` on the protagonist
+    fn on_vehicle_states<'a>(&'a mut self, frame_id: &'a str, sensor_mount: &'a SensorMount, ego: &'a IbeoEgoState, vehicle_states : &'a Vec<IbeoVehicleState>);
+    fn on_speed_limit(&mut self, speed_limit: Option<f64>);
+}
 
-pub struct IbeoSensorSys<'a> {
-    pub vehicle_state_listeners : &'a mut Vec<Box<VehicleStatesListener>>,
-    pub physics_world: &'a mut PWorld<f64>
+pub struct IbeoSensorSys {
+    pub vehicle_state_listeners : Vec<Box<VehicleStatesListener>>,
 }
 
-impl <'a> IbeoSensorSys<'a> {
-    pub fn new(vehicle_state_listeners : &'a mut Vec<Box<VehicleStatesListener>>,
-        physics_world: &'a mut PWorld<f64>) -> IbeoSensorSys<'a> {
-            IbeoSensorSys{vehicle_state_listeners: vehicle_state_listeners, 
-                          physics_world: physics_world}
+impl IbeoSensorSys {
+    pub fn new(vehicle_state_listeners : Vec<Box<VehicleStatesListener>>) -> IbeoSensorSys {
+            IbeoSensorSys{vehicle_state_listeners: vehicle_state_listeners}
     }
 }
 
+pub struct IbeoSensor {
+    pub params: IbeoParams,
+    age_map: HashMap<i32, i32>,
+    next_scan_time: f64,
+    // last measured velocity of every car, to derive the accelerations
+    last_velocities: HashMap<i32, (f64, Vec2f64)>,
+    noise_model: ObjectNoiseModel,
+    // protagonist state at measurement time and the measured objects
+    output_queue: LatencyQueue<(IbeoEgoState, Vec<IbeoVehicleState>)>,
+}
 
 pub struct IbeoSensorState {
-    age_map: HashMap<i32, i32>,
-    last_pub_time: time::Instant
+    pub sensors: Vec<IbeoSensor>,
+    last_pub_time: time::Instant,
 }
 
 impl IbeoSensorState {
-    pub fn new() -> IbeoSensorState {
-        IbeoSensorState{age_map: HashMap::<i32, i32>::new(), last_pub_time: time::Instant::now()}
+    pub fn new(sensors: Vec<IbeoParams>, seed: u32) -> IbeoSensorState {
+        let sensors = sensors.into_iter().enumerate().map(|(i, params)| {
+            let noise_model = ObjectNoiseModel::new(params.noise.clone(), SeedableRng::from_seed(&[seed as usize, i][..]));
+            let output_queue = LatencyQueue::new(params.noise.latency);
+            IbeoSensor{params: params, age_map: HashMap::<i32, i32>::new(), next_scan_time: 0.0,
+                       last_velocities: HashMap::new(), noise_model: noise_model, output_queue: output_queue}
+        }).collect();
+        IbeoSensorState{sensors: sensors, last_pub_time: time::Instant::now()}
+    }
+}
+
+impl IbeoSensor {
+    // state of the vehicle `id` as seen by the sensor, None if it is hidden
+    fn observe(&mut self, sensor_pose: &Pose2DF64, gridmap: &TownGridMap, physics_world: &PWorld<f64>, sensor_body: BodyHandle,
+               sim_time: f64, id: i32, body: BodyHandle, pose: &Pose2DF64, bb_size: Size2f64) -> Option<IbeoVehicleState> {
+        let visible = visible_box(&self.params, sensor_pose, gridmap, physics_world, sensor_body, body, pose, bb_size);
+        let (visible_pose, visible_size) = match visible {
+            Some(visible) => visible,
+            None => {
+                // the track is lost, it starts again from age 1 when the car is seen again
+                self.age_map.remove(&id);
+                self.last_velocities.remove(&id);
+                return None;
+            }
+        };
+
+        let rigid_body = physics_world.rigid_body(body).expect("car rigid body not found");
+        let velocity = Vec2f64::new(rigid_body.velocity().linear.x, rigid_body.velocity().linear.y);
+        let current_yaw_rate = rigid_body.velocity().angular;
+        let acceleration = match self.last_velocities.insert(id, (sim_time, velocity)) {
+            Some((last_time, last_velocity)) if sim_time > last_time => (velocity - last_velocity) / (sim_time - last_time),
+            _ => Vec2f64::new(0.0, 0.0)
+        };
+
+        let prev_age : i32 = *self.age_map.entry(id).or_insert(1);
+        let new_age = prev_age + 1;
+        self.age_map.insert(id, new_age);
+
+        Some(IbeoVehicleState{
+            id: id,
+            pose: visible_pose,
+            bb_size: visible_size,
+            velocity: velocity,
+            acceleration: acceleration,
+            yaw_rate: current_yaw_rate,
+            velocity_sigma: 0.0,
+            acceleration_sigma: 0.0,
+            yaw_rate_sigma: 0.0,
+            age: prev_age,
+            classification: IbeoClassification::CAR,
+            classification_certainty: 1.0f32,
+        })
     }
 }
 
-impl <'a, 'b> System<'a> for IbeoSensorSys<'b> {
+impl <'a> System<'a> for IbeoSensorSys {
     type SystemData = (
+        ReadExpect<'a, UpdateDeltaTime>,
+        ReadExpect<'a, PhysicsWorld>,
+        ReadExpect<'a, TownGridMap>,
+        ReadExpect<'a, TrafficState>,
         ReadStorage<'a, Car>,
         ReadStorage<'a, Node>,
         ReadStorage<'a, PhysicsComponent>,
         ReadStorage<'a, ProtagonistTag>,
+        ReadExpect<'a, SpeedLimitMap>,
+        ReadExpect<'a, WheelOdometry>,
         WriteExpect<'a, IbeoSensorState> 
     );
 
+    fn run(&mut self, (update_delta_time, physics_world, town_gridmap, traffic, cars, nodes, physics_components, protagonists, speed_limit_map, odometry, mut ibeo_state): Self::SystemData) {
+        let sim_time = update_delta_time.sim_time;
+        let protagonist = (&nodes, &physics_components, &protagonists).join()
+            .map(|(node, physics_component, _protagonist)| (node.pose.clone(), physics_component.body_handle))
+            .next();
+        let (protagonist_pose, protagonist_body) = match protagonist {
+            Some(protagonist) => protagonist,
+            None => return
+        };
+        let protagonist_velocity = physics_world.rigid_body(protagonist_body).expect("protagonist rigid body not found").velocity();
+        let ego = IbeoEgoState {
+            sim_time: sim_time,
+            pose: protagonist_pose,
+            velocity: Vec2f64::new(protagonist_velocity.linear.x, protagonist_velocity.linear.y),
+            yaw_rate: protagonist_velocity.angular,
+        };
+
+        for sensor in ibeo_state.sensors.iter_mut() {
+            if sim_time >= sensor.next_scan_time {
+                sensor.next_scan_time = sim_time + 1.0 / sensor.params.rate;
+                let sensor_pose = sensor.params.mount.world_pose(&ego.pose);
+                let mut other_car_states = Vec::<IbeoVehicleState>::new(); 
+
+                for (car, node, physics_component, ()) in (&cars, &nodes, &physics_components, !&protagonists).join() {
+                    other_car_states.extend(sensor.observe(&sensor_pose, &town_gridmap, &physics_world, protagonist_body, sim_time,
+                        car.id as i32, physics_component.body_handle, &node.pose, car.bb_size));
+                }
+                let traffic_size = traffic.vehicle_size();
+                for agent in &traffic.agents {
+                    if let Some(body) = traffic.proxy_body(agent) {
+                        other_car_states.extend(sensor.observe(&sensor_pose, &town_gridmap, &physics_world, protagonist_body, sim_time,
+                            traffic.object_id(agent) as i32, body, &traffic.agent_pose(agent), traffic_size));
+                    }
+                }
 
-    fn run(&mut self, (mut cars, nodes, physics_components, protagonists, mut ibeo_state): Self::SystemData) {
-        let mut other_car_states = Vec::<IbeoVehicleState>::new(); 
-
+                let (max_range, fov) = (sensor.params.max_range, sensor.params.fov);
+                let other_car_states = sensor.noise_model.apply(other_car_states, &sensor_pose, max_range, fov);
+                sensor.output_queue.push(sim_time, (ego.clone(), other_car_states));
+            }
 
+            while let Some((measurement_ego, measured_states)) = sensor.output_queue.pop_ready(sim_time) {
+                for listener in self.vehicle_state_listeners.iter_mut() {
+                    listener.on_vehicle_states(&sensor.params.frame_id, &sensor.params.mount, &measurement_ego, &measured_states);
+                }
+            }
+        }
 
         let now = time::Instant::now();
         let diff = now.duration_since(ibeo_state.last_pub_time);
@@ -239,39 +363,299 @@ impl <'a, 'b> System<'a> for IbeoSensorSys<'b> {
             return;
         }
 
-        for (car, node, physics_component, ()) in (&cars, &nodes, &physics_components, !&protagonists).join() {
-            let mut rigid_body = self.physics_world.rigid_body_mut(physics_component.body_handle).expect("car rigid body not found");
-            let current_speed = rigid_body.velocity().linear.norm();
-            let current_yaw_rate = rigid_body.velocity().angular;
-
-            let prev_age : i32 = *ibeo_state.age_map.entry(car.id as i32).or_insert(1);
-            let new_age = prev_age + 1;
-            ibeo_state.age_map.insert(car.id as i32, new_age);
-
-            other_car_states.push(IbeoVehicleState{
-                id: car.id as i32,
-                pose: node.pose.clone(),
-                bb_size: car.bb_size,
-                longitudinal_speed: current_speed,
-                age: prev_age
-            });
-        }
-
         for (car, node, physics_component, _protagonist) in (&cars, &nodes, &physics_components, &protagonists).join() {
-            let protagonist_car_node = node;
             for listener in &mut (self.vehicle_state_listeners).iter_mut() {
-                let protagonist_car_pose = &protagonist_car_node.pose;
-                listener.on_vehicle_states(&protagonist_car_pose, &other_car_states);
 
-
-                let mut rigid_body = self.physics_world.rigid_body_mut(physics_component.body_handle).expect("car rigid body not found");
-                let current_speed = rigid_body.velocity().linear.norm();
+                let rigid_body = physics_world.rigid_body(physics_component.body_handle).expect("car rigid body not found");
+                let velocity = rigid_body.velocity().linear;
+                let current_speed = velocity.x * node.pose.yaw.cos() + velocity.y * node.pose.yaw.sin();
                 let current_yaw_rate = rigid_body.velocity().angular;
 
-                listener.on_protagonist_state(&node.pose, current_speed, current_yaw_rate);
+                listener.on_protagonist_state(&node.pose, current_speed, current_yaw_rate, &odometry.estimate);
+                listener.on_speed_limit(speed_limit_map.speed_limit_at(&node.pose));
             }
         }
 
     }
 }
 
+#[cfg(feature = "ros")]
+pub use self::ros::*;
+
+#[cfg(feature = "ros")]
+mod ros {
+    use super::*;
+    use crate::msg;
+    use crate::ros_bridge::*;
+
+    pub fn make_tf_trasl_euler(frame: &str, child_frame: &str,
+        x: f64, y: f64, z: f64, roll: f64, pitch: f64, yaw: f64, time: &rosrust::Time) -> msg::geometry_msgs::TransformStamped {
+
+        let mut transform = msg::geometry_msgs::TransformStamped::default();
+
+        transform.header.stamp = time.clone();
+        transform.header.frame_id = String::from(frame);
+        transform.child_frame_id = String::from(child_frame);
+
+        // let car_center = protagonist.pose.center;
+        transform.transform.translation.x = x;
+        transform.transform.translation.y = y;
+        transform.transform.translation.z = z;
+
+        assert!(roll == 0.0);
+        assert!(pitch == 0.0);
+
+        transform.transform.rotation.w = (yaw / 2.0).cos();
+        transform.transform.rotation.z = (yaw / 2.0).sin();
+        transform
+    }
+
+    pub fn publish_tf_trasl_euler(tf_pub: &mut RosTopic<msg::tf2_msgs::TFMessage>, frame: &str, child_frame: &str, 
+        x: f64, y: f64, z: f64, roll: f64, pitch: f64, yaw: f64, time: &rosrust::Time) {
+
+        let mut msg = msg::tf2_msgs::TFMessage::default();
+        msg.transforms.push(make_tf_trasl_euler(frame, child_frame, x, y, z, roll, pitch, yaw, time));
+        tf_pub.send(msg);
+    }
+
+    // pose of base_link in `frame`, the twist is in base_link
+    pub fn make_odometry_msg(frame: &str, pose: &Pose2DF64, speed: f64, yaw_rate: f64, time: &rosrust::Time) -> msg::nav_msgs::Odometry {
+        let mut msg = msg::nav_msgs::Odometry::default();
+        msg.header.stamp = time.clone();
+        msg.header.frame_id = String::from(frame);
+        msg.child_frame_id = String::from("base_link");
+
+        msg.pose.pose.position.x = pose.center.x;
+        msg.pose.pose.position.y = pose.center.y;
+        msg.pose.pose.orientation.w = (pose.yaw / 2.0).cos();
+        msg.pose.pose.orientation.z = (pose.yaw / 2.0).sin();
+
+        msg.twist.twist.linear.x = speed;
+        msg.twist.twist.angular.z = yaw_rate;
+        msg
+    }
+
+    pub struct IbeoPublisher {
+        // one topic per sensor, /roadsim2d/vehicle_<frame_id>
+        ibeo_vehicle_pubs: HashMap<String, RosTopic<msg::ibeo_msgs::ObjectListEcu>>,
+        tf_pub: RosTopic<msg::tf2_msgs::TFMessage>,
+        protagonist_odom_pub: RosTopic<msg::nav_msgs::Odometry>,
+        ground_truth_pub: RosTopic<msg::nav_msgs::Odometry>,
+        protagonist_pose_pub: RosTopic<msg::geometry_msgs::Pose>,
+        speed_limit_pub: RosTopic<msg::std_msgs::Float64>,
+    }
+
+    impl IbeoPublisher {
+        // the topics are advertised once the ROS bridge is connected
+        pub fn new() -> IbeoPublisher {
+            IbeoPublisher {
+                ibeo_vehicle_pubs: HashMap::new(),
+                tf_pub: RosTopic::new("/tf"),
+                protagonist_odom_pub: RosTopic::new("/odom"),
+                ground_truth_pub: RosTopic::new("/roadsim2d/ground_truth"),
+                protagonist_pose_pub: RosTopic::new("/roadsim2d/pose"),
+                speed_limit_pub: RosTopic::new("/roadsim2d/speed_limit"),
+            }
+        }
+    }
+
+    impl VehicleStatesListener for IbeoPublisher {
+
+        fn on_protagonist_state<'a>(&'a mut self, protagonist_pose: &'a Pose2DF64, protagonist_speed : f64, protagonist_yaw_rate: f64,
+                                    odometry: &'a OdometryEstimate) {
+            let publish_time = match ros_now() {
+                Some(time) => time,
+                None => return
+            };
+
+            let odom_pose = &odometry.pose;
+            let correction = map_to_odom(protagonist_pose, odom_pose);
+            publish_tf_trasl_euler(&mut self.tf_pub, "map", "odom", correction.center.x, correction.center.y, 0.0, 0.0, 0.0, correction.yaw, &publish_time);
+            publish_tf_trasl_euler(&mut self.tf_pub, "odom", "base_link", odom_pose.center.x, odom_pose.center.y, 0.0, 0.0, 0.0, odom_pose.yaw, &publish_time);
+
+            self.protagonist_odom_pub.send(make_odometry_msg("odom", odom_pose, odometry.speed, odometry.yaw_rate, &publish_time));
+            self.ground_truth_pub.send(make_odometry_msg("map", protagonist_pose, protagonist_speed, protagonist_yaw_rate, &publish_time));
+
+           {
+                let car_center = protagonist_pose.center;
+                let mut msg = msg::geometry_msgs::Pose::default();
+                msg.position.x = car_center.x;
+                msg.position.y = car_center.y;
+
+                self.protagonist_pose_pub.send(msg);
+           }
+
+        }
+
+        fn on_vehicle_states<'a>(&'a mut self, frame_id: &'a str, sensor_mount: &'a SensorMount, ego: &'a IbeoEgoState, vehicle_states : &'a Vec<IbeoVehicleState>) {
+            // the mount is published once on /tf_static, see StaticTfPublisher
+            let publish_time = match sim_time_stamp(ego.sim_time) {
+                Some(time) => time,
+                None => return
+            };
+
+            let mut msg = msg::ibeo_msgs::ObjectListEcu::default();
+            msg.header.frame_id = String::from(frame_id);
+            msg.header.stamp = publish_time;
+
+            for vehicle_state in vehicle_states {
+                let mut object_msg = msg::ibeo_msgs::ObjectListEcuObj::default();
+                // note: id is cut to i32 here
+                object_msg.id = vehicle_state.id;
+
+                let object = to_sensor_frame(sensor_mount, ego, vehicle_state);
+
+                object_msg.classification = vehicle_state.classification as i32;
+                object_msg.age = vehicle_state.age;
+                object_msg.class_age = vehicle_state.age;
+                object_msg.classification_certainty = vehicle_state.classification_certainty;
+                object_msg.bounding_box.pose.x = object.center.x;
+                object_msg.bounding_box.pose.y = object.center.y;
+                object_msg.bounding_box.pose.theta = object.box_orientation;
+                object_msg.bounding_box.size.width = vehicle_state.bb_size.width;
+                object_msg.bounding_box.size.height = vehicle_state.bb_size.height;
+
+                object_msg.abs_vel.x = object.abs_vel.x;
+                object_msg.abs_vel.y = object.abs_vel.y;
+                object_msg.abs_vel_sigma.x = vehicle_state.velocity_sigma;
+                object_msg.abs_vel_sigma.y = vehicle_state.velocity_sigma;
+                object_msg.rel_vel.x = object.rel_vel.x;
+                object_msg.rel_vel.y = object.rel_vel.y;
+                object_msg.rel_vel_sigma.x = vehicle_state.velocity_sigma;
+                object_msg.rel_vel_sigma.y = vehicle_state.velocity_sigma;
+                object_msg.accel.x = object.acceleration.x;
+                object_msg.accel.y = object.acceleration.y;
+                object_msg.accel_sigma.x = vehicle_state.acceleration_sigma;
+                object_msg.accel_sigma.y = vehicle_state.acceleration_sigma;
+                object_msg.yaw_rate = vehicle_state.yaw_rate;
+                object_msg.yaw_rate_sigma = vehicle_state.yaw_rate_sigma;
+
+                msg.objects.push(object_msg);
+            }
+            self.ibeo_vehicle_pubs.entry(String::from(frame_id))
+                .or_insert_with(|| RosTopic::new(&format!("/roadsim2d/vehicle_{}", frame_id)))
+                .send(msg);
+        }
+
+        fn on_speed_limit(&mut self, speed_limit: Option<f64>) {
+            // no limit is published as +inf
+            let msg = msg::std_msgs::Float64 {
+                data: speed_limit.unwrap_or(std::f64::INFINITY)
+            };
+            self.speed_limit_pub.send(msg);
+        }
+
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EPS : f64 = 1e-9;
+
+    fn ego() -> IbeoEgoState {
+        IbeoEgoState {
+            sim_time: 0.0,
+            pose: Pose2DF64 {center: Point2f64::new(10.0, 5.0), yaw: 0.7},
+            velocity: Vec2f64::new(10.0 * 0.7f64.cos(), 10.0 * 0.7f64.sin()),
+            yaw_rate: 0.0,
+        }
+    }
+
+    fn vehicle(center: Point2f64, yaw: f64, velocity: Vec2f64) -> IbeoVehicleState {
+        IbeoVehicleState {
+            id: 1,
+            pose: Pose2DF64 {center: center, yaw: yaw},
+            bb_size: Size2f64::new(1.5, 3.0),
+            velocity: velocity,
+            acceleration: Vec2f64::new(0.5, -0.2),
+            yaw_rate: 0.1,
+            velocity_sigma: 0.0,
+            acceleration_sigma: 0.0,
+            yaw_rate_sigma: 0.0,
+            age: 1,
+            classification: IbeoClassification::CAR,
+            classification_certainty: 1.0,
+        }
+    }
+
+    #[test]
+    fn relative_velocity_of_static_and_following_objects() {
+        let ego = ego();
+        let mount = SensorMount {x: 1.0, y: 0.0, yaw: 0.0};
+
+        let static_object = vehicle(Point2f64::new(30.0, 20.0), 0.0, Vec2f64::new(0.0, 0.0));
+        let rel_vel = to_sensor_frame(&mount, &ego, &static_object).rel_vel;
+        assert!((rel_vel.x + 10.0).abs() < EPS);
+        assert!(rel_vel.y.abs() < EPS);
+
+        let following = vehicle(Point2f64::new(30.0, 20.0), 0.7, ego.velocity);
+        let object = to_sensor_frame(&mount, &ego, &following);
+        assert!(object.rel_vel.x.abs() < EPS && object.rel_vel.y.abs() < EPS);
+        assert!((object.abs_vel.x - 10.0).abs() < EPS && object.abs_vel.y.abs() < EPS);
+    }
+
+    // the objects against the transforms published on /tf
+    #[cfg(feature = "ros")]
+    mod tf {
+        use super::*;
+        use crate::msg;
+
+        // rotation of a vector of the xy plane by the quaternion of a transform
+        fn rotate(q: &msg::geometry_msgs::Quaternion, v: Vec2f64) -> Vec2f64 {
+            let (w, x, y, z) = (q.w, q.x, q.y, q.z);
+            Vec2f64::new((1.0 - 2.0 * (y * y + z * z)) * v.x + 2.0 * (x * y - z * w) * v.y,
+                         2.0 * (x * y + z * w) * v.x + (1.0 - 2.0 * (x * x + z * z)) * v.y)
+        }
+
+        // maps a point of the child frame to the parent frame
+        fn apply(transform: &msg::geometry_msgs::TransformStamped, p: Vec2f64) -> Vec2f64 {
+            let translation = &transform.transform.translation;
+            rotate(&transform.transform.rotation, p) + Vec2f64::new(translation.x, translation.y)
+        }
+
+        #[test]
+        fn sensor_frame_agrees_with_published_tf() {
+            let ego = ego();
+            let mount = SensorMount {x: 1.2, y: -0.4, yaw: 0.3};
+            let state = vehicle(Point2f64::new(25.0, 12.0), 2.0, Vec2f64::new(3.0, -1.0));
+            let object = to_sensor_frame(&mount, &ego, &state);
+
+            let time = rosrust::Time::default();
+            let odom_base_link = make_tf_trasl_euler("odom", "base_link", ego.pose.center.x, ego.pose.center.y, 0.0,
+                0.0, 0.0, ego.pose.yaw, &time);
+            let base_link_ibeo = make_tf_trasl_euler("base_link", "ibeo", mount.x, mount.y, 0.0, 0.0, 0.0, mount.yaw, &time);
+
+            let world_center = apply(&odom_base_link, apply(&base_link_ibeo, object.center));
+            assert!((world_center.x - state.pose.center.x).abs() < EPS);
+            assert!((world_center.y - state.pose.center.y).abs() < EPS);
+
+            let world_velocity = rotate(&odom_base_link.transform.rotation, rotate(&base_link_ibeo.transform.rotation, object.abs_vel));
+            assert!((world_velocity.x - state.velocity.x).abs() < EPS);
+            assert!((world_velocity.y - state.velocity.y).abs() < EPS);
+
+            let world_acceleration = rotate(&odom_base_link.transform.rotation, rotate(&base_link_ibeo.transform.rotation, object.acceleration));
+            assert!((world_acceleration.x - state.acceleration.x).abs() < EPS);
+            assert!((world_acceleration.y - state.acceleration.y).abs() < EPS);
+        }
+
+        #[test]
+        fn box_height_axis_points_along_the_heading() {
+            let ego = ego();
+            let mount = SensorMount {x: 0.0, y: 0.0, yaw: 0.0};
+            let time = rosrust::Time::default();
+            let odom_base_link = make_tf_trasl_euler("odom", "base_link", ego.pose.center.x, ego.pose.center.y, 0.0,
+                0.0, 0.0, ego.pose.yaw, &time);
+            for yaw in &[0.0, 0.5, 2.0, -2.5] {
+                let state = vehicle(Point2f64::new(20.0, 0.0), *yaw, Vec2f64::new(0.0, 0.0));
+                let theta = to_sensor_frame(&mount, &ego, &state).box_orientation;
+                // y axis of the box rotated by theta, in the ibeo frame and then in the world
+                let box_y_axis = Vec2f64::new(-theta.sin(), theta.cos());
+                let heading = rotate(&odom_base_link.transform.rotation, box_y_axis);
+                assert!((heading.x - yaw.cos()).abs() < EPS);
+                assert!((heading.y - yaw.sin()).abs() < EPS);
+            }
+        }
+    }
+}
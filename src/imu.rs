@@ -0,0 +1,221 @@
+use specs::{System, ReadStorage, ReadExpect, WriteExpect, Join};
+use rand::{SeedableRng, StdRng};
+use cgmath::{Basis2, Rotation, Rotation2, Rad};
+
+use super::primitives::*;
+use super::node::*;
+use super::physics::*;
+use super::protagonist::*;
+use super::global_resources::*;
+use super::raycast::*;
+use super::sensor_noise::*;
+use super::sensor_output::*;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ImuParams {
+    pub frame_id: String,
+    pub mount: SensorMount,
+    // samples per second
+    pub rate: f64,
+    // rad/s
+    pub gyro_bias: f64,
+    pub gyro_stddev: f64,
+    // m/s^2, along the x (longitudinal) and y (lateral) axes of the sensor
+    pub longitudinal_accel_bias: f64,
+    pub lateral_accel_bias: f64,
+    pub accel_stddev: f64,
+}
+
+impl Default for ImuParams {
+    fn default() -> Self {
+        ImuParams {
+            frame_id: String::from("imu"),
+            mount: SensorMount::default(),
+            rate: 100.0,
+            gyro_bias: 0.0,
+            gyro_stddev: 0.001,
+            longitudinal_accel_bias: 0.0,
+            lateral_accel_bias: 0.0,
+            accel_stddev: 0.02,
+        }
+    }
+}
+
+// rates and accelerations in the sensor frame, gravity excluded
+#[derive(Debug, Clone, Serialize)]
+pub struct ImuSample {
+    pub frame_id: String,
+    pub sim_time: f64,
+    pub yaw_rate: f64,
+    #[serde(with = "Vec2f64Serde")]
+    pub acceleration: Vec2f64,
+    pub gyro_stddev: f64,
+    pub accel_stddev: f64,
+}
+
+// velocity of the mounting point, in the world frame
+pub fn mount_velocity(vehicle_pose: &Pose2DF64, linear: Vec2f64, angular: f64, mount: &SensorMount) -> Vec2f64 {
+    let offset = mount.world_pose(vehicle_pose).center - vehicle_pose.center;
+    linear + Vec2f64::new(-angular * offset.y, angular * offset.x)
+}
+
+pub struct SimulatedImu {
+    pub params: ImuParams,
+    next_sample_time: f64,
+    // sim time and world velocity of the mount at the previous sample
+    last_velocity: Option<(f64, Vec2f64)>,
+    rng: StdRng,
+}
+
+impl SimulatedImu {
+    // differentiating the velocity of the mount gives its acceleration, centripetal
+    // and tangential terms included
+    pub fn measure(&mut self, sim_time: f64, vehicle_pose: &Pose2DF64, linear: Vec2f64, angular: f64) -> ImuSample {
+        let velocity = mount_velocity(vehicle_pose, linear, angular, &self.params.mount);
+        let world_acceleration = match self.last_velocity {
+            Some((last_time, last_velocity)) if sim_time > last_time => (velocity - last_velocity) / (sim_time - last_time),
+            _ => Vec2f64::new(0.0, 0.0)
+        };
+        self.last_velocity = Some((sim_time, velocity));
+
+        let world_to_sensor : Basis2<_> = Rotation2::<f64>::from_angle(Rad(-(vehicle_pose.yaw + self.params.mount.yaw)));
+        let acceleration = world_to_sensor.rotate_vector(world_acceleration);
+        ImuSample {
+            frame_id: self.params.frame_id.clone(),
+            sim_time: sim_time,
+            yaw_rate: angular + self.params.gyro_bias + gaussian_noise(&mut self.rng, self.params.gyro_stddev),
+            acceleration: Vec2f64::new(
+                acceleration.x + self.params.longitudinal_accel_bias + gaussian_noise(&mut self.rng, self.params.accel_stddev),
+                acceleration.y + self.params.lateral_accel_bias + gaussian_noise(&mut self.rng, self.params.accel_stddev)),
+            gyro_stddev: self.params.gyro_stddev,
+            accel_stddev: self.params.accel_stddev,
+        }
+    }
+}
+
+pub struct ImuState {
+    pub imus: Vec<SimulatedImu>,
+    pending_samples: Vec<ImuSample>,
+}
+
+impl ImuState {
+    pub fn new(imus: Vec<ImuParams>, seed: u32) -> ImuState {
+        ImuState {
+            imus: imus.into_iter().enumerate().map(|(i, params)| SimulatedImu {
+                params: params,
+                next_sample_time: 0.0,
+                last_velocity: None,
+                rng: SeedableRng::from_seed(&[seed as usize, i][..]),
+            }).collect(),
+            pending_samples: Vec::new(),
+        }
+    }
+}
+
+impl SensorOutputs for ImuState {
+    type Measurement = ImuSample;
+
+    fn pop_ready(&mut self, _sim_time: f64, ready: &mut Vec<ImuSample>) {
+        ready.extend(self.pending_samples.drain(..));
+    }
+}
+
+// samples the imus mounted on the protagonist
+pub struct ImuSys;
+
+impl <'a> System<'a> for ImuSys {
+    type SystemData = (
+        ReadExpect<'a, UpdateDeltaTime>,
+        ReadExpect<'a, PhysicsWorld>,
+        ReadStorage<'a, Node>,
+        ReadStorage<'a, PhysicsComponent>,
+        ReadStorage<'a, ProtagonistTag>,
+        WriteExpect<'a, ImuState>,
+    );
+
+    fn run(&mut self, (update_delta_time, physics_world, nodes, physics_components, protagonists, mut imu_state): Self::SystemData) {
+        let sim_time = update_delta_time.sim_time;
+        let imu_state = &mut *imu_state;
+        for (node, physics_component, _protagonist) in (&nodes, &physics_components, &protagonists).join() {
+            let rigid_body = physics_world.rigid_body(physics_component.body_handle).expect("protagonist rigid body not found");
+            let linear = Vec2f64::new(rigid_body.velocity().linear.x, rigid_body.velocity().linear.y);
+            let angular = rigid_body.velocity().angular;
+            for imu in imu_state.imus.iter_mut() {
+                if sim_time < imu.next_sample_time {
+                    continue;
+                }
+                imu.next_sample_time = sim_time + 1.0 / imu.params.rate;
+                imu_state.pending_samples.push(imu.measure(sim_time, &node.pose, linear, angular));
+            }
+        }
+    }
+}
+
+#[cfg(feature = "ros")]
+pub use self::ros::*;
+
+#[cfg(feature = "ros")]
+mod ros {
+    use super::*;
+    use crate::ros_bridge::*;
+    use crate::msg;
+
+    const GRAVITY : f64 = 9.81;
+
+    // publishes every imu on /roadsim2d/<frame_id>/data
+    pub struct ImuPublisher {
+        imu_pubs: FrameTopics<msg::sensor_msgs::Imu>,
+    }
+
+    impl ImuPublisher {
+        pub fn new() -> ImuPublisher {
+            ImuPublisher {imu_pubs: FrameTopics::new("data")}
+        }
+    }
+
+    impl SensorListener<ImuSample> for ImuPublisher {
+        fn on_measurement(&mut self, sample: &ImuSample) {
+            let stamp = match sim_time_stamp(sample.sim_time) {
+                Some(time) => time,
+                None => return
+            };
+
+            let mut msg = msg::sensor_msgs::Imu::default();
+            msg.header.frame_id = sample.frame_id.clone();
+            msg.header.stamp = stamp;
+            // no orientation estimate
+            msg.orientation_covariance[0] = -1.0;
+            msg.angular_velocity.z = sample.yaw_rate;
+            let gyro_variance = sample.gyro_stddev.powi(2);
+            msg.angular_velocity_covariance = [gyro_variance, 0.0, 0.0, 0.0, gyro_variance, 0.0, 0.0, 0.0, gyro_variance];
+            // an accelerometer at rest measures the reaction to gravity
+            msg.linear_acceleration.x = sample.acceleration.x;
+            msg.linear_acceleration.y = sample.acceleration.y;
+            msg.linear_acceleration.z = GRAVITY;
+            let accel_variance = sample.accel_stddev.powi(2);
+            msg.linear_acceleration_covariance = [accel_variance, 0.0, 0.0, 0.0, accel_variance, 0.0, 0.0, 0.0, accel_variance];
+            self.imu_pubs.send(&sample.frame_id, msg);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn turning_at_constant_speed_gives_centripetal_acceleration() {
+        let params = ImuParams {gyro_stddev: 0.0, accel_stddev: 0.0, ..ImuParams::default()};
+        let mut imu = ImuState::new(vec![params], 0).imus.remove(0);
+        // 10 m/s on a circle of radius 20 m, counterclockwise
+        let (speed, yaw_rate, dt) = (10.0, 0.5, 0.001);
+        let pose_at = |t: f64| Pose2DF64 {center: Point2f64::new(0.0, 0.0), yaw: yaw_rate * t};
+        let velocity_at = |t: f64| Vec2f64::new(speed * (yaw_rate * t).cos(), speed * (yaw_rate * t).sin());
+        imu.measure(0.0, &pose_at(0.0), velocity_at(0.0), yaw_rate);
+        let sample = imu.measure(dt, &pose_at(dt), velocity_at(dt), yaw_rate);
+        assert!((sample.yaw_rate - yaw_rate).abs() < 1e-9);
+        assert!(sample.acceleration.x.abs() < 1e-2);
+        assert!((sample.acceleration.y - speed * yaw_rate).abs() < 1e-2);
+    }
+}
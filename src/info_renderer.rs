@@ -2,11 +2,18 @@ use piston_window::*;
 use opengl_graphics::{GlGraphics, GlyphCache};
 use specs::{System, ReadStorage, Component, ReadExpect};
 use super::camera::Camera;
+use super::metrics::*;
+#[cfg(feature = "ros")]
+use super::ros_bridge::*;
 
 #[derive(Default)]
 pub struct SimInfo {
     pub sim_time: f64, 
-    pub fps:      f32
+    pub fps:      f32,
+    pub paused:   bool,
+    pub real_time_factor: f64,
+    #[cfg(feature = "ros")]
+    pub ros_status: RosBridgeStatus
 }
 
 pub struct RenderInfoSys<'a, 'b, 'c> {
@@ -16,10 +23,10 @@ pub struct RenderInfoSys<'a, 'b, 'c> {
 }
 
 impl<'a, 'b, 'c, 'd> System<'a> for RenderInfoSys<'b, 'c, 'd> {
-    type SystemData = (ReadExpect<'a, SimInfo>, ReadExpect<'a, Camera>);
+    type SystemData = (ReadExpect<'a, SimInfo>, ReadExpect<'a, Camera>, ReadExpect<'a, ProtagonistMetrics>);
     
 
-    fn run(& mut self, (info, camera): Self::SystemData) {
+    fn run(& mut self, (info, camera, metrics): Self::SystemData) {
         let font = &mut self.font_glyphs;
 
         self.opengl.draw(self.render_args.viewport(), |context, graphics| {
@@ -48,6 +55,54 @@ impl<'a, 'b, 'c, 'd> System<'a> for RenderInfoSys<'b, 'c, 'd> {
                 tran.trans(100.0, font_size as f64),
                 graphics,
             );
+
+            let control_str = if info.paused {
+                String::from("paused")
+            } else {
+                format!("x{:.2}", info.real_time_factor)
+            };
+
+            piston_window::text(
+                [1.0, 0.0, 0.0, 1.0],
+                font_size,
+                &control_str,
+                *font,
+                tran.trans(250.0, font_size as f64),
+                graphics,
+            );
+
+            #[cfg(feature = "ros")]
+            let () = {
+                let ros_color = match info.ros_status.state {
+                    RosConnectionState::Connected => [0.0, 0.5, 0.0, 1.0],
+                    RosConnectionState::Connecting => [0.5, 0.5, 0.5, 1.0],
+                    RosConnectionState::Lost => [1.0, 0.0, 0.0, 1.0],
+                };
+
+                piston_window::text(
+                    ros_color,
+                    font_size,
+                    &info.ros_status.describe(),
+                    *font,
+                    tran.trans(350.0, font_size as f64),
+                    graphics,
+                );
+            };
+
+            let speed_limit_str = match metrics.speed_limit {
+                Some(speed_limit) => format!("{:.1}/{:.1} m/s", metrics.speed, speed_limit),
+                None => format!("{:.1} m/s", metrics.speed)
+            };
+            let speed_color = if metrics.speeding { [1.0, 0.0, 0.0, 1.0] } else { [0.0, 0.5, 0.0, 1.0] };
+
+            piston_window::text(
+                speed_color,
+                font_size,
+                &speed_limit_str,
+                *font,
+                tran.trans(0.0, 2.0 * font_size as f64),
+                graphics,
+            );
         });
 
     }
@@ -0,0 +1,334 @@
+use specs::{System, ReadStorage, WriteStorage, ReadExpect, Join, VecStorage, Component};
+use std::collections::HashMap;
+use std::f64::consts::PI;
+
+use super::primitives::*;
+use super::car::*;
+use super::node::*;
+use super::physics::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum JunctionSign {
+    // no sign: right-before-left applies between unsigned arms
+    None,
+    Priority,
+    Yield,
+    Stop,
+}
+
+impl Default for JunctionSign {
+    fn default() -> Self {
+        JunctionSign::None
+    }
+}
+
+impl JunctionSign {
+    fn rank(&self) -> i32 {
+        match self {
+            JunctionSign::Priority => 2,
+            JunctionSign::None => 1,
+            JunctionSign::Yield | JunctionSign::Stop => 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JunctionArm {
+    // direction of the arm seen from the junction center
+    pub yaw: f64,
+    #[serde(default)]
+    pub sign: JunctionSign,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Junction {
+    pub center: (f64, f64),
+    pub radius: f64,
+    pub arms: Vec<JunctionArm>,
+}
+
+#[derive(Default)]
+pub struct JunctionMap {
+    pub junctions: Vec<Junction>,
+}
+
+const JUNCTION_APPROACH_DIST : f64 = 15.0;
+const JUNCTION_STOPPED_SPEED : f64 = 0.3;
+const JUNCTION_HEADING_TOLERANCE : f64 = PI / 3.0;
+
+fn normalize_angle(angle: f64) -> f64 {
+    let mut a = angle % (2.0 * PI);
+    if a > PI {
+        a -= 2.0 * PI;
+    } else if a < -PI {
+        a += 2.0 * PI;
+    }
+    a
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JunctionOccupancy {
+    Approaching(usize),
+    Inside,
+    Outside,
+}
+
+impl Junction {
+    fn center_point(&self) -> Point2f64 {
+        Point2f64::new(self.center.0, self.center.1)
+    }
+
+    pub fn closest_arm(&self, position: Point2f64) -> Option<usize> {
+        let rel = position - self.center_point();
+        let position_yaw = rel.y.atan2(rel.x);
+        self.arms.iter().enumerate()
+            .min_by(|(_, a), (_, b)| {
+                let da = normalize_angle(a.yaw - position_yaw).abs();
+                let db = normalize_angle(b.yaw - position_yaw).abs();
+                da.partial_cmp(&db).unwrap()
+            })
+            .map(|(i, _)| i)
+    }
+
+    pub fn occupancy(&self, pose: &Pose2DF64) -> JunctionOccupancy {
+        let rel = pose.center - self.center_point();
+        let dist = (rel.x * rel.x + rel.y * rel.y).sqrt();
+        if dist < self.radius {
+            return JunctionOccupancy::Inside;
+        }
+        if dist > self.radius + JUNCTION_APPROACH_DIST {
+            return JunctionOccupancy::Outside;
+        }
+        let to_center_yaw = (-rel.y).atan2(-rel.x);
+        if normalize_angle(pose.yaw - to_center_yaw).abs() > JUNCTION_HEADING_TOLERANCE {
+            return JunctionOccupancy::Outside;
+        }
+        match self.closest_arm(pose.center) {
+            Some(arm) => JunctionOccupancy::Approaching(arm),
+            None => JunctionOccupancy::Outside
+        }
+    }
+
+    // true if the arm `other` is on the right hand side of a driver entering from `arm`
+    fn is_on_the_right(&self, arm: usize, other: usize) -> bool {
+        let diff = normalize_angle(self.arms[other].yaw - self.arms[arm].yaw);
+        diff > 0.0 && diff < PI - 1.0e-3
+    }
+
+    pub fn must_yield_to(&self, arm: usize, other: usize) -> bool {
+        if arm == other {
+            return false;
+        }
+        let rank = self.arms[arm].sign.rank();
+        let other_rank = self.arms[other].sign.rank();
+        if rank != other_rank {
+            return rank < other_rank;
+        }
+        self.is_on_the_right(arm, other)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct JunctionAgent {
+    pub id: u64,
+    pub occupancy: JunctionOccupancy,
+    pub stopped: bool,
+}
+
+// decides if `agent` may enter the junction given the state of the other agents around it
+pub fn may_enter_junction(junction: &Junction, agent: &JunctionAgent, others: &[JunctionAgent],
+                          stop_done: bool) -> bool {
+    let arm = match agent.occupancy {
+        JunctionOccupancy::Approaching(arm) => arm,
+        _ => return true
+    };
+
+    if junction.arms[arm].sign == JunctionSign::Stop && !stop_done {
+        return false;
+    }
+
+    let mut any_blocking = false;
+    for other in others {
+        if other.id == agent.id {
+            continue;
+        }
+        match other.occupancy {
+            JunctionOccupancy::Inside => return false,
+            JunctionOccupancy::Approaching(other_arm) => {
+                if junction.must_yield_to(arm, other_arm) {
+                    if !other.stopped {
+                        return false;
+                    }
+                    any_blocking = true;
+                }
+            },
+            JunctionOccupancy::Outside => {}
+        }
+    }
+
+    if !any_blocking {
+        return true;
+    }
+
+    // every car we should yield to is itself waiting: break the deadlock letting
+    // the waiting car with the lowest id go first
+    agent.stopped && others.iter()
+        .filter(|other| other.id != agent.id && other.stopped)
+        .filter(|other| match other.occupancy { JunctionOccupancy::Approaching(_) => true, _ => false })
+        .all(|other| other.id > agent.id)
+}
+
+#[derive(Component, Debug)]
+#[storage(VecStorage)]
+pub struct CarJunctionState {
+    pub stop_done: bool,
+    pub current_junction: Option<usize>,
+    // the car has to wait before entering the junction
+    pub hold: bool,
+}
+
+impl CarJunctionState {
+    pub fn new() -> CarJunctionState {
+        CarJunctionState {
+            stop_done: false,
+            current_junction: None,
+            hold: false,
+        }
+    }
+}
+
+pub struct JunctionRightOfWaySys;
+
+impl <'a> System<'a> for JunctionRightOfWaySys {
+    type SystemData = (
+        ReadExpect<'a, PhysicsWorld>,
+        ReadExpect<'a, JunctionMap>,
+        ReadStorage<'a, Car>,
+        ReadStorage<'a, Node>,
+        ReadStorage<'a, PhysicsComponent>,
+        WriteStorage<'a, CarJunctionState>,
+    );
+
+    fn run(&mut self, (physics_world, junction_map, cars, nodes, physics_components,
+            mut junction_states): Self::SystemData) {
+
+        let mut agents_per_junction : HashMap<usize, Vec<JunctionAgent>> = HashMap::new();
+        for (car, node, physics_component) in (&cars, &nodes, &physics_components).join() {
+            let speed = physics_world.rigid_body(physics_component.body_handle)
+                .map(|rigid_body| rigid_body.velocity().linear.norm())
+                .unwrap_or(0.0);
+            for (junction_index, junction) in junction_map.junctions.iter().enumerate() {
+                let occupancy = junction.occupancy(&node.pose);
+                if occupancy != JunctionOccupancy::Outside {
+                    agents_per_junction.entry(junction_index).or_insert_with(Vec::new).push(JunctionAgent {
+                        id: car.id,
+                        occupancy: occupancy,
+                        stopped: speed < JUNCTION_STOPPED_SPEED,
+                    });
+                }
+            }
+        }
+
+        for (car, node, junction_state) in (&cars, &nodes, &mut junction_states).join() {
+            junction_state.hold = false;
+
+            let current = junction_map.junctions.iter().enumerate()
+                .map(|(i, junction)| (i, junction.occupancy(&node.pose)))
+                .find(|(_, occupancy)| *occupancy != JunctionOccupancy::Outside);
+
+            let (junction_index, occupancy) = match current {
+                Some(current) => current,
+                None => {
+                    junction_state.current_junction = None;
+                    junction_state.stop_done = false;
+                    continue;
+                }
+            };
+
+            if junction_state.current_junction != Some(junction_index) {
+                junction_state.current_junction = Some(junction_index);
+                junction_state.stop_done = false;
+            }
+
+            let others = agents_per_junction.get(&junction_index).map(|v| v.as_slice()).unwrap_or(&[]);
+            let me = match others.iter().find(|agent| agent.id == car.id) {
+                Some(me) => *me,
+                None => JunctionAgent{id: car.id, occupancy: occupancy, stopped: false}
+            };
+
+            if me.stopped {
+                junction_state.stop_done = true;
+            }
+
+            let junction = &junction_map.junctions[junction_index];
+            junction_state.hold = !may_enter_junction(junction, &me, others, junction_state.stop_done);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn four_way(signs: [JunctionSign; 4]) -> Junction {
+        Junction {
+            center: (0.0, 0.0),
+            radius: 5.0,
+            arms: (0..4).map(|i| JunctionArm{yaw: i as f64 * PI / 2.0, sign: signs[i]}).collect()
+        }
+    }
+
+    fn approaching(id: u64, arm: usize, stopped: bool) -> JunctionAgent {
+        JunctionAgent{id: id, occupancy: JunctionOccupancy::Approaching(arm), stopped: stopped}
+    }
+
+    #[test]
+    fn right_before_left() {
+        let junction = four_way([JunctionSign::None; 4]);
+        // coming from the west (arm 2), the south arm (3) is on the right
+        assert!(junction.must_yield_to(2, 3));
+        assert!(!junction.must_yield_to(3, 2));
+        let me = approaching(1, 2, false);
+        assert!(!may_enter_junction(&junction, &me, &[approaching(0, 3, false)], false));
+        assert!(may_enter_junction(&junction, &me, &[approaching(0, 1, false)], false));
+    }
+
+    #[test]
+    fn yield_and_stop_signs() {
+        let junction = four_way([JunctionSign::Priority, JunctionSign::Stop, JunctionSign::Priority, JunctionSign::Yield]);
+        assert!(junction.must_yield_to(1, 0));
+        assert!(!junction.must_yield_to(0, 3));
+        let me = approaching(1, 1, false);
+        assert!(!may_enter_junction(&junction, &me, &[], false));
+        assert!(may_enter_junction(&junction, &me, &[], true));
+    }
+
+    #[test]
+    fn occupied_junction_blocks() {
+        let junction = four_way([JunctionSign::Priority; 4]);
+        let me = approaching(1, 0, false);
+        let inside = JunctionAgent{id: 2, occupancy: JunctionOccupancy::Inside, stopped: false};
+        assert!(!may_enter_junction(&junction, &me, &[inside], false));
+    }
+
+    #[test]
+    fn deadlock_is_broken_by_id() {
+        let junction = four_way([JunctionSign::None; 4]);
+        let agents = [approaching(0, 0, true), approaching(1, 1, true),
+                      approaching(2, 2, true), approaching(3, 3, true)];
+        let allowed : Vec<bool> = agents.iter()
+            .map(|agent| may_enter_junction(&junction, agent, &agents, false)).collect();
+        assert_eq!(1, allowed.iter().filter(|x| **x).count());
+    }
+
+    #[test]
+    fn occupancy_from_pose() {
+        let junction = four_way([JunctionSign::None; 4]);
+        let west_heading_east = Pose2DF64{center: Point2f64::new(-10.0, 0.0), yaw: 0.0};
+        assert_eq!(JunctionOccupancy::Approaching(2), junction.occupancy(&west_heading_east));
+        let west_heading_west = Pose2DF64{center: Point2f64::new(-10.0, 0.0), yaw: PI};
+        assert_eq!(JunctionOccupancy::Outside, junction.occupancy(&west_heading_west));
+        let center = Pose2DF64{center: Point2f64::new(1.0, 1.0), yaw: 0.0};
+        assert_eq!(JunctionOccupancy::Inside, junction.occupancy(&center));
+    }
+}
@@ -8,8 +8,10 @@ extern crate piston;
 extern crate rand;
 extern crate euclid;
 extern crate conrod;
+#[cfg(feature = "ros")]
 extern crate rosrust;
 extern crate specs;
+#[cfg(feature = "ros")]
 #[macro_use]
 extern crate rosrust_codegen;
 #[macro_use]
@@ -21,8 +23,11 @@ extern crate pathfinding;
 extern crate serde;
 extern crate serde_yaml;
 #[macro_use]
+extern crate serde_json;
+#[macro_use]
 extern crate serde_derive;
 
+#[cfg(feature = "ros")]
 rosmsg_include!();
 
 mod camera;
@@ -37,7 +42,9 @@ mod vehicle_manager;
 mod debouncer;
 mod roads;
 mod key_action_mapper;
+#[cfg(feature = "ros")]
 mod msg;
+#[cfg(feature = "ros")]
 mod twist_subscriber;
 mod global_resources;
 mod input;
@@ -53,6 +60,32 @@ mod info_renderer;
 mod car_cmd_list_controller;
 mod scenario;
 mod cost_map_publisher;
+mod junction;
+mod speed_limit;
+mod metrics;
+mod town_export;
+mod town_generator;
+mod path_planner;
+mod traffic;
+mod raycast;
+#[cfg(feature = "ros")]
+mod point_cloud;
+mod lidar;
+mod sensor_noise;
+mod sensor_output;
+mod sensor_rig;
+mod radar;
+mod gnss;
+mod imu;
+mod odometry;
+mod ground_truth_objects;
+mod sim_commands;
+mod sim_control;
+mod protagonist_commands;
+mod sim_io;
+mod socket_bridge;
+#[cfg(feature = "ros")]
+mod ros_bridge;
 
 pub use std::time;
 pub use piston_window::*;
@@ -67,7 +100,9 @@ pub use self::sim_id::*;
 pub use self::vehicle_manager::*;
 pub use self::key_action_mapper::*;
 pub use self::roads::*;
+#[cfg(feature = "ros")]
 pub use self::msg::*;
+#[cfg(feature = "ros")]
 pub use self::twist_subscriber::*;
 pub use self::global_resources::*;
 pub use self::protagonist::*;
@@ -81,4 +116,28 @@ pub use self::fps_counter::*;
 pub use self::info_renderer::*;
 pub use self::car_cmd_list_controller::*;
 pub use self::scenario::*;
-pub use self::cost_map_publisher::*;
\ No newline at end of file
+pub use self::cost_map_publisher::*;
+pub use self::junction::*;
+pub use self::speed_limit::*;
+pub use self::metrics::*;
+pub use self::town_export::*;
+pub use self::town_generator::*;
+pub use self::path_planner::*;
+pub use self::traffic::*;
+pub use self::raycast::*;
+pub use self::lidar::*;
+pub use self::sensor_noise::*;
+pub use self::sensor_output::*;
+pub use self::sensor_rig::*;
+pub use self::radar::*;
+pub use self::gnss::*;
+pub use self::imu::*;
+pub use self::odometry::*;
+pub use self::ground_truth_objects::*;
+pub use self::sim_commands::*;
+pub use self::sim_control::*;
+pub use self::protagonist_commands::*;
+pub use self::sim_io::*;
+pub use self::socket_bridge::*;
+#[cfg(feature = "ros")]
+pub use self::ros_bridge::*;
@@ -0,0 +1,239 @@
+use specs::{System, ReadStorage, ReadExpect, WriteExpect, Join};
+use rand::{Rng, SeedableRng, StdRng};
+use nphysics2d::object::BodyHandle;
+use nphysics2d::world::World as PWorld;
+use std::f64::consts::PI;
+
+use super::primitives::*;
+use super::node::*;
+use super::physics::*;
+use super::protagonist::*;
+use super::global_resources::*;
+use super::town::*;
+use super::raycast::*;
+use super::sensor_noise::*;
+use super::sensor_output::*;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LidarParams {
+    pub frame_id: String,
+    pub mount: SensorMount,
+    // angles in radians, centered on the mount yaw
+    pub fov: f64,
+    pub angular_resolution: f64,
+    pub min_range: f64,
+    pub max_range: f64,
+    // scans per second
+    pub rate: f64,
+    // standard deviation of the range, in meters
+    pub range_noise: f64,
+    // probability of a beam without return
+    pub dropout_probability: f64,
+    // seconds between the scan and its output
+    pub latency: f64,
+}
+
+impl Default for LidarParams {
+    fn default() -> Self {
+        LidarParams {
+            frame_id: String::from("laser"),
+            mount: SensorMount::default(),
+            fov: 1.5 * PI,
+            angular_resolution: PI / 360.0,
+            min_range: 0.1,
+            max_range: 30.0,
+            rate: 10.0,
+            range_noise: 0.01,
+            dropout_probability: 0.0,
+            latency: 0.0,
+        }
+    }
+}
+
+impl LidarParams {
+    pub fn beams(&self) -> usize {
+        (self.fov / self.angular_resolution).round() as usize + 1
+    }
+
+    pub fn angle_min(&self) -> f64 {
+        -self.fov / 2.0
+    }
+}
+
+// ranges follow sensor_msgs/LaserScan: beams without return are +inf
+#[derive(Debug, Clone, Serialize)]
+pub struct LidarScan {
+    pub frame_id: String,
+    pub mount: SensorMount,
+    pub sim_time: f64,
+    pub scan_time: f64,
+    pub angle_min: f64,
+    pub angle_increment: f64,
+    pub range_min: f64,
+    pub range_max: f64,
+    pub ranges: Vec<f32>,
+}
+
+impl LidarScan {
+    // returns in the sensor frame
+    pub fn points(&self) -> Vec<(f32, f32)> {
+        self.ranges.iter().enumerate()
+            .filter(|(_, range)| range.is_finite())
+            .map(|(i, range)| {
+                let angle = self.angle_min + i as f64 * self.angle_increment;
+                (range * angle.cos() as f32, range * angle.sin() as f32)
+            }).collect()
+    }
+}
+
+pub fn simulate_lidar_scan(params: &LidarParams, sensor_pose: &Pose2DF64, gridmap: &TownGridMap,
+                           physics_world: &PWorld<f64>, ignored_body: Option<BodyHandle>, rng: &mut StdRng) -> Vec<f32> {
+    (0..params.beams()).map(|i| {
+        let yaw = sensor_pose.yaw + params.angle_min() + i as f64 * params.angular_resolution;
+        if params.dropout_probability > 0.0 && rng.gen_range(0.0, 1.0) < params.dropout_probability {
+            return std::f32::INFINITY;
+        }
+        match raycast(gridmap, physics_world, sensor_pose.center, yaw, params.max_range, ignored_body) {
+            Some((distance, _)) => {
+                let distance = distance + gaussian_noise(rng, params.range_noise);
+                if distance < params.min_range || distance > params.max_range {
+                    std::f32::INFINITY
+                } else {
+                    distance as f32
+                }
+            },
+            None => std::f32::INFINITY
+        }
+    }).collect()
+}
+
+pub struct SimulatedLidar {
+    pub params: LidarParams,
+    next_scan_time: f64,
+    rng: StdRng,
+    pending_scans: LatencyQueue<LidarScan>,
+}
+
+pub struct LidarState {
+    pub lidars: Vec<SimulatedLidar>,
+}
+
+impl LidarState {
+    pub fn new(lidars: Vec<LidarParams>, seed: u32) -> LidarState {
+        LidarState {
+            lidars: lidars.into_iter().enumerate().map(|(i, params)| SimulatedLidar {
+                pending_scans: LatencyQueue::new(params.latency),
+                params: params,
+                next_scan_time: 0.0,
+                rng: SeedableRng::from_seed(&[seed as usize, i][..]),
+            }).collect()
+        }
+    }
+}
+
+// scans the lidars mounted on the protagonist
+pub struct LidarSys;
+
+impl <'a> System<'a> for LidarSys {
+    type SystemData = (
+        ReadExpect<'a, UpdateDeltaTime>,
+        ReadExpect<'a, PhysicsWorld>,
+        ReadExpect<'a, TownGridMap>,
+        ReadStorage<'a, Node>,
+        ReadStorage<'a, PhysicsComponent>,
+        ReadStorage<'a, ProtagonistTag>,
+        WriteExpect<'a, LidarState>,
+    );
+
+    fn run(&mut self, (update_delta_time, physics_world, town_gridmap, nodes, physics_components,
+            protagonists, mut lidar_state): Self::SystemData) {
+        let sim_time = update_delta_time.sim_time;
+        for (node, physics_component, _protagonist) in (&nodes, &physics_components, &protagonists).join() {
+            for lidar in lidar_state.lidars.iter_mut() {
+                if sim_time < lidar.next_scan_time {
+                    continue;
+                }
+                let period = 1.0 / lidar.params.rate;
+                lidar.next_scan_time = sim_time + period;
+
+                let sensor_pose = lidar.params.mount.world_pose(&node.pose);
+                let ranges = simulate_lidar_scan(&lidar.params, &sensor_pose, &town_gridmap, &physics_world,
+                    Some(physics_component.body_handle), &mut lidar.rng);
+                let scan = LidarScan {
+                    frame_id: lidar.params.frame_id.clone(),
+                    mount: lidar.params.mount,
+                    sim_time: sim_time,
+                    scan_time: period,
+                    angle_min: lidar.params.angle_min(),
+                    angle_increment: lidar.params.angular_resolution,
+                    range_min: lidar.params.min_range,
+                    range_max: lidar.params.max_range,
+                    ranges: ranges,
+                };
+                lidar.pending_scans.push(sim_time, scan);
+            }
+        }
+    }
+}
+
+impl SensorOutputs for LidarState {
+    type Measurement = LidarScan;
+
+    fn pop_ready(&mut self, sim_time: f64, ready: &mut Vec<LidarScan>) {
+        for lidar in self.lidars.iter_mut() {
+            while let Some(scan) = lidar.pending_scans.pop_ready(sim_time) {
+                ready.push(scan);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "ros")]
+pub use self::ros::*;
+
+#[cfg(feature = "ros")]
+mod ros {
+    use super::*;
+    use crate::ros_bridge::*;
+    use crate::point_cloud::*;
+    use crate::msg;
+
+    // publishes every lidar on /roadsim2d/<frame_id>/scan and /roadsim2d/<frame_id>/points
+    // (the mounts are published on /tf_static, see StaticTfPublisher)
+    pub struct LidarPublisher {
+        scan_pubs: FrameTopics<msg::sensor_msgs::LaserScan>,
+        cloud_pubs: FrameTopics<msg::sensor_msgs::PointCloud2>,
+    }
+
+    impl LidarPublisher {
+        pub fn new() -> LidarPublisher {
+            LidarPublisher {scan_pubs: FrameTopics::new("scan"), cloud_pubs: FrameTopics::new("points")}
+        }
+    }
+
+    impl SensorListener<LidarScan> for LidarPublisher {
+        fn on_measurement(&mut self, scan: &LidarScan) {
+            let publish_time = match sim_time_stamp(scan.sim_time) {
+                Some(time) => time,
+                None => return
+            };
+
+            let mut msg = msg::sensor_msgs::LaserScan::default();
+            msg.header.frame_id = scan.frame_id.clone();
+            msg.header.stamp = publish_time.clone();
+            msg.angle_min = scan.angle_min as f32;
+            msg.angle_max = (scan.angle_min + (scan.ranges.len() as f64 - 1.0) * scan.angle_increment) as f32;
+            msg.angle_increment = scan.angle_increment as f32;
+            msg.time_increment = 0.0;
+            msg.scan_time = scan.scan_time as f32;
+            msg.range_min = scan.range_min as f32;
+            msg.range_max = scan.range_max as f32;
+            msg.ranges = scan.ranges.clone();
+            self.scan_pubs.send(&scan.frame_id, msg);
+
+            let points : Vec<Vec<f32>> = scan.points().iter().map(|(x, y)| vec![*x, *y, 0.0]).collect();
+            self.cloud_pubs.send(&scan.frame_id, make_point_cloud2(&scan.frame_id, publish_time, &["x", "y", "z"], &points));
+        }
+    }
+}
@@ -0,0 +1,61 @@
+use specs::{System, ReadStorage, ReadExpect, WriteExpect, Join};
+
+use super::node::*;
+use super::physics::*;
+use super::protagonist::*;
+use super::global_resources::*;
+use super::speed_limit::*;
+
+// speeding below this margin (m/s) is not reported
+const SPEEDING_TOLERANCE : f64 = 0.5;
+
+#[derive(Debug, Default)]
+pub struct ProtagonistMetrics {
+    pub speed: f64,
+    pub speed_limit: Option<f64>,
+    pub speeding: bool,
+    pub speeding_time: f64,
+    pub speeding_events: u32,
+    pub max_speed_excess: f64,
+}
+
+impl ProtagonistMetrics {
+    pub fn update_speed(&mut self, speed: f64, speed_limit: Option<f64>, dt: f64) {
+        self.speed = speed;
+        self.speed_limit = speed_limit;
+
+        let excess = speed_limit.map_or(0.0, |limit| speed - limit);
+        let speeding = excess > SPEEDING_TOLERANCE;
+        if speeding {
+            if !self.speeding {
+                self.speeding_events += 1;
+            }
+            self.speeding_time += dt;
+            self.max_speed_excess = self.max_speed_excess.max(excess);
+        }
+        self.speeding = speeding;
+    }
+}
+
+pub struct SpeedingMetricsSys;
+
+impl <'a> System<'a> for SpeedingMetricsSys {
+    type SystemData = (
+        ReadExpect<'a, UpdateDeltaTime>,
+        ReadExpect<'a, PhysicsWorld>,
+        ReadExpect<'a, SpeedLimitMap>,
+        ReadStorage<'a, Node>,
+        ReadStorage<'a, PhysicsComponent>,
+        ReadStorage<'a, ProtagonistTag>,
+        WriteExpect<'a, ProtagonistMetrics>,
+    );
+
+    fn run(&mut self, (update_delta_time, physics_world, speed_limit_map, nodes, physics_components,
+            protagonists, mut metrics): Self::SystemData) {
+        for (node, physics_component, _protagonist) in (&nodes, &physics_components, &protagonists).join() {
+            let rigid_body = physics_world.rigid_body(physics_component.body_handle).expect("protagonist rigid body not found");
+            let speed = rigid_body.velocity().linear.norm();
+            metrics.update_speed(speed, speed_limit_map.speed_limit_at(&node.pose), update_delta_time.dt);
+        }
+    }
+}
@@ -1,2 +1,8 @@
 rosmsg_include!(ibeo_msgs/ObjectListEcu, tf2_msgs/TFMessage, geometry_msgs/Twist, geometry_msgs/Pose,
-     nav_msgs/Odometry, geometry_msgs/PoseWithCovariance, geometry_msgs/TwistWithCovariance);
\ No newline at end of file
+     nav_msgs/Odometry, geometry_msgs/PoseWithCovariance, geometry_msgs/TwistWithCovariance, std_msgs/Float64,
+     sensor_msgs/LaserScan, sensor_msgs/PointCloud2, sensor_msgs/NavSatFix, sensor_msgs/Imu,
+     nav_msgs/OccupancyGrid, nav_msgs/MapMetaData, visualization_msgs/MarkerArray, visualization_msgs/Marker,
+     derived_object_msgs/ObjectArray, derived_object_msgs/Object, std_msgs/ColorRGBA, geometry_msgs/Point32,
+     std_srvs/Trigger, std_srvs/SetBool, roadsim2d_msgs/SpawnVehicle, roadsim2d_msgs/DeleteVehicle, roadsim2d_msgs/SetPose,
+     roadsim2d_msgs/Step, roadsim2d_msgs/SetRealTimeFactor, roadsim2d_msgs/LoadScenario,
+     ackermann_msgs/AckermannDriveStamped, nav_msgs/Path, rosgraph_msgs/Clock);
\ No newline at end of file
@@ -0,0 +1,168 @@
+use specs::{System, ReadStorage, ReadExpect, WriteExpect, Join};
+use rand::{SeedableRng, StdRng};
+
+use super::primitives::*;
+use super::car::*;
+use super::node::*;
+use super::physics::*;
+use super::protagonist::*;
+use super::global_resources::*;
+use super::sensor_noise::*;
+
+// below this speed the steering angle does not tell the yaw rate, which is then
+// taken from the (noisy) ground truth
+const ODOMETRY_MIN_STEERING_SPEED : f64 = 0.1;
+
+// errors of the wheel encoders and of the steering angle sensor
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WheelOdometryParams {
+    // relative error of the wheel radius, 0.01 reads 1% too fast
+    pub speed_scale_error: f64,
+    // m/s
+    pub speed_stddev: f64,
+    // radians
+    pub steering_bias: f64,
+    pub steering_stddev: f64,
+}
+
+impl Default for WheelOdometryParams {
+    fn default() -> Self {
+        WheelOdometryParams {
+            speed_scale_error: 0.01,
+            speed_stddev: 0.05,
+            steering_bias: 0.002,
+            steering_stddev: 0.005,
+        }
+    }
+}
+
+// the protagonist as seen by its own wheels, the pose is in the odom frame
+#[derive(Debug, Clone, Default)]
+pub struct OdometryEstimate {
+    pub pose: Pose2DF64,
+    pub speed: f64,
+    pub yaw_rate: f64,
+}
+
+pub struct WheelOdometry {
+    pub params: WheelOdometryParams,
+    pub estimate: OdometryEstimate,
+    rng: StdRng,
+    seed: u32,
+}
+
+impl WheelOdometry {
+    pub fn new(params: WheelOdometryParams, seed: u32) -> WheelOdometry {
+        WheelOdometry {params: params, estimate: OdometryEstimate::default(), rng: SeedableRng::from_seed(&[seed as usize][..]), seed: seed}
+    }
+
+    // back to the start of the estimate, with the noise of a new run
+    pub fn reset(&mut self) {
+        self.estimate = OdometryEstimate::default();
+        self.rng = SeedableRng::from_seed(&[self.seed as usize][..]);
+    }
+
+    // `speed` is the signed longitudinal speed of the protagonist, the wheels are
+    // modelled as a bicycle with the given wheelbase
+    pub fn update(&mut self, speed: f64, yaw_rate: f64, wheelbase: f64, dt: f64) {
+        let measured_speed = speed * (1.0 + self.params.speed_scale_error) + gaussian_noise(&mut self.rng, self.params.speed_stddev);
+        let measured_yaw_rate = if speed.abs() > ODOMETRY_MIN_STEERING_SPEED {
+            let steering = (wheelbase * yaw_rate / speed).atan();
+            let measured_steering = steering + self.params.steering_bias + gaussian_noise(&mut self.rng, self.params.steering_stddev);
+            measured_speed * measured_steering.tan() / wheelbase
+        } else {
+            yaw_rate + gaussian_noise(&mut self.rng, self.params.steering_stddev)
+        };
+
+        // midpoint integration
+        let estimate = &mut self.estimate;
+        let mid_yaw = estimate.pose.yaw + measured_yaw_rate * dt / 2.0;
+        estimate.pose.center.x += measured_speed * mid_yaw.cos() * dt;
+        estimate.pose.center.y += measured_speed * mid_yaw.sin() * dt;
+        estimate.pose.yaw += measured_yaw_rate * dt;
+        estimate.speed = measured_speed;
+        estimate.yaw_rate = measured_yaw_rate;
+    }
+}
+
+// map -> odom, such that map -> odom -> base_link is the ground truth pose
+pub fn map_to_odom(ground_truth: &Pose2DF64, odom_pose: &Pose2DF64) -> Pose2DF64 {
+    let yaw = ground_truth.yaw - odom_pose.yaw;
+    let (sin, cos) = yaw.sin_cos();
+    Pose2DF64 {
+        center: Point2f64::new(ground_truth.center.x - (cos * odom_pose.center.x - sin * odom_pose.center.y),
+                               ground_truth.center.y - (sin * odom_pose.center.x + cos * odom_pose.center.y)),
+        yaw: yaw
+    }
+}
+
+pub struct WheelOdometrySys;
+
+impl <'a> System<'a> for WheelOdometrySys {
+    type SystemData = (
+        ReadExpect<'a, UpdateDeltaTime>,
+        ReadExpect<'a, PhysicsWorld>,
+        ReadStorage<'a, Car>,
+        ReadStorage<'a, Node>,
+        ReadStorage<'a, PhysicsComponent>,
+        ReadStorage<'a, ProtagonistTag>,
+        WriteExpect<'a, WheelOdometry>,
+    );
+
+    fn run(&mut self, (update_delta_time, physics_world, cars, nodes, physics_components, protagonists,
+            mut odometry): Self::SystemData) {
+        for (car, node, physics_component, _protagonist) in (&cars, &nodes, &physics_components, &protagonists).join() {
+            let rigid_body = physics_world.rigid_body(physics_component.body_handle).expect("protagonist rigid body not found");
+            let velocity = rigid_body.velocity().linear;
+            let speed = velocity.x * node.pose.yaw.cos() + velocity.y * node.pose.yaw.sin();
+            odometry.update(speed, rigid_body.velocity().angular, car.wheel_base as f64, update_delta_time.dt);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exact_params() -> WheelOdometryParams {
+        WheelOdometryParams {speed_scale_error: 0.0, speed_stddev: 0.0, steering_bias: 0.0, steering_stddev: 0.0}
+    }
+
+    #[test]
+    fn exact_wheels_follow_the_circle() {
+        let mut odometry = WheelOdometry::new(exact_params(), 0);
+        // a full circle of radius 10 m
+        let (speed, yaw_rate, dt) = (5.0, 0.5, 0.01);
+        let steps = (2.0 * std::f64::consts::PI / yaw_rate / dt).round() as usize;
+        for _ in 0..steps {
+            odometry.update(speed, yaw_rate, 2.7, dt);
+        }
+        assert!(odometry.estimate.pose.center.x.abs() < 0.05);
+        assert!(odometry.estimate.pose.center.y.abs() < 0.05);
+    }
+
+    #[test]
+    fn scale_error_makes_odom_drift() {
+        let params = WheelOdometryParams {speed_scale_error: 0.02, ..exact_params()};
+        let mut odometry = WheelOdometry::new(params, 0);
+        for _ in 0..1000 {
+            odometry.update(10.0, 0.0, 2.7, 0.01);
+        }
+        assert!((odometry.estimate.pose.center.x - 102.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn map_to_odom_corrects_the_drift() {
+        let ground_truth = Pose2DF64 {center: Point2f64::new(50.0, -20.0), yaw: 1.2};
+        let odom_pose = Pose2DF64 {center: Point2f64::new(48.0, -15.0), yaw: 0.9};
+        let correction = map_to_odom(&ground_truth, &odom_pose);
+        // map -> odom composed with odom -> base_link
+        let (sin, cos) = correction.yaw.sin_cos();
+        let x = correction.center.x + cos * odom_pose.center.x - sin * odom_pose.center.y;
+        let y = correction.center.y + sin * odom_pose.center.x + cos * odom_pose.center.y;
+        assert!((x - ground_truth.center.x).abs() < 1e-9);
+        assert!((y - ground_truth.center.y).abs() < 1e-9);
+        assert!((correction.yaw + odom_pose.yaw - ground_truth.yaw).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,177 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Sender, Receiver};
+use std::thread;
+
+use super::primitives::*;
+use super::town::*;
+
+struct PathRequest {
+    id: u64,
+    start: Vec2f32,
+    end: Vec2f32,
+}
+
+struct PathResult {
+    id: u64,
+    path: Option<VecDeque<Vec2f32>>,
+}
+
+// runs A* on a pool of worker threads. In deterministic mode every request is
+// completed exactly at the first poll after its submission, so that the
+// simulation gives the same results regardless of the machine load
+pub struct PathPlanner {
+    request_tx: Option<Mutex<Sender<PathRequest>>>,
+    result_rx: Mutex<Receiver<PathResult>>,
+    workers: Vec<thread::JoinHandle<()>>,
+    next_request_id: u64,
+    in_flight: usize,
+    ready: HashMap<u64, Option<VecDeque<Vec2f32>>>,
+    deterministic: bool,
+}
+
+impl PathPlanner {
+    pub fn new(gridmap: Arc<TownGridMap>, workers_count: usize, deterministic: bool) -> PathPlanner {
+        let (request_tx, request_rx) = channel::<PathRequest>();
+        let (result_tx, result_rx) = channel::<PathResult>();
+        let request_rx = Arc::new(Mutex::new(request_rx));
+
+        let workers = (0..workers_count.max(1)).map(|_| {
+            let gridmap = gridmap.clone();
+            let request_rx = request_rx.clone();
+            let result_tx = result_tx.clone();
+            thread::spawn(move || {
+                loop {
+                    let request = match request_rx.lock().unwrap().recv() {
+                        Ok(request) => request,
+                        Err(_) => break
+                    };
+                    let path = find_shortest_path(&gridmap, request.start, request.end);
+                    if result_tx.send(PathResult{id: request.id, path: path}).is_err() {
+                        break;
+                    }
+                }
+            })
+        }).collect();
+
+        PathPlanner {
+            request_tx: Some(Mutex::new(request_tx)),
+            result_rx: Mutex::new(result_rx),
+            workers: workers,
+            next_request_id: 0,
+            in_flight: 0,
+            ready: HashMap::new(),
+            deterministic: deterministic,
+        }
+    }
+
+    pub fn submit(&mut self, start: Vec2f32, end: Vec2f32) -> u64 {
+        let id = self.next_request_id;
+        self.next_request_id += 1;
+        self.request_tx.as_ref().unwrap().lock().unwrap()
+            .send(PathRequest{id: id, start: start, end: end})
+            .expect("path planner workers stopped");
+        self.in_flight += 1;
+        id
+    }
+
+    // collects the completed requests, blocks on the outstanding ones in deterministic mode
+    pub fn poll(&mut self) {
+        let result_rx = self.result_rx.lock().unwrap();
+        while self.in_flight > 0 {
+            let result = if self.deterministic {
+                result_rx.recv().ok()
+            } else {
+                result_rx.try_recv().ok()
+            };
+            match result {
+                Some(result) => {
+                    self.in_flight -= 1;
+                    self.ready.insert(result.id, result.path);
+                },
+                None => break
+            }
+        }
+    }
+
+    // None while the request is still running, Some(None) if no path was found
+    pub fn take_result(&mut self, request_id: u64) -> Option<Option<VecDeque<Vec2f32>>> {
+        self.ready.remove(&request_id)
+    }
+
+    // the car of a request may have been deleted or may have replanned before the result arrived
+    pub fn drop_unclaimed_results(&mut self, waiting_requests: &HashSet<u64>) {
+        self.ready.retain(|request_id, _| waiting_requests.contains(request_id));
+    }
+}
+
+impl Drop for PathPlanner {
+    fn drop(&mut self) {
+        // closing the channel stops the workers
+        self.request_tx.take();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a free corridor along y = 2 and a free cell cut off from it
+    fn corridor_gridmap() -> TownGridMap {
+        let mut gridmap = TownGridMap::new(TownMapInfo::centered(20, 10, 1.0));
+        for x in 0..20 {
+            gridmap.set_cell(x, 2, TownCell::Free);
+        }
+        gridmap.set_cell(10, 8, TownCell::Free);
+        gridmap
+    }
+
+    fn world(gridmap: &TownGridMap, x: i32, y: i32) -> Vec2f32 {
+        gridmap.gridmap_xy_to_world(Vec2i32::new(x, y))
+    }
+
+    #[test]
+    fn deterministic_results_are_ready_at_the_next_poll() {
+        let gridmap = corridor_gridmap();
+        let mut planner = PathPlanner::new(Arc::new(gridmap.clone()), 3, true);
+        let requests : Vec<(u64, Vec2f32, Vec2f32)> = (0..6).map(|i| {
+            let (start, end) = (world(&gridmap, i, 2), world(&gridmap, 19 - i, 2));
+            (planner.submit(start, end), start, end)
+        }).collect();
+        assert!(requests.windows(2).all(|pair| pair[0].0 < pair[1].0));
+
+        planner.poll();
+        for (request_id, start, end) in requests.iter().rev() {
+            let path = planner.take_result(*request_id).expect("result not ready after the poll");
+            assert_eq!(find_shortest_path(&gridmap, *start, *end), path);
+        }
+        assert!(planner.take_result(requests[0].0).is_none());
+    }
+
+    #[test]
+    fn no_path_found() {
+        let gridmap = corridor_gridmap();
+        let mut planner = PathPlanner::new(Arc::new(gridmap.clone()), 1, true);
+        let request_id = planner.submit(world(&gridmap, 0, 2), world(&gridmap, 10, 8));
+        planner.poll();
+        assert_eq!(Some(None), planner.take_result(request_id));
+    }
+
+    #[test]
+    fn unclaimed_results_are_dropped() {
+        let gridmap = corridor_gridmap();
+        let mut planner = PathPlanner::new(Arc::new(gridmap.clone()), 2, true);
+        let kept = planner.submit(world(&gridmap, 0, 2), world(&gridmap, 5, 2));
+        let abandoned = planner.submit(world(&gridmap, 1, 2), world(&gridmap, 6, 2));
+        planner.poll();
+
+        let mut waiting = HashSet::new();
+        waiting.insert(kept);
+        planner.drop_unclaimed_results(&waiting);
+        assert!(planner.take_result(abandoned).is_none());
+        assert!(planner.take_result(kept).is_some());
+    }
+}
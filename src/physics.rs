@@ -17,6 +17,8 @@ use nphysics2d::object::BodyStatus;
 use super::car::*;
 use super::node::*;
 use super::primitives::*;
+use super::global_resources::*;
+use std::ops::{Deref, DerefMut};
 
 #[derive(Component, Debug)]
 #[storage(VecStorage)]
@@ -24,6 +26,34 @@ pub struct PhysicsComponent {
     pub body_handle: BodyHandle
 }
 
+// the nphysics world shared by the systems as a specs resource, so that the
+// systems only reading it can run in parallel
+pub struct PhysicsWorld {
+    pub world: PWorld<f64>
+}
+
+impl PhysicsWorld {
+    pub fn new() -> PhysicsWorld {
+        let mut world = PWorld::new();
+        world.set_gravity(Vector2::new(0.0, 0.0));
+        PhysicsWorld { world: world }
+    }
+}
+
+impl Deref for PhysicsWorld {
+    type Target = PWorld<f64>;
+
+    fn deref(&self) -> &PWorld<f64> {
+        &self.world
+    }
+}
+
+impl DerefMut for PhysicsWorld {
+    fn deref_mut(&mut self) -> &mut PWorld<f64> {
+        &mut self.world
+    }
+}
+
 const COLLIDER_MARGIN: f64 = 0.00001;
 
 pub fn make_physics_for_car(world: &mut PWorld<f64>, car: &Car, pose: &Pose2DF64) -> PhysicsComponent {
@@ -50,19 +80,60 @@ pub fn make_physics_for_car(world: &mut PWorld<f64>, car: &Car, pose: &Pose2DF64
     PhysicsComponent{body_handle: handle}
 }
 
-pub struct PhysicsUpdateNodeSys<'a> {
-    pub physics_world: &'a PWorld<f64>
+// body moved by setting its pose, it pushes the cars but is not pushed back
+pub fn make_kinematic_body(world: &mut PWorld<f64>, bb_size: Size2f64) -> BodyHandle {
+    let geom = ShapeHandle::new(Cuboid::new(Vector2::new(bb_size.height/2.0, bb_size.width/2.0)));
+    let inertia = geom.inertia(1.0);
+    let center_of_mass = geom.center_of_mass();
+
+    let handle = world.add_rigid_body(Isometry2::identity(), inertia, center_of_mass);
+    world.add_collider(COLLIDER_MARGIN, geom, handle, Isometry2::identity(), Material::default());
+    world.rigid_body_mut(handle).expect("just added rigid body not found").set_status(BodyStatus::Kinematic);
+    handle
 }
 
-impl <'a, 'b> System<'a> for PhysicsUpdateNodeSys<'b> {
+// velocities chosen by a controller, the controllers only read the physics world
+// and PhysicsStepSys applies their commands before stepping it
+#[derive(Component, Debug)]
+#[storage(VecStorage)]
+pub struct BodyCommand {
+    pub linear_velocity: Vector2<f64>,
+    pub angular_velocity: f64,
+}
+
+pub struct PhysicsStepSys;
+
+impl <'a> System<'a> for PhysicsStepSys {
+    type SystemData = (
+        ReadExpect<'a, UpdateDeltaTime>,
+        WriteExpect<'a, PhysicsWorld>,
+        ReadStorage<'a, PhysicsComponent>,
+        WriteStorage<'a, BodyCommand>,
+    );
+
+    fn run(&mut self, (update_delta_time, mut physics_world, physics_components, mut body_commands): Self::SystemData) {
+        for (physics_component, body_command) in (&physics_components, body_commands.drain()).join() {
+            let rigid_body = physics_world.rigid_body_mut(physics_component.body_handle).expect("Rigid-body not found.");
+            rigid_body.set_linear_velocity(body_command.linear_velocity);
+            rigid_body.set_angular_velocity(body_command.angular_velocity);
+        }
+        physics_world.set_timestep(update_delta_time.dt);
+        physics_world.step();
+    }
+}
+
+pub struct PhysicsUpdateNodeSys;
+
+impl <'a> System<'a> for PhysicsUpdateNodeSys {
     type SystemData = (
+        ReadExpect<'a, PhysicsWorld>,
         ReadStorage<'a, PhysicsComponent>,
         WriteStorage<'a, Node>,
     );
 
-    fn run(&mut self, (physics_components, mut nodes): Self::SystemData) {
+    fn run(&mut self, (physics_world, physics_components, mut nodes): Self::SystemData) {
         for (physics_component, node) in (&physics_components, &mut nodes).join() {
-            let rigid_body = self.physics_world.rigid_body(physics_component.body_handle).expect("Rigid-body not found.");
+            let rigid_body = physics_world.rigid_body(physics_component.body_handle).expect("Rigid-body not found.");
             let pos = rigid_body.position().translation.vector;
             let rot = rigid_body.position().rotation;
             node.pose.center.x = pos.x;
@@ -0,0 +1,35 @@
+use super::msg;
+
+// sensor_msgs/PointField datatype
+const POINT_FIELD_FLOAT32 : u8 = 7;
+
+// unorganised cloud of float32 fields, one entry of `points` per point with one value per field
+pub fn make_point_cloud2(frame_id: &str, stamp: rosrust::Time, field_names: &[&str], points: &[Vec<f32>]) -> msg::sensor_msgs::PointCloud2 {
+    let mut cloud = msg::sensor_msgs::PointCloud2::default();
+    cloud.header.frame_id = String::from(frame_id);
+    cloud.header.stamp = stamp;
+
+    for (i, name) in field_names.iter().enumerate() {
+        cloud.fields.push(msg::sensor_msgs::PointField {
+            name: String::from(*name),
+            offset: (i * 4) as u32,
+            datatype: POINT_FIELD_FLOAT32,
+            count: 1
+        });
+    }
+
+    cloud.height = 1;
+    cloud.width = points.len() as u32;
+    cloud.is_bigendian = false;
+    cloud.point_step = (field_names.len() * 4) as u32;
+    cloud.row_step = cloud.point_step * cloud.width;
+    cloud.is_dense = true;
+    cloud.data.reserve(cloud.row_step as usize);
+    for point in points {
+        assert!(point.len() == field_names.len());
+        for value in point {
+            cloud.data.extend_from_slice(&value.to_bits().to_le_bytes());
+        }
+    }
+    cloud
+}
@@ -27,6 +27,13 @@ struct Point2f64Serde {
     y: f64,
 }
 
+#[derive(Serialize, Deserialize)]
+#[serde(remote = "cgmath::Vector2::<f64>")]
+pub struct Vec2f64Serde {
+    x: f64,
+    y: f64,
+}
+
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Pose2DF64 {
@@ -5,7 +5,11 @@ use std::collections::hash_set::HashSet;
 use std::collections::vec_deque::VecDeque;
 use super::car::*;
 use super::physics::*;
+use super::node::*;
+use super::global_resources::*;
+use super::protagonist_commands::*;
 use nphysics2d::world::World as PWorld;
+use nphysics2d::object::RigidBody;
 use nphysics2d::math::Velocity;
 use nphysics2d::math::Force;
 use nalgebra::Vector2;
@@ -24,47 +28,87 @@ pub struct Twist2D {
 pub struct ProtagonistTag;
 
 
-pub struct ControlProtagonistSys<'a> {
-   pub physics_world: &'a mut PWorld<f64>,
-   pub target_protagonist_twist: &'a Twist2D
+// the twist, ackermann and trajectory subscribers share the last command with this system
+pub struct ControlProtagonistSys {
+   pub commands: ProtagonistCommandHandle
 }
 
-impl <'a, 'b> System<'a> for ControlProtagonistSys<'b> {
-    type SystemData = (
-        WriteStorage<'a, PhysicsComponent>,
-        WriteStorage<'a, Car>,
-        ReadStorage<'a, ProtagonistTag>,
-    );
+fn drive_twist(rigid_body: &RigidBody<f64>, car: &mut Car, twist: &Twist2D) -> BodyCommand {
+    let speed = twist.x as f32;
+    let yaw_rate = twist.z_rot as f32;
 
-    fn run(&mut self, (mut physics_components, mut cars, protagonists): Self::SystemData) {
-        for (physics_component, car, _protagonist) in (&mut physics_components, &mut cars, &protagonists).join() {
-            let speed = self.target_protagonist_twist.x as f32;
-            let yaw_rate = self.target_protagonist_twist.z_rot as f32;
+    let current_yaw_rate = rigid_body.velocity().angular as f32;
 
-            let mut rigid_body = self.physics_world.rigid_body_mut(physics_component.body_handle).expect("protagonist rigid body not found");
+    let yaw_increment = (yaw_rate - current_yaw_rate);  
+    let max_yaw_increment = 0.2f32;
+    let yaw_increment_clamped = f32::max(-max_yaw_increment, f32::min(max_yaw_increment, yaw_increment));
 
-            let current_yaw_rate = rigid_body.velocity().angular as f32;
+    let car_frame_velocity = Vector2::<f64>::new(speed as f64, 0.0);
+    let mut car_velocity = car_frame_velocity.clone();
+    rigid_body.position().rotation.rotate(&mut car_velocity);
 
-            let yaw_increment = (yaw_rate - current_yaw_rate);  
-            let max_yaw_increment = 0.2f32;
-            let yaw_increment_clamped = f32::max(-max_yaw_increment, f32::min(max_yaw_increment, yaw_increment));
+    let mut command = BodyCommand {linear_velocity: car_velocity, angular_velocity: rigid_body.velocity().angular};
 
-            let car_frame_velocity = Vector2::<f64>::new(speed as f64, 0.0);
-            let mut car_velocity = car_frame_velocity.clone();
-            rigid_body.position().rotation.rotate(&mut car_velocity);
+    // println!("car long speed {}  yaw_rate {}", car_longitudinal_speed, yaw_increment_clamped);
+    if(speed > 0.0f32) {
+        let new_wheel_yaw = yaw_increment_clamped / speed * (car.bb_size.height as f32 / 2.0f32);
+        let max_wheel_yaw = 0.6;
+        let new_clamped_wheel_yaw = f32::max(-max_wheel_yaw, f32::min(max_wheel_yaw, new_wheel_yaw));
+
+        car.wheel_yaw = new_clamped_wheel_yaw;
+        command.angular_velocity = yaw_increment_clamped as f64;
+    }
+    command
+}
 
-            rigid_body.set_linear_velocity(car_velocity);
+// kinematic bicycle: the yaw rate follows from the speed and the steering angle
+fn drive_ackermann(rigid_body: &RigidBody<f64>, car: &mut Car, target: &AckermannTarget, max_steering_angle: f64, dt: f64) -> BodyCommand {
+    let yaw = rigid_body.position().rotation.angle();
+    let velocity = rigid_body.velocity().linear;
+    let speed = approach_speed(velocity.x * yaw.cos() + velocity.y * yaw.sin(), target, dt);
+    let steering_angle = target.steering_angle.max(-max_steering_angle).min(max_steering_angle);
 
-            // println!("car long speed {}  yaw_rate {}", car_longitudinal_speed, yaw_increment_clamped);
-            if(speed > 0.0f32) {
-                let new_wheel_yaw = yaw_increment_clamped / speed * (car.bb_size.height as f32 / 2.0f32);
-                let max_wheel_yaw = 0.6;
-                let new_clamped_wheel_yaw = f32::max(-max_wheel_yaw, f32::min(max_wheel_yaw, new_wheel_yaw));
+    let mut car_velocity = Vector2::<f64>::new(speed, 0.0);
+    rigid_body.position().rotation.rotate(&mut car_velocity);
+    car.wheel_yaw = steering_angle as f32;
+    BodyCommand {linear_velocity: car_velocity, angular_velocity: speed * steering_angle.tan() / car.wheel_base as f64}
+}
+
+impl <'a> System<'a> for ControlProtagonistSys {
+    type SystemData = (
+        Entities<'a>,
+        ReadExpect<'a, UpdateDeltaTime>,
+        ReadExpect<'a, ProtagonistControlParams>,
+        ReadExpect<'a, PhysicsWorld>,
+        ReadStorage<'a, PhysicsComponent>,
+        WriteStorage<'a, Car>,
+        ReadStorage<'a, Node>,
+        ReadStorage<'a, ProtagonistTag>,
+        WriteStorage<'a, BodyCommand>,
+    );
 
-                car.wheel_yaw = new_clamped_wheel_yaw;
-                rigid_body.set_angular_velocity(yaw_increment_clamped as f64);
-            }
+    fn run(&mut self, (entities, update_delta_time, params, physics_world, physics_components, mut cars, nodes, protagonists,
+            mut body_commands): Self::SystemData) {
+        let mut commands = self.commands.lock().unwrap();
+        commands.stamp(update_delta_time.sim_time);
+        // the controller died, stop where the wheels point
+        let timed_out = commands.timed_out(update_delta_time.sim_time, &params);
+        for (entity, physics_component, car, node, _protagonist) in (&entities, &physics_components, &mut cars, &nodes, &protagonists).join() {
+            let rigid_body = physics_world.rigid_body(physics_component.body_handle).expect("protagonist rigid body not found");
 
+            let target = if timed_out {
+                AckermannTarget {steering_angle: car.wheel_yaw as f64, speed: 0.0, acceleration: params.stop_deceleration}
+            } else {
+                match commands.command {
+                    ProtagonistCommand::Twist(ref twist) => {
+                        body_commands.insert(entity, drive_twist(rigid_body, car, twist)).ok();
+                        continue;
+                    },
+                    ProtagonistCommand::Ackermann(ref target) => target.clone(),
+                    ProtagonistCommand::Trajectory(ref mut trajectory) => trajectory.follow(&node.pose, car.wheel_base as f64, &params),
+                }
+            };
+            body_commands.insert(entity, drive_ackermann(rigid_body, car, &target, params.max_steering_angle, update_delta_time.dt)).ok();
         }
     }
 }
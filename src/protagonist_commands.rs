@@ -0,0 +1,288 @@
+use std::sync::{Arc, Mutex};
+
+use super::primitives::*;
+use super::protagonist::*;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ProtagonistControlParams {
+    // simulation seconds without commands after which the protagonist is stopped,
+    // a trajectory is followed until its end instead
+    pub command_timeout: f64,
+    // twist commands used to be kept until the next one, they time out only if set
+    pub twist_timeout: bool,
+    // m/s^2, used to stop after a timeout and at the end of a trajectory
+    pub stop_deceleration: f64,
+    // radians
+    pub max_steering_angle: f64,
+    // meters, distance of the point followed on a trajectory
+    pub lookahead_distance: f64,
+    // m/s, used when the poses of a trajectory have no stamps
+    pub trajectory_speed: f64,
+}
+
+impl Default for ProtagonistControlParams {
+    fn default() -> Self {
+        ProtagonistControlParams {
+            command_timeout: 0.5,
+            twist_timeout: false,
+            stop_deceleration: 5.0,
+            max_steering_angle: 0.6,
+            lookahead_distance: 4.0,
+            trajectory_speed: 5.0,
+        }
+    }
+}
+
+// as ackermann_msgs/AckermannDrive, a zero acceleration changes the speed at once
+#[derive(Debug, Clone, Default)]
+pub struct AckermannTarget {
+    pub steering_angle: f64,
+    pub speed: f64,
+    pub acceleration: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct TrajectoryPoint {
+    pub position: Point2f64,
+    // seconds, none if the pose was not stamped
+    pub stamp: Option<f64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Trajectory {
+    pub points: Vec<TrajectoryPoint>,
+    // index of the closest point reached so far, the protagonist never goes back
+    pub progress: usize,
+}
+
+impl Trajectory {
+    pub fn new(points: Vec<TrajectoryPoint>) -> Trajectory {
+        Trajectory {points: points, progress: 0}
+    }
+
+    // pure pursuit of the point at the lookahead distance, the speed is the one of the
+    // current segment if the poses are stamped
+    pub fn follow(&mut self, pose: &Pose2DF64, wheel_base: f64, params: &ProtagonistControlParams) -> AckermannTarget {
+        let stop = AckermannTarget {steering_angle: 0.0, speed: 0.0, acceleration: params.stop_deceleration};
+        if self.points.is_empty() {
+            return stop;
+        }
+        let distance = |point: &TrajectoryPoint| ((point.position.x - pose.center.x).powi(2) + (point.position.y - pose.center.y).powi(2)).sqrt();
+
+        while self.progress + 1 < self.points.len() && distance(&self.points[self.progress + 1]) <= distance(&self.points[self.progress]) {
+            self.progress += 1;
+        }
+        let last = self.points.len() - 1;
+        if self.progress == last {
+            return stop;
+        }
+
+        let target = self.points[self.progress..].iter()
+            .find(|point| distance(point) >= params.lookahead_distance)
+            .unwrap_or(&self.points[last]);
+        let (sin, cos) = pose.yaw.sin_cos();
+        let (dx, dy) = (target.position.x - pose.center.x, target.position.y - pose.center.y);
+        let lateral = -sin * dx + cos * dy;
+        let lookahead = distance(target).max(1e-3);
+        let steering_angle = (2.0 * wheel_base * lateral / lookahead.powi(2)).atan();
+
+        let (from, to) = (&self.points[self.progress], &self.points[self.progress + 1]);
+        let speed = match (from.stamp, to.stamp) {
+            (Some(from_stamp), Some(to_stamp)) if to_stamp > from_stamp => {
+                ((to.position.x - from.position.x).powi(2) + (to.position.y - from.position.y).powi(2)).sqrt() / (to_stamp - from_stamp)
+            },
+            _ => params.trajectory_speed
+        };
+        AckermannTarget {steering_angle: steering_angle, speed: speed, acceleration: 0.0}
+    }
+}
+
+pub enum ProtagonistCommand {
+    Twist(Twist2D),
+    Ackermann(AckermannTarget),
+    // nav_msgs/Path in the map frame
+    Trajectory(Trajectory),
+}
+
+// the last command and the sim time it arrived at, written by the subscribers. They do not
+// know the sim time, a new command is stamped by the first update that sees it
+pub struct ProtagonistCommandState {
+    pub command: ProtagonistCommand,
+    pub received: Option<f64>,
+    unstamped: bool,
+}
+
+impl ProtagonistCommandState {
+    pub fn new() -> ProtagonistCommandState {
+        ProtagonistCommandState {command: ProtagonistCommand::Twist(Twist2D::default()), received: None, unstamped: false}
+    }
+
+    pub fn set(&mut self, command: ProtagonistCommand) {
+        self.command = command;
+        self.unstamped = true;
+    }
+
+    pub fn stamp(&mut self, sim_time: f64) {
+        if self.unstamped {
+            self.received = Some(sim_time);
+            self.unstamped = false;
+        }
+    }
+
+    pub fn timed_out(&self, sim_time: f64, params: &ProtagonistControlParams) -> bool {
+        match self.command {
+            ProtagonistCommand::Twist(_) if !params.twist_timeout => return false,
+            // planners may publish a path only once, the protagonist stops at its end
+            ProtagonistCommand::Trajectory(_) => return false,
+            _ => {}
+        }
+        match self.received {
+            Some(received) => sim_time - received > params.command_timeout,
+            None => false
+        }
+    }
+}
+
+pub type ProtagonistCommandHandle = Arc<Mutex<ProtagonistCommandState>>;
+
+// speed reached after dt, moving towards target by at most `acceleration` m/s^2
+pub fn approach_speed(current: f64, target: &AckermannTarget, dt: f64) -> f64 {
+    if target.acceleration <= 0.0 {
+        return target.speed;
+    }
+    let max_change = target.acceleration * dt;
+    current + (target.speed - current).max(-max_change).min(max_change)
+}
+
+#[cfg(feature = "ros")]
+pub use self::ros::*;
+
+#[cfg(feature = "ros")]
+mod ros {
+    use super::*;
+    use rosrust::api::raii::Subscriber;
+    use crate::msg;
+
+    fn ros_time_to_sec(time: &rosrust::Time) -> Option<f64> {
+        if time.sec == 0 && time.nsec == 0 {
+            None
+        } else {
+            Some(time.sec as f64 + time.nsec as f64 * 1e-9)
+        }
+    }
+
+    pub struct AckermannSubscriber {
+        _ackermann_sub: Subscriber,
+    }
+
+    impl AckermannSubscriber {
+        pub fn new(commands: ProtagonistCommandHandle) -> Option<AckermannSubscriber> {
+            let ackermann_sub = rosrust::subscribe("roadsim2d/protagonist_ackermann", move |v: msg::ackermann_msgs::AckermannDriveStamped| {
+                commands.lock().unwrap().set(ProtagonistCommand::Ackermann(AckermannTarget {
+                    steering_angle: v.drive.steering_angle as f64,
+                    speed: v.drive.speed as f64,
+                    acceleration: v.drive.acceleration.abs() as f64,
+                }));
+            });
+            ackermann_sub.ok().map(|ackermann_sub| AckermannSubscriber {_ackermann_sub: ackermann_sub})
+        }
+    }
+
+    pub struct PathSubscriber {
+        _path_sub: Subscriber,
+    }
+
+    impl PathSubscriber {
+        pub fn new(commands: ProtagonistCommandHandle) -> Option<PathSubscriber> {
+            let path_sub = rosrust::subscribe("roadsim2d/protagonist_path", move |v: msg::nav_msgs::Path| {
+                let points = v.poses.iter().map(|pose| TrajectoryPoint {
+                    position: Point2f64::new(pose.pose.position.x, pose.pose.position.y),
+                    stamp: ros_time_to_sec(&pose.header.stamp),
+                }).collect();
+                commands.lock().unwrap().set(ProtagonistCommand::Trajectory(Trajectory::new(points)));
+            });
+            path_sub.ok().map(|path_sub| PathSubscriber {_path_sub: path_sub})
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn straight_line(stamped: bool) -> Trajectory {
+        Trajectory::new((0..20).map(|i| TrajectoryPoint {
+            position: Point2f64::new(i as f64, 2.0),
+            stamp: if stamped { Some(i as f64 * 0.5) } else { None },
+        }).collect())
+    }
+
+    #[test]
+    fn trajectory_steers_towards_the_path() {
+        let params = ProtagonistControlParams::default();
+        let mut trajectory = straight_line(true);
+        let target = trajectory.follow(&Pose2DF64 {center: Point2f64::new(0.0, 0.0), yaw: 0.0}, 2.5, &params);
+        // the path is on the left, 1 m every half second
+        assert!(target.steering_angle > 0.0);
+        assert!((target.speed - 2.0).abs() < 1e-9);
+
+        let mut trajectory = straight_line(false);
+        let target = trajectory.follow(&Pose2DF64 {center: Point2f64::new(5.0, 4.0), yaw: 0.0}, 2.5, &params);
+        assert!(target.steering_angle < 0.0);
+        assert_eq!(params.trajectory_speed, target.speed);
+        assert_eq!(5, trajectory.progress);
+    }
+
+    #[test]
+    fn trajectory_stops_at_the_end() {
+        let params = ProtagonistControlParams::default();
+        let mut trajectory = straight_line(false);
+        let target = trajectory.follow(&Pose2DF64 {center: Point2f64::new(25.0, 2.0), yaw: 0.0}, 2.5, &params);
+        assert_eq!(0.0, target.speed);
+        assert_eq!(params.stop_deceleration, target.acceleration);
+    }
+
+    #[test]
+    fn speed_changes_with_the_acceleration() {
+        let target = AckermannTarget {steering_angle: 0.0, speed: 10.0, acceleration: 2.0};
+        assert!((approach_speed(5.0, &target, 0.1) - 5.2).abs() < 1e-9);
+        assert!((approach_speed(10.1, &target, 0.1) - 10.0).abs() < 1e-9);
+        assert_eq!(10.0, approach_speed(0.0, &AckermannTarget {acceleration: 0.0, ..target}, 0.1));
+    }
+
+    #[test]
+    fn commands_time_out_in_sim_time() {
+        let params = ProtagonistControlParams::default();
+        let mut state = ProtagonistCommandState::new();
+        state.set(ProtagonistCommand::Ackermann(AckermannTarget::default()));
+        // paused: no sim time passes however long the command waits
+        state.stamp(10.0);
+        state.stamp(10.4);
+        assert!(!state.timed_out(10.4, &params));
+        assert!(state.timed_out(10.6, &params));
+
+        state.set(ProtagonistCommand::Twist(Twist2D::default()));
+        state.stamp(11.0);
+        assert!(!state.timed_out(20.0, &params));
+        assert!(state.timed_out(20.0, &ProtagonistControlParams {twist_timeout: true, ..params}));
+    }
+
+    #[test]
+    fn path_received_once_is_followed_to_the_end() {
+        let params = ProtagonistControlParams::default();
+        let mut state = ProtagonistCommandState::new();
+        state.set(ProtagonistCommand::Trajectory(straight_line(true)));
+        state.stamp(1.0);
+        assert!(!state.timed_out(6.0, &params));
+
+        if let ProtagonistCommand::Trajectory(ref mut trajectory) = state.command {
+            let halfway = trajectory.follow(&Pose2DF64 {center: Point2f64::new(10.0, 2.0), yaw: 0.0}, 2.5, &params);
+            assert!((halfway.speed - 2.0).abs() < 1e-9);
+            let end = trajectory.follow(&Pose2DF64 {center: Point2f64::new(19.0, 2.0), yaw: 0.0}, 2.5, &params);
+            assert_eq!(0.0, end.speed);
+        } else {
+            panic!("the trajectory was replaced");
+        }
+    }
+}
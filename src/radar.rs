@@ -0,0 +1,344 @@
+use specs::{System, ReadStorage, ReadExpect, WriteExpect, Join};
+use rand::{Rng, SeedableRng, StdRng};
+use nphysics2d::object::BodyHandle;
+use nphysics2d::world::World as PWorld;
+use cgmath::InnerSpace;
+use std::collections::HashMap;
+
+use super::primitives::*;
+use super::node::*;
+use super::physics::*;
+use super::protagonist::*;
+use super::global_resources::*;
+use super::town::*;
+use super::raycast::*;
+use super::sensor_noise::*;
+use super::sensor_output::*;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RadarParams {
+    pub frame_id: String,
+    pub mount: SensorMount,
+    // scans per second
+    pub rate: f64,
+    pub max_range: f64,
+    // angles in radians, centered on the mount yaw
+    pub fov: f64,
+    // returns closer than the resolutions in range, azimuth and range rate
+    // cannot be told apart and give a single detection
+    pub range_resolution: f64,
+    pub azimuth_resolution: f64,
+    pub range_rate_resolution: f64,
+    // spacing of the rays sampling the scene, finer than azimuth_resolution
+    pub ray_spacing: f64,
+    pub range_stddev: f64,
+    pub azimuth_stddev: f64,
+    pub range_rate_stddev: f64,
+    // radar cross sections in dBsm
+    pub vehicle_rcs: f64,
+    pub town_rcs: f64,
+    pub rcs_stddev: f64,
+    pub detection_probability: f64,
+    // mean number of clutter detections per scan
+    pub clutter_rate: f64,
+    // seconds between the scan and its output
+    pub latency: f64,
+}
+
+impl Default for RadarParams {
+    fn default() -> Self {
+        RadarParams {
+            frame_id: String::from("radar"),
+            mount: SensorMount::default(),
+            rate: 20.0,
+            max_range: 150.0,
+            fov: 90.0f64.to_radians(),
+            range_resolution: 0.5,
+            azimuth_resolution: 2.0f64.to_radians(),
+            range_rate_resolution: 0.1,
+            ray_spacing: 0.25f64.to_radians(),
+            range_stddev: 0.1,
+            azimuth_stddev: 0.3f64.to_radians(),
+            range_rate_stddev: 0.05,
+            vehicle_rcs: 10.0,
+            town_rcs: 0.0,
+            rcs_stddev: 2.0,
+            detection_probability: 1.0,
+            clutter_rate: 0.0,
+            latency: 0.0,
+        }
+    }
+}
+
+// polar measurement in the radar frame, range rate is positive when the target moves away
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct RadarDetection {
+    pub range: f64,
+    pub azimuth: f64,
+    pub range_rate: f64,
+    pub rcs: f64,
+}
+
+impl RadarDetection {
+    pub fn position(&self) -> (f64, f64) {
+        (self.range * self.azimuth.cos(), self.range * self.azimuth.sin())
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RadarScan {
+    pub frame_id: String,
+    pub sim_time: f64,
+    pub detections: Vec<RadarDetection>,
+}
+
+pub fn range_rate(sensor_position: Point2f64, sensor_velocity: Vec2f64, point: Point2f64, point_velocity: Vec2f64) -> f64 {
+    let line_of_sight = point - sensor_position;
+    let distance = line_of_sight.magnitude();
+    if distance == 0.0 {
+        return 0.0;
+    }
+    (point_velocity - sensor_velocity).dot(line_of_sight) / distance
+}
+
+// velocity of `point` on a body moving with the given linear and angular velocity
+fn point_velocity(center: Point2f64, linear: Vec2f64, angular: f64, point: Point2f64) -> Vec2f64 {
+    let offset = point - center;
+    linear + Vec2f64::new(-angular * offset.y, angular * offset.x)
+}
+
+// returns falling in the same range, azimuth and range rate cell are merged into one detection
+pub fn merge_returns(params: &RadarParams, returns: &[RadarDetection]) -> Vec<RadarDetection> {
+    let mut cells : HashMap<(i64, i64, i64), Vec<&RadarDetection>> = HashMap::new();
+    for radar_return in returns {
+        let key = ((radar_return.range / params.range_resolution).floor() as i64,
+                   (radar_return.azimuth / params.azimuth_resolution).floor() as i64,
+                   (radar_return.range_rate / params.range_rate_resolution).floor() as i64);
+        cells.entry(key).or_insert_with(Vec::new).push(radar_return);
+    }
+
+    let mut keys : Vec<_> = cells.keys().cloned().collect();
+    keys.sort();
+    keys.iter().map(|key| {
+        let cell = &cells[key];
+        let count = cell.len() as f64;
+        RadarDetection {
+            range: cell.iter().map(|r| r.range).sum::<f64>() / count,
+            azimuth: cell.iter().map(|r| r.azimuth).sum::<f64>() / count,
+            range_rate: cell.iter().map(|r| r.range_rate).sum::<f64>() / count,
+            rcs: cell.iter().map(|r| r.rcs).fold(std::f64::NEG_INFINITY, f64::max),
+        }
+    }).collect()
+}
+
+// the detections of a scan from `sensor_pose`, moving at `sensor_velocity`
+pub fn simulate_radar_scan(params: &RadarParams, sensor_pose: &Pose2DF64, sensor_velocity: Vec2f64, gridmap: &TownGridMap,
+                           physics_world: &PWorld<f64>, ignored_body: Option<BodyHandle>, rng: &mut StdRng) -> Vec<RadarDetection> {
+    let rays = (params.fov / params.ray_spacing).round() as usize + 1;
+    let mut returns = Vec::new();
+    for i in 0..rays {
+        let azimuth = -params.fov / 2.0 + i as f64 * params.ray_spacing;
+        let yaw = sensor_pose.yaw + azimuth;
+        let (distance, hit) = match raycast(gridmap, physics_world, sensor_pose.center, yaw, params.max_range, ignored_body) {
+            Some(hit) => hit,
+            None => continue
+        };
+        let point = Point2f64::new(sensor_pose.center.x + distance * yaw.cos(), sensor_pose.center.y + distance * yaw.sin());
+        let (velocity, rcs) = match hit {
+            RayHit::Town => (Vec2f64::new(0.0, 0.0), params.town_rcs),
+            RayHit::Vehicle(body) => {
+                let rigid_body = physics_world.rigid_body(body).expect("car rigid body not found");
+                let center = rigid_body.position().translation.vector;
+                let linear = rigid_body.velocity().linear;
+                (point_velocity(Point2f64::new(center.x, center.y), Vec2f64::new(linear.x, linear.y),
+                    rigid_body.velocity().angular, point), params.vehicle_rcs)
+            }
+        };
+        returns.push(RadarDetection {
+            range: distance,
+            azimuth: azimuth,
+            range_rate: range_rate(sensor_pose.center, sensor_velocity, point, velocity),
+            rcs: rcs,
+        });
+    }
+
+    let mut detections = Vec::new();
+    for detection in merge_returns(params, &returns) {
+        if rng.gen_range(0.0, 1.0) >= params.detection_probability {
+            continue;
+        }
+        detections.push(RadarDetection {
+            range: detection.range + gaussian_noise(rng, params.range_stddev),
+            azimuth: detection.azimuth + gaussian_noise(rng, params.azimuth_stddev),
+            range_rate: detection.range_rate + gaussian_noise(rng, params.range_rate_stddev),
+            rcs: detection.rcs + gaussian_noise(rng, params.rcs_stddev),
+        });
+    }
+
+    // clutter is static ground seen anywhere in the field of view, with a weak echo
+    for _ in 0..poisson_sample(rng, params.clutter_rate) {
+        let range = rng.gen_range(0.0, params.max_range);
+        let azimuth = rng.gen_range(-params.fov / 2.0, params.fov / 2.0);
+        let yaw = sensor_pose.yaw + azimuth;
+        let point = Point2f64::new(sensor_pose.center.x + range * yaw.cos(), sensor_pose.center.y + range * yaw.sin());
+        detections.push(RadarDetection {
+            range: range,
+            azimuth: azimuth,
+            range_rate: range_rate(sensor_pose.center, sensor_velocity, point, Vec2f64::new(0.0, 0.0)),
+            rcs: rng.gen_range(-20.0, -5.0),
+        });
+    }
+    detections
+}
+
+pub struct SimulatedRadar {
+    pub params: RadarParams,
+    next_scan_time: f64,
+    rng: StdRng,
+    pending_scans: LatencyQueue<RadarScan>,
+}
+
+pub struct RadarState {
+    pub radars: Vec<SimulatedRadar>,
+}
+
+impl RadarState {
+    pub fn new(radars: Vec<RadarParams>, seed: u32) -> RadarState {
+        RadarState {
+            radars: radars.into_iter().enumerate().map(|(i, params)| SimulatedRadar {
+                pending_scans: LatencyQueue::new(params.latency),
+                params: params,
+                next_scan_time: 0.0,
+                rng: SeedableRng::from_seed(&[seed as usize, i][..]),
+            }).collect()
+        }
+    }
+}
+
+// scans the radars mounted on the protagonist
+pub struct RadarSys;
+
+impl <'a> System<'a> for RadarSys {
+    type SystemData = (
+        ReadExpect<'a, UpdateDeltaTime>,
+        ReadExpect<'a, PhysicsWorld>,
+        ReadExpect<'a, TownGridMap>,
+        ReadStorage<'a, Node>,
+        ReadStorage<'a, PhysicsComponent>,
+        ReadStorage<'a, ProtagonistTag>,
+        WriteExpect<'a, RadarState>,
+    );
+
+    fn run(&mut self, (update_delta_time, physics_world, town_gridmap, nodes, physics_components,
+            protagonists, mut radar_state): Self::SystemData) {
+        let sim_time = update_delta_time.sim_time;
+        for (node, physics_component, _protagonist) in (&nodes, &physics_components, &protagonists).join() {
+            let rigid_body = physics_world.rigid_body(physics_component.body_handle).expect("protagonist rigid body not found");
+            let linear = rigid_body.velocity().linear;
+            let angular = rigid_body.velocity().angular;
+
+            for radar in radar_state.radars.iter_mut() {
+                if sim_time < radar.next_scan_time {
+                    continue;
+                }
+                radar.next_scan_time = sim_time + 1.0 / radar.params.rate;
+
+                let sensor_pose = radar.params.mount.world_pose(&node.pose);
+                let sensor_velocity = point_velocity(node.pose.center, Vec2f64::new(linear.x, linear.y), angular, sensor_pose.center);
+                let detections = simulate_radar_scan(&radar.params, &sensor_pose, sensor_velocity, &town_gridmap,
+                    &physics_world, Some(physics_component.body_handle), &mut radar.rng);
+                let scan = RadarScan {
+                    frame_id: radar.params.frame_id.clone(),
+                    sim_time: sim_time,
+                    detections: detections,
+                };
+                radar.pending_scans.push(sim_time, scan);
+            }
+        }
+    }
+}
+
+impl SensorOutputs for RadarState {
+    type Measurement = RadarScan;
+
+    fn pop_ready(&mut self, sim_time: f64, ready: &mut Vec<RadarScan>) {
+        for radar in self.radars.iter_mut() {
+            while let Some(scan) = radar.pending_scans.pop_ready(sim_time) {
+                ready.push(scan);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "ros")]
+pub use self::ros::*;
+
+#[cfg(feature = "ros")]
+mod ros {
+    use super::*;
+    use crate::ros_bridge::*;
+    use crate::point_cloud::*;
+    use crate::msg;
+
+    // publishes every radar as a point cloud on /roadsim2d/<frame_id>/detections
+    pub struct RadarPublisher {
+        detections_pubs: FrameTopics<msg::sensor_msgs::PointCloud2>,
+    }
+
+    impl RadarPublisher {
+        pub fn new() -> RadarPublisher {
+            RadarPublisher {detections_pubs: FrameTopics::new("detections")}
+        }
+    }
+
+    impl SensorListener<RadarScan> for RadarPublisher {
+        fn on_measurement(&mut self, scan: &RadarScan) {
+            let stamp = match sim_time_stamp(scan.sim_time) {
+                Some(time) => time,
+                None => return
+            };
+
+            let points : Vec<Vec<f32>> = scan.detections.iter().map(|detection| {
+                let (x, y) = detection.position();
+                vec![x as f32, y as f32, 0.0, detection.range as f32, detection.azimuth as f32,
+                     detection.range_rate as f32, detection.rcs as f32]
+            }).collect();
+            let cloud = make_point_cloud2(&scan.frame_id, stamp,
+                &["x", "y", "z", "range", "azimuth", "range_rate", "rcs"], &points);
+            self.detections_pubs.send(&scan.frame_id, cloud);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn range_rate_of_approaching_and_crossing_targets() {
+        let sensor = Point2f64::new(0.0, 0.0);
+        let sensor_velocity = Vec2f64::new(10.0, 0.0);
+        // static target ahead, the distance shrinks at the ego speed
+        assert!((range_rate(sensor, sensor_velocity, Point2f64::new(50.0, 0.0), Vec2f64::new(0.0, 0.0)) + 10.0).abs() < 1e-9);
+        // leading car at the same speed
+        assert!(range_rate(sensor, sensor_velocity, Point2f64::new(50.0, 0.0), sensor_velocity).abs() < 1e-9);
+        // target crossing perpendicular to the line of sight of a static sensor
+        assert!(range_rate(sensor, Vec2f64::new(0.0, 0.0), Point2f64::new(0.0, 30.0), Vec2f64::new(5.0, 0.0)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn returns_closer_than_the_resolution_are_merged() {
+        let params = RadarParams::default();
+        let detection = |range, azimuth, range_rate| RadarDetection {range: range, azimuth: azimuth, range_rate: range_rate, rcs: 10.0};
+        let merged = merge_returns(&params, &[
+            detection(20.1, 0.001, -5.01),
+            detection(20.2, 0.002, -5.02),
+            // same cell but different range rate, e.g. a car passing a parked one
+            detection(20.2, 0.002, -1.0),
+            detection(40.0, 0.001, -5.01),
+        ]);
+        assert_eq!(3, merged.len());
+        assert!(merged.iter().any(|d| (d.range - 20.15).abs() < 1e-9 && (d.range_rate + 5.015).abs() < 1e-9));
+    }
+}
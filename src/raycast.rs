@@ -0,0 +1,138 @@
+use ncollide2d::query::Ray;
+use ncollide2d::world::CollisionGroups;
+use nphysics2d::object::BodyHandle;
+use nphysics2d::world::World as PWorld;
+use nalgebra::{Point2, Vector2};
+
+use super::primitives::*;
+use super::town::*;
+
+// pose of a sensor in the frame of the vehicle carrying it (x forward, y left)
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct SensorMount {
+    pub x: f64,
+    pub y: f64,
+    pub yaw: f64,
+}
+
+impl SensorMount {
+    pub fn world_pose(&self, vehicle_pose: &Pose2DF64) -> Pose2DF64 {
+        let (sin, cos) = vehicle_pose.yaw.sin_cos();
+        Pose2DF64 {
+            center: Point2f64::new(vehicle_pose.center.x + cos * self.x - sin * self.y,
+                                   vehicle_pose.center.y + sin * self.x + cos * self.y),
+            yaw: vehicle_pose.yaw + self.yaw
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RayHit {
+    Town,
+    Vehicle(BodyHandle),
+}
+
+// walks the cells crossed by the ray (Amanatides-Woo), returns the distance of the
+// first occupied one. Unknown cells do not stop the ray, leaving the map does
+pub fn raycast_town(gridmap: &TownGridMap, origin: Point2f64, yaw: f64, max_range: f64) -> Option<f64> {
+    let info = &gridmap.info;
+    let (dir_y, dir_x) = yaw.sin_cos();
+    let start_x = (origin.x - info.origin.x) / info.resolution;
+    let start_y = (origin.y - info.origin.y) / info.resolution;
+    let max_t = max_range / info.resolution;
+
+    let mut cell_x = start_x.floor() as i64;
+    let mut cell_y = start_y.floor() as i64;
+    let step_x = if dir_x > 0.0 { 1 } else { -1 };
+    let step_y = if dir_y > 0.0 { 1 } else { -1 };
+    let t_delta_x = if dir_x != 0.0 { (1.0 / dir_x).abs() } else { std::f64::INFINITY };
+    let t_delta_y = if dir_y != 0.0 { (1.0 / dir_y).abs() } else { std::f64::INFINITY };
+    let mut t_max_x = if dir_x > 0.0 {
+        (cell_x as f64 + 1.0 - start_x) / dir_x
+    } else if dir_x < 0.0 {
+        (start_x - cell_x as f64) / -dir_x
+    } else {
+        std::f64::INFINITY
+    };
+    let mut t_max_y = if dir_y > 0.0 {
+        (cell_y as f64 + 1.0 - start_y) / dir_y
+    } else if dir_y < 0.0 {
+        (start_y - cell_y as f64) / -dir_y
+    } else {
+        std::f64::INFINITY
+    };
+
+    let mut t = 0.0;
+    while t <= max_t {
+        if cell_x < 0 || cell_y < 0 || cell_x >= info.width as i64 || cell_y >= info.height as i64 {
+            return None;
+        }
+        if gridmap.cell(cell_x as usize, cell_y as usize) == TownCell::Occupied {
+            return Some(t * info.resolution);
+        }
+        if t_max_x < t_max_y {
+            t = t_max_x;
+            t_max_x += t_delta_x;
+            cell_x += step_x;
+        } else {
+            t = t_max_y;
+            t_max_y += t_delta_y;
+            cell_y += step_y;
+        }
+    }
+    None
+}
+
+// closest vehicle collider hit by the ray, `ignored_body` is usually the vehicle carrying the sensor
+pub fn raycast_vehicles(physics_world: &PWorld<f64>, origin: Point2f64, yaw: f64, max_range: f64,
+                        ignored_body: Option<BodyHandle>) -> Option<(f64, BodyHandle)> {
+    let ray = Ray::new(Point2::new(origin.x, origin.y), Vector2::new(yaw.cos(), yaw.sin()));
+    let groups = CollisionGroups::new();
+    physics_world.collision_world().interferences_with_ray(&ray, &groups)
+        .map(|(collider, intersection)| (intersection.toi, collider.data().body()))
+        .filter(|(toi, body)| *toi <= max_range && Some(*body) != ignored_body)
+        .min_by(|a, b| a.0.partial_cmp(&b.0).unwrap())
+}
+
+pub fn raycast(gridmap: &TownGridMap, physics_world: &PWorld<f64>, origin: Point2f64, yaw: f64, max_range: f64,
+               ignored_body: Option<BodyHandle>) -> Option<(f64, RayHit)> {
+    let town_hit = raycast_town(gridmap, origin, yaw, max_range);
+    let range = town_hit.unwrap_or(max_range);
+    match raycast_vehicles(physics_world, origin, yaw, range, ignored_body) {
+        Some((distance, body)) => Some((distance, RayHit::Vehicle(body))),
+        None => town_hit.map(|distance| (distance, RayHit::Town))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ray_stops_at_the_first_wall() {
+        let mut gridmap = TownGridMap::new(TownMapInfo::centered(20, 20, 0.5));
+        for x in 0..20 {
+            for y in 0..20 {
+                if x != 15 {
+                    gridmap.set_cell(x, y, TownCell::Free);
+                }
+            }
+        }
+        // the wall column x = 15 starts 2.5 m right of the center
+        let distance = raycast_town(&gridmap, Point2f64::new(0.0, 0.0), 0.0, 10.0).unwrap();
+        assert!((distance - 2.5).abs() < 1e-9);
+        assert_eq!(None, raycast_town(&gridmap, Point2f64::new(0.0, 0.0), 0.0, 2.0));
+        // leaving the map is not a hit
+        assert_eq!(None, raycast_town(&gridmap, Point2f64::new(0.0, 0.0), std::f64::consts::PI, 10.0));
+    }
+
+    #[test]
+    fn mount_pose_in_world() {
+        let mount = SensorMount {x: 1.0, y: 0.5, yaw: 0.1};
+        let vehicle_pose = Pose2DF64 {center: Point2f64::new(10.0, 5.0), yaw: std::f64::consts::PI / 2.0};
+        let pose = mount.world_pose(&vehicle_pose);
+        assert!((pose.center.x - 9.5).abs() < 1e-9);
+        assert!((pose.center.y - 6.0).abs() < 1e-9);
+        assert!((pose.yaw - (std::f64::consts::PI / 2.0 + 0.1)).abs() < 1e-9);
+    }
+}
@@ -36,6 +36,43 @@ impl QuadrinomialParams {
     fn zero_order(a: f32) -> QuadrinomialParams {
         QuadrinomialParams{a: a, b: 0.0, c: 0.0, d: 0.0}
     }
+
+    fn eval(&self, ds: f64) -> f64 {
+        let ds = ds as f32;
+        (self.a + self.b * ds + self.c * ds * ds + self.d * ds * ds * ds) as f64
+    }
+}
+
+const SPIRAL_INTEGRATION_STEP : f64 = 0.1;
+
+impl RoadGeometry {
+    // position and heading at distance `ds` from the start of the geometry
+    fn pose_at(&self, ds: f64) -> Pose2DF64 {
+        let origin = self.base.origin;
+        match self.sub {
+            RoadGeometrySub::Line{} => {
+                Pose2DF64 {
+                    center: Point2f64::new(origin.x + ds * self.base.yaw.cos(), origin.y + ds * self.base.yaw.sin()),
+                    yaw: self.base.yaw
+                }
+            },
+            RoadGeometrySub::Spiral{curv_start, curv_end} => {
+                let curv_rate = (curv_end - curv_start) as f64 / self.base.length;
+                let (mut x, mut y, mut yaw) = (origin.x, origin.y, self.base.yaw);
+                let mut s = 0.0;
+                while s < ds {
+                    let step = f64::min(SPIRAL_INTEGRATION_STEP, ds - s);
+                    let curv = curv_start as f64 + curv_rate * (s + step / 2.0);
+                    let mid_yaw = yaw + curv * step / 2.0;
+                    x += step * mid_yaw.cos();
+                    y += step * mid_yaw.sin();
+                    yaw += curv * step;
+                    s += step;
+                }
+                Pose2DF64 {center: Point2f64::new(x, y), yaw: yaw}
+            }
+        }
+    }
 }
 
 struct Lane {
@@ -110,6 +147,95 @@ pub fn draw_road(context: Context, graphics: &mut G2d,
 
 impl Road {
 
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn junction(&self) -> i64 {
+        self.junction
+    }
+
+    pub fn length(&self) -> f64 {
+        self.geometries.iter().map(|geometry| geometry.base.length).sum()
+    }
+
+    fn pose_at(&self, s: f64) -> Option<Pose2DF64> {
+        self.geometries.iter()
+            .filter(|geometry| geometry.base.s <= s)
+            .last()
+            .map(|geometry| geometry.pose_at(f64::min(s - geometry.base.s, geometry.base.length)))
+    }
+
+    fn lane_section_at(&self, s: f64) -> Option<&LaneSection> {
+        self.lane_sections.iter().filter(|lane_section| lane_section.s <= s).last()
+    }
+
+    fn sample_s(&self, step: f64) -> Vec<f64> {
+        let length = self.length();
+        let samples = (length / step).ceil().max(1.0) as usize;
+        (0..samples + 1).map(|i| f64::min(i as f64 * step, length)).collect()
+    }
+
+    pub fn reference_line(&self, step: f64) -> Vec<Pose2DF64> {
+        self.sample_s(step).iter().filter_map(|s| self.pose_at(*s)).collect()
+    }
+
+    pub fn lane_ids(&self) -> Vec<i32> {
+        let mut ids : Vec<i32> = self.lane_sections.iter()
+            .flat_map(|lane_section| lane_section.lanes.iter().map(|lane| lane.id))
+            .filter(|id| *id != 0)
+            .collect();
+        ids.sort();
+        ids.dedup();
+        ids
+    }
+
+    // lateral offset of the lane center from the reference line, left lanes have positive ids
+    fn lane_center_offset(lane_section: &LaneSection, lane_id: i32, s: f64) -> Option<f64> {
+        let ds = s - lane_section.s;
+        let width_of = |id: i32| lane_section.lanes.iter()
+            .find(|lane| lane.id == id)
+            .map(|lane| lane.width_params.eval(ds));
+        let lane_width = width_of(lane_id)?;
+        let inner_width : f64 = (1..lane_id.abs()).map(|i| width_of(i * lane_id.signum()).unwrap_or(0.0)).sum();
+        Some((inner_width + lane_width / 2.0) * lane_id.signum() as f64)
+    }
+
+    pub fn lane_centreline(&self, lane_id: i32, step: f64) -> Vec<Point2f64> {
+        self.sample_s(step).iter().filter_map(|s| {
+            let pose = self.pose_at(*s)?;
+            let offset = Road::lane_center_offset(self.lane_section_at(*s)?, lane_id, *s)?;
+            Some(Point2f64::new(pose.center.x - offset * pose.yaw.sin(), pose.center.y + offset * pose.yaw.cos()))
+        }).collect()
+    }
+
+    pub fn set_junction(&mut self, junction: i64) {
+        self.junction = junction;
+    }
+
+    // total width of the lanes at `s`
+    pub fn width_at(&self, s: f64) -> f64 {
+        match self.lane_section_at(s) {
+            Some(lane_section) => lane_section.lanes.iter()
+                .map(|lane| lane.width_params.eval(s - lane_section.s)).sum(),
+            None => 0.0
+        }
+    }
+
+    // lateral offset of the reference line from the center of the road at `s`
+    pub fn center_offset_at(&self, s: f64) -> f64 {
+        match self.lane_section_at(s) {
+            Some(lane_section) => {
+                let left : f64 = lane_section.lanes.iter().filter(|lane| lane.id > 0)
+                    .map(|lane| lane.width_params.eval(s - lane_section.s)).sum();
+                let right : f64 = lane_section.lanes.iter().filter(|lane| lane.id < 0)
+                    .map(|lane| lane.width_params.eval(s - lane_section.s)).sum();
+                (left - right) / 2.0
+            },
+            None => 0.0
+        }
+    }
+
     fn new(id_provider: &mut IdProvider) -> Road {
         Road {
             id : id_provider.next(),
@@ -120,6 +246,33 @@ impl Road {
     }
 }
 
+// straight road from `start` to `end` with the same number of lanes in both directions
+pub fn make_straight_road(id_provider: &mut IdProvider, start: Point2f64, end: Point2f64,
+                          lanes_per_direction: u32, lane_width: f64) -> Road {
+    let mut road = Road::new(id_provider);
+    let mut lane_section = LaneSection::new();
+    let diff = end - start;
+
+    road.geometries.push(RoadGeometry {
+        base: RoadGeometryBase {
+            s: 0.0,
+            origin: Vec2f64{x: start.x, y: start.y},
+            yaw: diff.y.atan2(diff.x),
+            length: (diff.x * diff.x + diff.y * diff.y).sqrt()
+        },
+        sub: RoadGeometrySub::Line {}
+    });
+
+    let lanes_per_direction = lanes_per_direction as i32;
+    for id in -lanes_per_direction..lanes_per_direction + 1 {
+        let width = if id == 0 { 0.0 } else { lane_width as f32 };
+        lane_section.lanes.push(Lane{id: id, s: 0.0, width_params: QuadrinomialParams::zero_order(width)});
+    }
+    road.lane_sections.push(lane_section);
+
+    road
+}
+
 pub fn generate_random_road(id_provider: &mut IdProvider) -> Road {
     let mut road = Road::new(id_provider);
     let mut lane_section = LaneSection::new();
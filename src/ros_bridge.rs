@@ -0,0 +1,393 @@
+use rosrust::api::raii::Publisher;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time;
+
+use super::twist_subscriber::*;
+use super::protagonist_commands::*;
+use super::sim_commands::*;
+use super::sim_control::*;
+use super::sim_io::*;
+use super::ibeo::*;
+use super::lidar::*;
+use super::radar::*;
+use super::gnss::*;
+use super::imu::*;
+use super::ground_truth_objects::*;
+use super::cost_map_publisher::*;
+use super::sensor_rig::*;
+use super::town::*;
+use super::msg;
+
+// rosrust keeps a single node per process, so does the bridge
+static CONNECTION_STATE : AtomicUsize = AtomicUsize::new(0);
+// incremented every time a (new) master is found, the topics are advertised again
+static CONNECTION_GENERATION : AtomicUsize = AtomicUsize::new(0);
+static SEND_FAILURES : AtomicUsize = AtomicUsize::new(0);
+static RECONNECTIONS : AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RosConnectionState {
+    // no master reached yet
+    Connecting,
+    Connected,
+    // the master went away, the peer to peer connections may still work
+    Lost,
+}
+
+impl RosConnectionState {
+    fn from_usize(value: usize) -> RosConnectionState {
+        match value {
+            1 => RosConnectionState::Connected,
+            2 => RosConnectionState::Lost,
+            _ => RosConnectionState::Connecting,
+        }
+    }
+
+    fn to_usize(&self) -> usize {
+        match self {
+            RosConnectionState::Connecting => 0,
+            RosConnectionState::Connected => 1,
+            RosConnectionState::Lost => 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RosBridgeStatus {
+    pub state: RosConnectionState,
+    pub generation: usize,
+    pub send_failures: usize,
+    // master restarts seen after the first connection
+    pub reconnections: usize,
+}
+
+impl Default for RosBridgeStatus {
+    fn default() -> Self {
+        RosBridgeStatus {state: RosConnectionState::Connecting, generation: 0, send_failures: 0, reconnections: 0}
+    }
+}
+
+impl RosBridgeStatus {
+    pub fn current() -> RosBridgeStatus {
+        RosBridgeStatus {
+            state: RosConnectionState::from_usize(CONNECTION_STATE.load(Ordering::SeqCst)),
+            generation: CONNECTION_GENERATION.load(Ordering::SeqCst),
+            send_failures: SEND_FAILURES.load(Ordering::SeqCst),
+            reconnections: RECONNECTIONS.load(Ordering::SeqCst),
+        }
+    }
+
+    // true if what was registered at `generation` belongs to an older connection
+    pub fn is_newer_than(&self, generation: usize) -> bool {
+        self.state == RosConnectionState::Connected && self.generation != generation
+    }
+
+    pub fn describe(&self) -> String {
+        let state = match self.state {
+            RosConnectionState::Connecting => "connecting",
+            RosConnectionState::Connected => "connected",
+            RosConnectionState::Lost => "lost",
+        };
+        if self.send_failures > 0 {
+            format!("ROS {} ({} send failures)", state, self.send_failures)
+        } else {
+            format!("ROS {}", state)
+        }
+    }
+}
+
+// a restarted master gets a new /run_id
+fn master_run_id() -> Option<String> {
+    rosrust::param("/run_id").and_then(|param| param.get::<String>().ok())
+}
+
+// watches the master from a background thread: initialises rosrust once a master can be
+// reached and notices when the master goes away or is restarted
+pub struct RosBridge {
+    last_generation: usize,
+}
+
+impl RosBridge {
+    pub fn start(poll_period: time::Duration) -> RosBridge {
+        thread::spawn(move || {
+            let mut run_id : Option<String> = None;
+            loop {
+                if rosrust::is_initialized() || rosrust::try_init("roadsim2d").is_ok() {
+                    let state = RosConnectionState::from_usize(CONNECTION_STATE.load(Ordering::SeqCst));
+                    match master_run_id() {
+                        Some(current_run_id) => {
+                            if run_id.as_ref() != Some(&current_run_id) {
+                                if run_id.is_some() {
+                                    println!("The ROS master restarted, advertising again");
+                                    RECONNECTIONS.fetch_add(1, Ordering::SeqCst);
+                                } else {
+                                    println!("Connected to the ROS master");
+                                }
+                                run_id = Some(current_run_id);
+                                CONNECTION_GENERATION.fetch_add(1, Ordering::SeqCst);
+                            }
+                            CONNECTION_STATE.store(RosConnectionState::Connected.to_usize(), Ordering::SeqCst);
+                        },
+                        None => {
+                            if state == RosConnectionState::Connected {
+                                println!("Lost the ROS master");
+                                CONNECTION_STATE.store(RosConnectionState::Lost.to_usize(), Ordering::SeqCst);
+                            }
+                        }
+                    }
+                }
+                thread::sleep(poll_period);
+            }
+        });
+        RosBridge {last_generation: 0}
+    }
+
+    // true once after every new connection, when subscriptions and services have to be registered
+    pub fn connected_again(&mut self) -> bool {
+        self.update_generation(&RosBridgeStatus::current())
+    }
+
+    fn update_generation(&mut self, status: &RosBridgeStatus) -> bool {
+        if status.is_newer_than(self.last_generation) {
+            self.last_generation = status.generation;
+            return true;
+        }
+        false
+    }
+}
+
+// a topic advertised once the bridge is connected, and again after a master restart,
+// the messages sent before are dropped
+pub struct RosTopic<T: rosrust::Message> {
+    name: String,
+    latching: bool,
+    publisher: Option<Publisher<T>>,
+    generation: usize,
+    // failures are logged once until a message gets through
+    failing: bool,
+}
+
+impl <T: rosrust::Message> RosTopic<T> {
+    pub fn new(name: &str) -> RosTopic<T> {
+        RosTopic {name: String::from(name), latching: false, publisher: None, generation: 0, failing: false}
+    }
+
+    pub fn latched(name: &str) -> RosTopic<T> {
+        RosTopic {latching: true, ..RosTopic::new(name)}
+    }
+
+    // true if the next message advertises the topic, latched topics have to send it again
+    pub fn needs_advertising(&self) -> bool {
+        RosBridgeStatus::current().is_newer_than(self.generation)
+    }
+
+    fn record_failure(&mut self, what: &str, error: &std::fmt::Display) {
+        SEND_FAILURES.fetch_add(1, Ordering::SeqCst);
+        if !self.failing {
+            println!("{} {} failed: {}", what, self.name, error);
+            self.failing = true;
+        }
+    }
+
+    pub fn send(&mut self, msg: T) {
+        if self.needs_advertising() {
+            // the old publisher is unadvertised first
+            self.publisher = None;
+            match rosrust::publish(&self.name) {
+                Ok(mut publisher) => {
+                    publisher.set_latching(self.latching);
+                    self.publisher = Some(publisher);
+                    self.generation = RosBridgeStatus::current().generation;
+                },
+                Err(error) => {
+                    self.record_failure("Advertising", &error);
+                    return;
+                }
+            }
+        }
+        let result = match self.publisher {
+            Some(ref mut publisher) => publisher.send(msg),
+            None => return
+        };
+        match result {
+            Ok(()) => self.failing = false,
+            Err(error) => self.record_failure("Sending on", &error)
+        }
+    }
+}
+
+// one topic per sensor, /roadsim2d/<frame_id>/<name>, created with the first message of the sensor
+pub struct FrameTopics<T: rosrust::Message> {
+    name: &'static str,
+    topics: HashMap<String, RosTopic<T>>,
+}
+
+impl <T: rosrust::Message> FrameTopics<T> {
+    pub fn new(name: &'static str) -> FrameTopics<T> {
+        FrameTopics {name: name, topics: HashMap::new()}
+    }
+
+    pub fn send(&mut self, frame_id: &str, msg: T) {
+        let name = self.name;
+        self.topics.entry(String::from(frame_id))
+            .or_insert_with(|| RosTopic::new(&format!("/roadsim2d/{}/{}", frame_id, name)))
+            .send(msg);
+    }
+}
+
+// sim time of the current step in nanoseconds, the time published on /clock
+static SIM_CLOCK_NANOS : AtomicUsize = AtomicUsize::new(0);
+
+// the stamp of the messages about the current step, none until the bridge has initialised rosrust
+pub fn ros_now() -> Option<rosrust::Time> {
+    sim_time_stamp(SIM_CLOCK_NANOS.load(Ordering::SeqCst) as f64 * 1e-9)
+}
+
+// the stamp of a measurement taken at `sim_time`, delayed outputs keep the time they were measured at
+pub fn sim_time_stamp(sim_time: f64) -> Option<rosrust::Time> {
+    if rosrust::is_initialized() {
+        Some(rosrust::Time::from_nanos((sim_time * 1e9).round() as i64))
+    } else {
+        None
+    }
+}
+
+// publishes the sim time on /clock, ROS nodes using /use_sim_time follow pauses and steps
+pub struct ClockPublisher {
+    clock_pub: RosTopic<msg::rosgraph_msgs::Clock>,
+}
+
+impl ClockPublisher {
+    pub fn new() -> ClockPublisher {
+        ClockPublisher {clock_pub: RosTopic::new("/clock")}
+    }
+}
+
+impl ClockListener for ClockPublisher {
+    fn on_sim_time(&mut self, sim_time: f64) {
+        SIM_CLOCK_NANOS.store((sim_time * 1e9).round() as usize, Ordering::SeqCst);
+        if let Some(stamp) = ros_now() {
+            self.clock_pub.send(msg::rosgraph_msgs::Clock {clock: stamp});
+        }
+    }
+}
+
+// subscriptions and services, registered again whenever the bridge finds a new master
+struct RosInterfaces {
+    _twist_subscriber: Option<TwistSubscriber>,
+    _ackermann_subscriber: Option<AckermannSubscriber>,
+    _path_subscriber: Option<PathSubscriber>,
+    _sim_services: Option<SimServices>,
+    _sim_control_services: Option<SimControlServices>,
+}
+
+// the commands of the ROS topics and services
+pub struct RosCommandSource {
+    bridge: RosBridge,
+    interfaces: Option<RosInterfaces>,
+}
+
+impl RosCommandSource {
+    pub fn new(bridge: RosBridge) -> RosCommandSource {
+        RosCommandSource {bridge: bridge, interfaces: None}
+    }
+}
+
+impl CommandSource for RosCommandSource {
+    fn update(&mut self, targets: &CommandTargets) {
+        if self.bridge.connected_again() {
+            // the old registrations are dropped before registering on the new master
+            self.interfaces.take();
+            self.interfaces = Some(RosInterfaces {
+                _twist_subscriber: TwistSubscriber::new(targets.protagonist.clone()),
+                _ackermann_subscriber: AckermannSubscriber::new(targets.protagonist.clone()),
+                _path_subscriber: PathSubscriber::new(targets.protagonist.clone()),
+                _sim_services: SimServices::try_new(targets.sim_commands.clone()),
+                _sim_control_services: SimControlServices::try_new(targets.sim_control.clone()),
+            });
+        }
+    }
+}
+
+// the latched /map and /tf_static of the loaded scenario
+pub struct RosScenarioPublisher {
+    map_publisher: MapPublisher,
+    static_tf_publisher: StaticTfPublisher,
+}
+
+impl RosScenarioPublisher {
+    pub fn new() -> RosScenarioPublisher {
+        RosScenarioPublisher {map_publisher: MapPublisher::new(), static_tf_publisher: StaticTfPublisher::new()}
+    }
+}
+
+impl StateSink for RosScenarioPublisher {
+    fn on_scenario(&mut self, gridmap: &TownGridMap, sensor_rig: &SensorRig) {
+        self.map_publisher.publish(gridmap);
+        self.static_tf_publisher.publish(sensor_rig);
+    }
+
+    fn on_state(&mut self, _state: &SimState) {
+        self.map_publisher.refresh();
+        self.static_tf_publisher.refresh();
+    }
+}
+
+// all the ROS publishers, and the subscriptions and services registered through a new bridge
+pub fn add_ros_backend(outputs: &mut SimOutputs, command_sources: &mut Vec<Box<CommandSource>>) {
+    outputs.clocks.push(Box::new(ClockPublisher::new()));
+    outputs.vehicle_states.push(Box::new(IbeoPublisher::new()));
+    outputs.lidar_scans.push(Box::new(LidarPublisher::new()));
+    outputs.radar_scans.push(Box::new(RadarPublisher::new()));
+    outputs.gnss_fixes.push(Box::new(GnssPublisher::new()));
+    outputs.imu_samples.push(Box::new(ImuPublisher::new()));
+    outputs.ground_truth.push(Box::new(GroundTruthPublisher::new()));
+    outputs.local_costmaps.push(Box::new(LocalCostmapPublisher::new()));
+    outputs.state_sinks.push(Box::new(RosScenarioPublisher::new()));
+    // the topics are advertised once the bridge reaches a master, which may be started after the simulator
+    command_sources.push(Box::new(RosCommandSource::new(RosBridge::start(time::Duration::from_secs(1)))));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(state: RosConnectionState, generation: usize) -> RosBridgeStatus {
+        RosBridgeStatus {state: state, generation: generation, ..RosBridgeStatus::default()}
+    }
+
+    #[test]
+    fn connection_state_encoding() {
+        for state in [RosConnectionState::Connecting, RosConnectionState::Connected, RosConnectionState::Lost].iter() {
+            assert_eq!(*state, RosConnectionState::from_usize(state.to_usize()));
+        }
+        assert_eq!(RosConnectionState::Connecting, RosConnectionState::from_usize(42));
+    }
+
+    #[test]
+    fn status_description() {
+        assert_eq!("ROS connecting", RosBridgeStatus::default().describe());
+        assert_eq!("ROS connected", status(RosConnectionState::Connected, 1).describe());
+        let failing = RosBridgeStatus {send_failures: 3, ..status(RosConnectionState::Lost, 1)};
+        assert_eq!("ROS lost (3 send failures)", failing.describe());
+    }
+
+    #[test]
+    fn registered_again_once_per_connection() {
+        let mut bridge = RosBridge {last_generation: 0};
+        assert!(!bridge.update_generation(&status(RosConnectionState::Connecting, 0)));
+        assert!(bridge.update_generation(&status(RosConnectionState::Connected, 1)));
+        assert!(!bridge.update_generation(&status(RosConnectionState::Connected, 1)));
+        // nothing to register without a master, the restarted one is a new generation
+        assert!(!bridge.update_generation(&status(RosConnectionState::Lost, 1)));
+        assert!(!bridge.update_generation(&status(RosConnectionState::Lost, 2)));
+        assert!(bridge.update_generation(&status(RosConnectionState::Connected, 2)));
+
+        // a topic advertised at generation 1 has to be advertised again after the restart
+        assert!(!status(RosConnectionState::Connected, 1).is_newer_than(1));
+        assert!(status(RosConnectionState::Connected, 2).is_newer_than(1));
+        assert!(!status(RosConnectionState::Lost, 2).is_newer_than(1));
+    }
+}
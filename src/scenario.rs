@@ -1,22 +1,33 @@
 use serde::{Deserialize, Serialize};
 use std::fs::File;
+use std::path::Path;
 use std::io::Read;
 use std::error::Error;
 
 use super::primitives::*;
+use super::town::*;
+use super::town_generator::*;
+use super::town_export::*;
+use super::junction::*;
+use super::speed_limit::*;
+use super::traffic::*;
+use super::sensor_rig::*;
+use super::odometry::*;
+use super::cost_map_publisher::*;
+use super::protagonist_commands::*;
 
 type SimTimeStamp = f64; 
 use std::collections::VecDeque;
 use std::vec::Vec;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ScriptedCar {
     pub rgb  : (f32, f32, f32),
     pub pose : Pose2DF64,
     pub cmds : VecDeque<CarActionState>
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CarActionState {
     pub stamp:   SimTimeStamp,
     pub lon_vel: f32,
@@ -31,8 +42,86 @@ pub struct InitialPose {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Scenario {
     pub town_image : Option<String>,
+    // ROS map_server yaml, takes precedence over town_image
+    pub town_map : Option<String>,
+    // seed of the random town, used when no image or map is given
+    pub town_seed : Option<u32>,
+    // seed.txt written by `export`, used if town_seed is missing
+    pub town_seed_file : Option<String>,
+    pub town_generator : Option<TownGeneratorParams>,
     pub cars : Vec<ScriptedCar>,
-    pub protagonist_car_init : Option<InitialPose>
+    pub protagonist_car_init : Option<InitialPose>,
+    #[serde(default)]
+    pub junctions : Vec<Junction>,
+    pub speed_limits : Option<SpeedLimitMap>,
+    // lightweight background traffic on the lane graph of generated towns
+    pub traffic : Option<TrafficParams>,
+    // sensors mounted on the protagonist
+    pub sensor_rig : Option<SensorRig>,
+    // errors of the wheel odometry published on /odom
+    pub wheel_odometry : Option<WheelOdometryParams>,
+    // rolling costmap around the protagonist, not published if missing
+    pub local_costmap : Option<LocalCostmapParams>,
+    // timeouts and limits of the ackermann and trajectory commands
+    pub protagonist_control : Option<ProtagonistControlParams>,
+    // reproducible runs: asynchronous work (e.g. path planning) completes at a fixed step
+    #[serde(default)]
+    pub deterministic : bool
+}
+
+// what /roadsim2d/reset restores
+pub struct ScenarioStart {
+    pub protagonist_pose: Pose2DF64,
+    pub cars: Vec<ScriptedCar>,
+}
+
+impl ScenarioStart {
+    pub fn from_scenario(scenario: Option<&Scenario>) -> ScenarioStart {
+        ScenarioStart {
+            protagonist_pose: scenario.and_then(|scenario| scenario.protagonist_car_init.as_ref())
+                .map_or(Pose2DF64::default(), |init| init.pose.clone()),
+            cars: scenario.map_or(Vec::new(), |scenario| scenario.cars.clone()),
+        }
+    }
+}
+
+pub struct LoadedTown {
+    pub gridmap: TownGridMap,
+    pub road_network: RoadNetwork,
+    // set if the town is random
+    pub seed: Option<u32>,
+}
+
+// builds the town described by the scenario
+pub fn load_town_for_scenario(scenario: Option<&Scenario>) -> Result<LoadedTown, Box<Error>> {
+    let seed_file = scenario.and_then(|scenario| scenario.town_seed_file.as_ref());
+    let seed = match (scenario.and_then(|scenario| scenario.town_seed), seed_file) {
+        (Some(seed), _) => seed,
+        (None, Some(seed_file)) => load_town_seed(Path::new(seed_file)).map_err(|error| format!("{}: {}", seed_file, error))?,
+        (None, None) => rand::random()
+    };
+    let town = match scenario {
+        Some(Scenario{town_map: Some(ref town_map), ..}) => {
+            println!("Loading map_server map from scenario");
+            let gridmap = load_town_from_map_server_yaml(town_map)?;
+            LoadedTown {gridmap: gridmap, road_network: RoadNetwork::empty(), seed: None}
+        },
+        Some(Scenario{town_image: Some(ref town_image), ..}) => {
+            println!("Loading image from scenario");
+            let gridmap = load_town_from_file(town_image)?;
+            LoadedTown {gridmap: gridmap, road_network: RoadNetwork::empty(), seed: None}
+        },
+        Some(Scenario{town_generator: Some(ref params), ..}) => {
+            println!("Generating {:?} town with seed {}", params.layout, seed);
+            let (road_network, gridmap) = generate_town(params, seed);
+            LoadedTown {gridmap: gridmap, road_network: road_network, seed: Some(seed)}
+        },
+        _ => {
+            println!("Generating random town with seed {}", seed);
+            LoadedTown {gridmap: make_random_town_gridmap(seed), road_network: RoadNetwork::empty(), seed: Some(seed)}
+        }
+    };
+    Ok(town)
 }
 
 pub struct ScenarioLoader {
@@ -0,0 +1,237 @@
+use rand::{Rng, StdRng};
+use rand::distributions::{Normal, IndependentSample};
+use std::collections::{HashMap, VecDeque};
+
+use super::primitives::*;
+use super::ibeo::*;
+
+// ids given to false positives and to tracks after an id switch, far from the car ids
+const NOISE_FIRST_FAKE_ID : i32 = 1_000_000;
+const FALSE_POSITIVE_MIN_RANGE : f64 = 2.0;
+const MIN_OBJECT_SIZE : f64 = 0.1;
+
+pub fn gaussian_noise(rng: &mut StdRng, stddev: f64) -> f64 {
+    if stddev > 0.0 {
+        Normal::new(0.0, stddev).ind_sample(rng)
+    } else {
+        0.0
+    }
+}
+
+// Knuth's algorithm, fine for the small rates of sensor clutter
+pub fn poisson_sample(rng: &mut StdRng, rate: f64) -> usize {
+    if rate <= 0.0 {
+        return 0;
+    }
+    let limit = (-rate).exp();
+    let mut count = 0;
+    let mut product = rng.gen_range(0.0, 1.0);
+    while product > limit {
+        count += 1;
+        product *= rng.gen_range(0.0, 1.0);
+    }
+    count
+}
+
+// holds the outputs of a sensor until `latency` seconds (of simulation time) have passed
+pub struct LatencyQueue<T> {
+    latency: f64,
+    queue: VecDeque<(f64, T)>,
+}
+
+impl<T> LatencyQueue<T> {
+    pub fn new(latency: f64) -> LatencyQueue<T> {
+        LatencyQueue {latency: latency, queue: VecDeque::new()}
+    }
+
+    pub fn push(&mut self, stamp: f64, item: T) {
+        self.queue.push_back((stamp, item));
+    }
+
+    pub fn pop_ready(&mut self, now: f64) -> Option<T> {
+        match self.queue.front() {
+            Some((stamp, _)) if stamp + self.latency <= now => self.queue.pop_front().map(|(_, item)| item),
+            _ => None
+        }
+    }
+}
+
+// errors of an object list sensor, everything is off by default (perfect ground truth)
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ObjectNoiseParams {
+    pub position_stddev: f64,
+    pub heading_stddev: f64,
+    pub velocity_stddev: f64,
+    pub acceleration_stddev: f64,
+    pub yaw_rate_stddev: f64,
+    // added to the true width and length of the boxes
+    pub width_bias: f64,
+    pub length_bias: f64,
+    pub misclassification_probability: f64,
+    // mean number of false positives per output
+    pub false_positive_rate: f64,
+    pub missed_detection_probability: f64,
+    // probability per output that a track gets a new id
+    pub id_switch_probability: f64,
+    // seconds
+    pub latency: f64,
+}
+
+const WRONG_CLASSIFICATIONS : [IbeoClassification; 5] = [
+    IbeoClassification::UNKNOWN_SMALL,
+    IbeoClassification::UNKNOWN_BIG,
+    IbeoClassification::PEDESTRIAN,
+    IbeoClassification::BIKE,
+    IbeoClassification::TRUCK,
+];
+
+pub struct ObjectNoiseModel {
+    pub params: ObjectNoiseParams,
+    rng: StdRng,
+    // current reported id of the tracks that switched id
+    switched_ids: HashMap<i32, i32>,
+    next_fake_id: i32,
+}
+
+impl ObjectNoiseModel {
+    pub fn new(params: ObjectNoiseParams, rng: StdRng) -> ObjectNoiseModel {
+        ObjectNoiseModel {
+            params: params,
+            rng: rng,
+            switched_ids: HashMap::new(),
+            next_fake_id: NOISE_FIRST_FAKE_ID,
+        }
+    }
+
+    fn fake_id(&mut self) -> i32 {
+        let id = self.next_fake_id;
+        self.next_fake_id += 1;
+        id
+    }
+
+    // `max_range` and `fov` bound the area where false positives appear around `sensor_pose`
+    pub fn apply(&mut self, states: Vec<IbeoVehicleState>, sensor_pose: &Pose2DF64, max_range: f64, fov: f64) -> Vec<IbeoVehicleState> {
+        let mut noisy_states = Vec::with_capacity(states.len());
+        for mut state in states {
+            if self.rng.gen_range(0.0, 1.0) < self.params.missed_detection_probability {
+                continue;
+            }
+
+            if self.rng.gen_range(0.0, 1.0) < self.params.id_switch_probability {
+                let new_id = self.fake_id();
+                self.switched_ids.insert(state.id, new_id);
+            }
+            if let Some(switched_id) = self.switched_ids.get(&state.id) {
+                state.id = *switched_id;
+            }
+
+            state.pose.center.x += gaussian_noise(&mut self.rng, self.params.position_stddev);
+            state.pose.center.y += gaussian_noise(&mut self.rng, self.params.position_stddev);
+            state.pose.yaw += gaussian_noise(&mut self.rng, self.params.heading_stddev);
+            state.velocity.x += gaussian_noise(&mut self.rng, self.params.velocity_stddev);
+            state.velocity.y += gaussian_noise(&mut self.rng, self.params.velocity_stddev);
+            state.acceleration.x += gaussian_noise(&mut self.rng, self.params.acceleration_stddev);
+            state.acceleration.y += gaussian_noise(&mut self.rng, self.params.acceleration_stddev);
+            state.yaw_rate += gaussian_noise(&mut self.rng, self.params.yaw_rate_stddev);
+            state.velocity_sigma = self.params.velocity_stddev;
+            state.acceleration_sigma = self.params.acceleration_stddev;
+            state.yaw_rate_sigma = self.params.yaw_rate_stddev;
+            state.bb_size.width = (state.bb_size.width + self.params.width_bias).max(MIN_OBJECT_SIZE);
+            state.bb_size.height = (state.bb_size.height + self.params.length_bias).max(MIN_OBJECT_SIZE);
+
+            let misclassification_probability = self.params.misclassification_probability;
+            if self.rng.gen_range(0.0, 1.0) < misclassification_probability {
+                state.classification = *self.rng.choose(&WRONG_CLASSIFICATIONS).unwrap();
+                state.classification_certainty = self.rng.gen_range(0.3, 0.7);
+            } else if misclassification_probability > 0.0 {
+                state.classification_certainty = self.rng.gen_range(1.0 - misclassification_probability, 1.0) as f32;
+            }
+            noisy_states.push(state);
+        }
+
+        for _ in 0..poisson_sample(&mut self.rng, self.params.false_positive_rate) {
+            let range = self.rng.gen_range(FALSE_POSITIVE_MIN_RANGE, max_range.max(FALSE_POSITIVE_MIN_RANGE + 1.0));
+            let bearing = sensor_pose.yaw + self.rng.gen_range(-fov / 2.0, fov / 2.0);
+            let id = self.fake_id();
+            let yaw = self.rng.gen_range(-std::f64::consts::PI, std::f64::consts::PI);
+            let size = Size2f64::new(self.rng.gen_range(0.3, 1.5), self.rng.gen_range(0.3, 2.0));
+            let certainty = self.rng.gen_range(0.1, 0.5);
+            noisy_states.push(IbeoVehicleState {
+                id: id,
+                pose: Pose2DF64 {
+                    center: Point2f64::new(sensor_pose.center.x + range * bearing.cos(), sensor_pose.center.y + range * bearing.sin()),
+                    yaw: yaw
+                },
+                bb_size: size,
+                velocity: Vec2f64::new(0.0, 0.0),
+                acceleration: Vec2f64::new(0.0, 0.0),
+                yaw_rate: 0.0,
+                velocity_sigma: self.params.velocity_stddev,
+                acceleration_sigma: self.params.acceleration_stddev,
+                yaw_rate_sigma: self.params.yaw_rate_stddev,
+                age: 1,
+                classification: IbeoClassification::UNKNOWN_SMALL,
+                classification_certainty: certainty,
+            });
+        }
+        noisy_states
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+
+    fn car_state(id: i32) -> IbeoVehicleState {
+        IbeoVehicleState {
+            id: id,
+            pose: Pose2DF64 {center: Point2f64::new(10.0, 2.0), yaw: 0.3},
+            bb_size: Size2f64::new(1.5, 3.0),
+            velocity: Vec2f64::new(5.0, 0.0),
+            acceleration: Vec2f64::new(0.0, 0.0),
+            yaw_rate: 0.0,
+            velocity_sigma: 0.0,
+            acceleration_sigma: 0.0,
+            yaw_rate_sigma: 0.0,
+            age: 3,
+            classification: IbeoClassification::CAR,
+            classification_certainty: 1.0,
+        }
+    }
+
+    #[test]
+    fn default_noise_is_ground_truth() {
+        let mut model = ObjectNoiseModel::new(ObjectNoiseParams::default(), SeedableRng::from_seed(&[1usize][..]));
+        let states = model.apply(vec![car_state(1), car_state(2)], &Pose2DF64::default(), 50.0, 1.0);
+        assert_eq!(2, states.len());
+        assert_eq!(1, states[0].id);
+        assert_eq!(10.0, states[0].pose.center.x);
+        assert_eq!(0.3, states[0].pose.yaw);
+        assert_eq!(3.0, states[0].bb_size.height);
+        assert_eq!(1.0, states[0].classification_certainty);
+    }
+
+    #[test]
+    fn switched_ids_stay_switched() {
+        let params = ObjectNoiseParams {id_switch_probability: 1.0, ..ObjectNoiseParams::default()};
+        let mut model = ObjectNoiseModel::new(params, SeedableRng::from_seed(&[1usize][..]));
+        let first = model.apply(vec![car_state(1)], &Pose2DF64::default(), 50.0, 1.0);
+        assert!(first[0].id >= NOISE_FIRST_FAKE_ID);
+        model.params.id_switch_probability = 0.0;
+        let second = model.apply(vec![car_state(1)], &Pose2DF64::default(), 50.0, 1.0);
+        assert_eq!(first[0].id, second[0].id);
+    }
+
+    #[test]
+    fn latency_queue_releases_in_order() {
+        let mut queue = LatencyQueue::new(0.1);
+        queue.push(0.0, 1);
+        queue.push(0.05, 2);
+        assert_eq!(None, queue.pop_ready(0.09));
+        assert_eq!(Some(1), queue.pop_ready(0.1));
+        assert_eq!(None, queue.pop_ready(0.1));
+        assert_eq!(Some(2), queue.pop_ready(0.2));
+    }
+}
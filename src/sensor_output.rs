@@ -0,0 +1,46 @@
+use specs::{System, ReadExpect, WriteExpect};
+
+use super::global_resources::*;
+
+// receives the measurements of one kind of sensor, e.g. a publisher or a socket client
+pub trait SensorListener<T> {
+    fn on_measurement(&mut self, measurement: &T);
+}
+
+// a sensor state resource queueing its measurements until they are due
+pub trait SensorOutputs: Send + Sync + 'static {
+    type Measurement;
+
+    // moves the measurements due at `sim_time` into `ready`
+    fn pop_ready(&mut self, sim_time: f64, ready: &mut Vec<Self::Measurement>);
+}
+
+// hands the due measurements of the sensors in `S` to the listeners.
+// Listeners are not Send, the output systems have to be run as thread local
+// while the sensor systems filling `S` run in parallel
+pub struct SensorOutputSys<S: SensorOutputs> {
+    pub listeners: Vec<Box<SensorListener<S::Measurement>>>,
+    ready: Vec<S::Measurement>,
+}
+
+impl <S: SensorOutputs> SensorOutputSys<S> {
+    pub fn new(listeners: Vec<Box<SensorListener<S::Measurement>>>) -> SensorOutputSys<S> {
+        SensorOutputSys {listeners: listeners, ready: Vec::new()}
+    }
+}
+
+impl <'a, S: SensorOutputs> System<'a> for SensorOutputSys<S> {
+    type SystemData = (
+        ReadExpect<'a, UpdateDeltaTime>,
+        WriteExpect<'a, S>,
+    );
+
+    fn run(&mut self, (update_delta_time, mut sensors): Self::SystemData) {
+        sensors.pop_ready(update_delta_time.sim_time, &mut self.ready);
+        for measurement in self.ready.drain(..) {
+            for listener in self.listeners.iter_mut() {
+                listener.on_measurement(&measurement);
+            }
+        }
+    }
+}
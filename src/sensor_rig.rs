@@ -0,0 +1,197 @@
+use std::collections::HashSet;
+
+use super::ibeo::*;
+use super::lidar::*;
+use super::radar::*;
+use super::gnss::*;
+use super::imu::*;
+use super::raycast::*;
+
+// frames of the TF tree published by the simulator, a sensor frame with one of these names would break it
+const SIMULATOR_FRAMES : [&str; 3] = ["map", "odom", "base_link"];
+
+// a sensor mounted on the protagonist, `type` selects the simulated model and the
+// other fields are the parameters of that model
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum SensorConfig {
+    Ibeo(IbeoParams),
+    Lidar(LidarParams),
+    Radar(RadarParams),
+    Gnss(GnssParams),
+    Imu(ImuParams),
+}
+
+impl SensorConfig {
+    pub fn frame_id(&self) -> &str {
+        match self {
+            SensorConfig::Ibeo(params) => &params.frame_id,
+            SensorConfig::Lidar(params) => &params.frame_id,
+            SensorConfig::Radar(params) => &params.frame_id,
+            SensorConfig::Gnss(params) => &params.frame_id,
+            SensorConfig::Imu(params) => &params.frame_id,
+        }
+    }
+
+    pub fn mount(&self) -> &SensorMount {
+        match self {
+            SensorConfig::Ibeo(params) => &params.mount,
+            SensorConfig::Lidar(params) => &params.mount,
+            SensorConfig::Radar(params) => &params.mount,
+            SensorConfig::Gnss(params) => &params.mount,
+            SensorConfig::Imu(params) => &params.mount,
+        }
+    }
+}
+
+// the sensor layout of the protagonist. Without a rig in the scenario the protagonist
+// carries a single Ibeo on the vehicle origin
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SensorRig {
+    pub sensors: Vec<SensorConfig>,
+}
+
+impl Default for SensorRig {
+    fn default() -> Self {
+        SensorRig {sensors: vec![SensorConfig::Ibeo(IbeoParams::default())]}
+    }
+}
+
+impl SensorRig {
+    // frame ids name both the TF frames and the topics, they have to be unique
+    pub fn validate(&self) -> Result<(), String> {
+        let mut frame_ids = HashSet::new();
+        for sensor in &self.sensors {
+            if SIMULATOR_FRAMES.iter().any(|frame| *frame == sensor.frame_id()) {
+                return Err(format!("sensor frame {} is published by the simulator", sensor.frame_id()));
+            }
+            if !frame_ids.insert(sensor.frame_id()) {
+                return Err(format!("sensor frame {} is not unique", sensor.frame_id()));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn ibeos(&self) -> Vec<IbeoParams> {
+        self.sensors.iter().filter_map(|sensor| match sensor {
+            SensorConfig::Ibeo(params) => Some(params.clone()),
+            _ => None
+        }).collect()
+    }
+
+    pub fn lidars(&self) -> Vec<LidarParams> {
+        self.sensors.iter().filter_map(|sensor| match sensor {
+            SensorConfig::Lidar(params) => Some(params.clone()),
+            _ => None
+        }).collect()
+    }
+
+    pub fn radars(&self) -> Vec<RadarParams> {
+        self.sensors.iter().filter_map(|sensor| match sensor {
+            SensorConfig::Radar(params) => Some(params.clone()),
+            _ => None
+        }).collect()
+    }
+
+    pub fn gnss_receivers(&self) -> Vec<GnssParams> {
+        self.sensors.iter().filter_map(|sensor| match sensor {
+            SensorConfig::Gnss(params) => Some(params.clone()),
+            _ => None
+        }).collect()
+    }
+
+    pub fn imus(&self) -> Vec<ImuParams> {
+        self.sensors.iter().filter_map(|sensor| match sensor {
+            SensorConfig::Imu(params) => Some(params.clone()),
+            _ => None
+        }).collect()
+    }
+}
+
+#[cfg(feature = "ros")]
+pub use self::ros::*;
+
+#[cfg(feature = "ros")]
+mod ros {
+    use super::*;
+    use crate::ros_bridge::*;
+    use crate::msg;
+
+    impl SensorRig {
+        // base_link -> sensor frame for every mount
+        pub fn mount_transforms(&self, time: &rosrust::Time) -> msg::tf2_msgs::TFMessage {
+            let mut msg = msg::tf2_msgs::TFMessage::default();
+            for sensor in &self.sensors {
+                let mount = sensor.mount();
+                msg.transforms.push(make_tf_trasl_euler("base_link", sensor.frame_id(),
+                    mount.x, mount.y, 0.0, 0.0, 0.0, mount.yaw, time));
+            }
+            msg
+        }
+    }
+
+    // the mounts never move, they are sent once on the latched /tf_static.
+    // The publisher has to be kept alive for late subscribers to get them
+    pub struct StaticTfPublisher {
+        tf_static_pub: RosTopic<msg::tf2_msgs::TFMessage>,
+        rig: SensorRig,
+    }
+
+    impl StaticTfPublisher {
+        pub fn new() -> StaticTfPublisher {
+            StaticTfPublisher {tf_static_pub: RosTopic::latched("/tf_static"), rig: SensorRig {sensors: Vec::new()}}
+        }
+
+        pub fn publish(&mut self, rig: &SensorRig) {
+            self.rig = rig.clone();
+            self.send();
+        }
+
+        // to be called periodically, sends the mounts again after a master restart
+        pub fn refresh(&mut self) {
+            if self.tf_static_pub.needs_advertising() {
+                self.send();
+            }
+        }
+
+        fn send(&mut self) {
+            if let Some(stamp) = ros_now() {
+                self.tf_static_pub.send(self.rig.mount_transforms(&stamp));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rig_from_yaml() {
+        let yaml = r#"
+sensors:
+    - type: Ibeo
+      frame_id: ibeo_front
+      mount: {x: 3.6, y: 0.0, yaw: 0.0}
+    - type: Lidar
+      frame_id: laser_rear
+      mount: {x: -1.0, y: 0.0, yaw: 3.14}
+      rate: 20.0
+"#;
+        let rig : SensorRig = serde_yaml::from_str(yaml).unwrap();
+        assert!(rig.validate().is_ok());
+        assert_eq!(1, rig.ibeos().len());
+        assert_eq!(3.6, rig.ibeos()[0].mount.x);
+        assert_eq!(30.0, rig.ibeos()[0].rate);
+        assert_eq!("laser_rear", rig.lidars()[0].frame_id);
+        assert_eq!(20.0, rig.lidars()[0].rate);
+
+        let duplicated = SensorRig {sensors: vec![rig.sensors[0].clone(), rig.sensors[0].clone()]};
+        assert!(duplicated.validate().is_err());
+
+        for frame_id in SIMULATOR_FRAMES.iter() {
+            let lidar = LidarParams {frame_id: String::from(*frame_id), ..LidarParams::default()};
+            assert!(SensorRig {sensors: vec![SensorConfig::Lidar(lidar)]}.validate().is_err());
+        }
+    }
+}
@@ -0,0 +1,342 @@
+use specs::{System, ReadStorage, WriteStorage, ReadExpect, WriteExpect, Entities, LazyUpdate, Read, Join};
+use nalgebra::{Isometry2, Vector2};
+use nphysics2d::math::Velocity;
+use conrod::color::rgb;
+use rand::{Rng, SeedableRng, StdRng};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Sender};
+use std::rc::Rc;
+use std::cell::RefCell;
+
+use super::primitives::*;
+use super::car::*;
+use super::node::*;
+use super::physics::*;
+use super::protagonist::*;
+use super::global_resources::*;
+use super::sim_id::*;
+use super::car_controller::*;
+use super::car_hl_controller::*;
+use super::car_cmd_list_controller::*;
+use super::junction::*;
+use super::scenario::*;
+use super::traffic::*;
+use super::odometry::*;
+use super::metrics::*;
+use super::protagonist_commands::*;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VehicleProfile {
+    Car,
+    Van,
+    Truck,
+}
+
+impl VehicleProfile {
+    pub fn parse(name: &str) -> Option<VehicleProfile> {
+        match name {
+            "" | "car" => Some(VehicleProfile::Car),
+            "van" => Some(VehicleProfile::Van),
+            "truck" => Some(VehicleProfile::Truck),
+            _ => None
+        }
+    }
+
+    pub fn make_car(&self, id: u64) -> Car {
+        // width, length and wheel base
+        let (width, length, wheel_base) = match self {
+            VehicleProfile::Car => (1.5, 3.0, 2.5),
+            VehicleProfile::Van => (2.0, 5.0, 3.5),
+            VehicleProfile::Truck => (2.5, 8.0, 5.0),
+        };
+        Car {
+            id: id,
+            wheel_yaw: 0.0,
+            wheel_base: wheel_base,
+            bb_size: Size2f64::new(width, length),
+            color: rgb(0.9, 0.5, 0.1),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VehicleBehaviour {
+    // plans paths to random destinations, as the vehicles spawned with K
+    Path,
+    // keeps the spawn heading
+    Straight,
+    Static,
+}
+
+impl VehicleBehaviour {
+    pub fn parse(name: &str) -> Option<VehicleBehaviour> {
+        match name {
+            "" | "path" => Some(VehicleBehaviour::Path),
+            "straight" => Some(VehicleBehaviour::Straight),
+            "static" => Some(VehicleBehaviour::Static),
+            _ => None
+        }
+    }
+}
+
+// the id of the affected vehicle, or why the command failed
+pub type SimCommandReply = Sender<Result<u64, String>>;
+
+pub enum SimCommand {
+    SpawnVehicle {pose: Pose2DF64, profile: VehicleProfile, behaviour: VehicleBehaviour, target_speed: f64, reply: SimCommandReply},
+    DeleteVehicle {id: u64, reply: SimCommandReply},
+    // no id moves the protagonist
+    SetPose {id: Option<u64>, pose: Pose2DF64, reply: SimCommandReply},
+    // removes every vehicle, puts the protagonist back at its start and respawns the scripted cars;
+    // the traffic, the spawned speeds, the odometry, the metrics and the last protagonist command start over as well
+    Reset {reply: SimCommandReply},
+}
+
+// commands are produced by the ROS service threads or the socket clients and executed by SimCommandSys
+pub type SimCommandQueue = Arc<Mutex<VecDeque<SimCommand>>>;
+
+// blocks until SimCommandSys executed the command, a queued command always runs so
+// the caller waits for its result however long the main loop takes
+pub fn submit_sim_command<F>(queue: &SimCommandQueue, make_command: F) -> Result<u64, String> where F: FnOnce(SimCommandReply) -> SimCommand {
+    let (reply, result) = channel();
+    queue.lock().unwrap().push_back(make_command(reply));
+    result.recv().unwrap_or_else(|_| Err(String::from("the simulation stopped")))
+}
+
+// draws the speed of the spawned vehicles, seeded by the scenario so that deterministic runs repeat
+pub struct SpawnRng {
+    seed: u32,
+    rng: StdRng,
+}
+
+impl SpawnRng {
+    pub fn new(seed: u32) -> SpawnRng {
+        SpawnRng {seed: seed, rng: SeedableRng::from_seed(&[seed as usize][..])}
+    }
+
+    pub fn reset(&mut self) {
+        *self = SpawnRng::new(self.seed);
+    }
+}
+
+fn set_body_pose(physics_world: &mut PhysicsWorld, physics_component: &PhysicsComponent, pose: &Pose2DF64) {
+    let rigid_body = physics_world.rigid_body_mut(physics_component.body_handle).expect("car rigid body not found");
+    rigid_body.set_position(Isometry2::new(Vector2::new(pose.center.x, pose.center.y), pose.yaw));
+    rigid_body.set_velocity(Velocity::zero());
+}
+
+// the id provider is shared through an Rc, the system has to be run as thread local
+pub struct SimCommandSys {
+    pub commands: SimCommandQueue,
+    pub id_provider: Rc<RefCell<IdProvider>>,
+    pub protagonist_commands: ProtagonistCommandHandle,
+}
+
+impl <'a> System<'a> for SimCommandSys {
+    type SystemData = (
+        Entities<'a>,
+        ReadExpect<'a, UpdateDeltaTime>,
+        ReadExpect<'a, ScenarioStart>,
+        WriteExpect<'a, PhysicsWorld>,
+        WriteExpect<'a, TrafficState>,
+        WriteExpect<'a, WheelOdometry>,
+        WriteExpect<'a, ProtagonistMetrics>,
+        WriteExpect<'a, SpawnRng>,
+        ReadStorage<'a, Car>,
+        WriteStorage<'a, Node>,
+        ReadStorage<'a, PhysicsComponent>,
+        ReadStorage<'a, ProtagonistTag>,
+        ReadStorage<'a, CarPathControllerState>,
+        Read<'a, LazyUpdate>
+    );
+
+    fn run(&mut self, (entities, update_delta_time, scenario_start, mut physics_world, mut traffic, mut wheel_odometry, mut metrics,
+            mut spawn_rng, cars, mut nodes, physics_components, protagonists, path_controller_states, updater): Self::SystemData) {
+        let commands : Vec<SimCommand> = self.commands.lock().unwrap().drain(..).collect();
+        for command in commands {
+            match command {
+                SimCommand::SpawnVehicle {pose, profile, behaviour, target_speed, reply} => {
+                    let car = profile.make_car(self.id_provider.borrow_mut().next());
+                    let id = car.id;
+                    let target_speed = match behaviour {
+                        VehicleBehaviour::Static => 0.0,
+                        _ if target_speed > 0.0 => target_speed,
+                        _ => spawn_rng.rng.gen_range(10.0, 20.0),
+                    };
+                    self.spawn(&entities, &mut physics_world, &updater, car, pose, behaviour, target_speed, VecDeque::new());
+                    reply.send(Ok(id)).ok();
+                },
+                SimCommand::DeleteVehicle {id, reply} => {
+                    let found = (&entities, &cars, &physics_components, !&protagonists).join()
+                        .find(|(_entity, car, _physics_component, ())| car.id == id)
+                        .map(|(entity, _car, physics_component, ())| (entity, physics_component.body_handle));
+                    match found {
+                        Some((entity, body_handle)) => {
+                            physics_world.remove_bodies(&[body_handle]);
+                            entities.delete(entity).ok();
+                            reply.send(Ok(id)).ok();
+                        },
+                        None => { reply.send(Err(format!("no vehicle with id {}", id))).ok(); }
+                    }
+                },
+                SimCommand::SetPose {id, pose, reply} => {
+                    let found = (&entities, &cars, &physics_components, &mut nodes).join()
+                        .find(|(entity, car, _physics_component, _node)| match id {
+                            Some(id) => car.id == id,
+                            None => protagonists.get(*entity).is_some()
+                        });
+                    match found {
+                        Some((entity, car, physics_component, node)) => {
+                            set_body_pose(&mut physics_world, physics_component, &pose);
+                            node.pose = pose;
+                            // the old path starts somewhere else
+                            if path_controller_states.get(entity).is_some() {
+                                updater.insert(entity, CarPathControllerState::new());
+                            }
+                            reply.send(Ok(car.id)).ok();
+                        },
+                        None => { reply.send(Err(String::from("vehicle not found"))).ok(); }
+                    }
+                },
+                SimCommand::Reset {reply} => {
+                    for (entity, physics_component, ()) in (&entities, &physics_components, !&protagonists).join() {
+                        physics_world.remove_bodies(&[physics_component.body_handle]);
+                        entities.delete(entity).ok();
+                    }
+                    for (physics_component, node, _protagonist) in (&physics_components, &mut nodes, &protagonists).join() {
+                        set_body_pose(&mut physics_world, physics_component, &scenario_start.protagonist_pose);
+                        node.pose = scenario_start.protagonist_pose.clone();
+                    }
+                    // the commands of the scripted cars are timed from the reset
+                    let sim_time = update_delta_time.sim_time;
+                    for scripted_car in scenario_start.cars.iter().cloned() {
+                        let cmds = scripted_car.cmds.into_iter()
+                            .map(|cmd| CarActionState {stamp: cmd.stamp + sim_time, ..cmd})
+                            .collect();
+                        CarCmdListController::create_car(&entities, &mut physics_world, &updater, self.id_provider.clone(),
+                            scripted_car.pose, cmds, scripted_car.rgb);
+                    }
+                    traffic.reset();
+                    spawn_rng.reset();
+                    wheel_odometry.reset();
+                    *metrics = ProtagonistMetrics::default();
+                    *self.protagonist_commands.lock().unwrap() = ProtagonistCommandState::new();
+                    reply.send(Ok(0)).ok();
+                },
+            }
+        }
+    }
+}
+
+impl SimCommandSys {
+    fn spawn(&self, entities: &Entities, physics_world: &mut PhysicsWorld, updater: &LazyUpdate, car: Car, pose: Pose2DF64,
+             behaviour: VehicleBehaviour, target_speed: f64, mut cmds: VecDeque<CarActionState>) {
+        let entity = entities.create();
+        let physics_component = make_physics_for_car(physics_world, &car, &pose);
+
+        let mut hl_control_state = CarHighLevelControllerState::new();
+        hl_control_state.target_yaw = pose.yaw as f32;
+        hl_control_state.target_long_speed = target_speed as f32;
+        if let Some(first_cmd) = cmds.pop_front() {
+            hl_control_state.target_yaw = first_cmd.yaw;
+            hl_control_state.target_long_speed = first_cmd.lon_vel;
+        }
+
+        if behaviour == VehicleBehaviour::Path {
+            updater.insert(entity, CarPathControllerState::new());
+        }
+        if !cmds.is_empty() {
+            updater.insert(entity, CarCmdListState::with_cmds(cmds));
+        }
+        updater.insert(entity, car);
+        updater.insert(entity, Node {pose: pose});
+        updater.insert(entity, physics_component);
+        updater.insert(entity, CarController{});
+        updater.insert(entity, hl_control_state);
+        updater.insert(entity, CarJunctionState::new());
+    }
+}
+
+#[cfg(feature = "ros")]
+pub use self::ros::*;
+
+#[cfg(feature = "ros")]
+mod ros {
+    use super::*;
+    use rosrust::api::raii::Service;
+    use crate::msg;
+
+    fn pose_from_request(x: f64, y: f64, yaw: f64) -> Pose2DF64 {
+        Pose2DF64 {center: Point2f64::new(x, y), yaw: yaw}
+    }
+
+    // /roadsim2d/{spawn_vehicle,delete_vehicle,set_pose,reset}, the services stop with this struct
+    pub struct SimServices {
+        _services: Vec<Service>,
+    }
+
+    impl SimServices {
+        // ROS has to be initialised already, RosBridge registers them again after a master restart
+        pub fn try_new(queue: SimCommandQueue) -> Option<SimServices> {
+            if !rosrust::is_initialized() {
+                return None;
+            }
+            let mut services = Vec::new();
+
+            let spawn_queue = queue.clone();
+            services.push(rosrust::service::<msg::roadsim2d_msgs::SpawnVehicle, _>("/roadsim2d/spawn_vehicle", move |req| {
+                let profile = VehicleProfile::parse(&req.profile).ok_or(format!("unknown profile {}", req.profile))?;
+                let behaviour = VehicleBehaviour::parse(&req.behaviour).ok_or(format!("unknown behaviour {}", req.behaviour))?;
+                let result = submit_sim_command(&spawn_queue, |reply| SimCommand::SpawnVehicle {
+                    pose: pose_from_request(req.x, req.y, req.yaw),
+                    profile: profile,
+                    behaviour: behaviour,
+                    target_speed: req.target_speed,
+                    reply: reply
+                });
+                Ok(msg::roadsim2d_msgs::SpawnVehicleRes {
+                    success: result.is_ok(),
+                    id: *result.as_ref().unwrap_or(&0),
+                    message: result.err().unwrap_or_default(),
+                })
+            }).ok()?);
+
+            let delete_queue = queue.clone();
+            services.push(rosrust::service::<msg::roadsim2d_msgs::DeleteVehicle, _>("/roadsim2d/delete_vehicle", move |req| {
+                let result = submit_sim_command(&delete_queue, |reply| SimCommand::DeleteVehicle {id: req.id, reply: reply});
+                Ok(msg::roadsim2d_msgs::DeleteVehicleRes {success: result.is_ok(), message: result.err().unwrap_or_default()})
+            }).ok()?);
+
+            let set_pose_queue = queue.clone();
+            services.push(rosrust::service::<msg::roadsim2d_msgs::SetPose, _>("/roadsim2d/set_pose", move |req| {
+                let id = if req.protagonist { None } else { Some(req.id) };
+                let result = submit_sim_command(&set_pose_queue, |reply| SimCommand::SetPose {id: id, pose: pose_from_request(req.x, req.y, req.yaw), reply: reply});
+                Ok(msg::roadsim2d_msgs::SetPoseRes {success: result.is_ok(), message: result.err().unwrap_or_default()})
+            }).ok()?);
+
+            let reset_queue = queue.clone();
+            services.push(rosrust::service::<msg::std_srvs::Trigger, _>("/roadsim2d/reset", move |_req| {
+                let result = submit_sim_command(&reset_queue, |reply| SimCommand::Reset {reply: reply});
+                Ok(msg::std_srvs::TriggerRes {success: result.is_ok(), message: result.err().unwrap_or_default()})
+            }).ok()?);
+
+            Some(SimServices {_services: services})
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_strings() {
+        assert_eq!(Some(VehicleProfile::Car), VehicleProfile::parse(""));
+        assert_eq!(Some(VehicleProfile::Truck), VehicleProfile::parse("truck"));
+        assert_eq!(None, VehicleProfile::parse("bus"));
+        assert_eq!(Some(VehicleBehaviour::Path), VehicleBehaviour::parse(""));
+        assert_eq!(Some(VehicleBehaviour::Static), VehicleBehaviour::parse("static"));
+        assert_eq!(None, VehicleBehaviour::parse("fly"));
+    }
+}
@@ -0,0 +1,166 @@
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::Sender;
+
+// steps run in a single window update while stepping, the rest are run in the next ones
+const MAX_STEPS_PER_UPDATE : u32 = 100;
+
+// a scenario file to load, none reloads the current one
+pub struct ScenarioLoadRequest {
+    pub path: Option<String>,
+    pub reply: Sender<Result<(), String>>,
+}
+
+// how the main loop advances the simulation, every step lasts the window update period
+pub struct SimControl {
+    pub paused: bool,
+    pub real_time_factor: f64,
+    pending_steps: u32,
+    // sim time at the end of the requested steps
+    step_reply: Option<Sender<f64>>,
+    // fraction of a step carried to the next update when the real time factor is not an integer
+    step_budget: f64,
+    pub load_request: Option<ScenarioLoadRequest>,
+}
+
+impl SimControl {
+    pub fn new() -> SimControl {
+        SimControl {
+            paused: false,
+            real_time_factor: 1.0,
+            pending_steps: 0,
+            step_reply: None,
+            step_budget: 0.0,
+            load_request: None,
+        }
+    }
+
+    // pauses and runs exactly `steps` steps, the reply is sent once they are done
+    pub fn request_steps(&mut self, steps: u32, reply: Sender<f64>) {
+        self.paused = true;
+        self.pending_steps += steps;
+        self.step_reply = Some(reply);
+    }
+
+    // number of steps to run for one window update
+    pub fn steps_for_update(&mut self) -> u32 {
+        if self.pending_steps > 0 {
+            let steps = self.pending_steps.min(MAX_STEPS_PER_UPDATE);
+            self.pending_steps -= steps;
+            return steps;
+        }
+        if self.paused {
+            return 0;
+        }
+        self.step_budget += self.real_time_factor;
+        let steps = self.step_budget.floor();
+        self.step_budget -= steps;
+        steps as u32
+    }
+
+    // to be called after the steps of an update have run
+    pub fn on_steps_done(&mut self, sim_time: f64) {
+        if self.pending_steps == 0 {
+            if let Some(reply) = self.step_reply.take() {
+                reply.send(sim_time).ok();
+            }
+        }
+    }
+}
+
+// shared by the ROS service threads, the socket clients and the main loop
+pub type SimControlHandle = Arc<Mutex<SimControl>>;
+
+#[cfg(feature = "ros")]
+pub use self::ros::*;
+
+#[cfg(feature = "ros")]
+mod ros {
+    use super::*;
+    use rosrust::api::raii::Service;
+    use std::sync::mpsc::channel;
+    use crate::msg;
+
+    // /roadsim2d/{pause,step,set_real_time_factor,load_scenario}, the services stop with this struct
+    pub struct SimControlServices {
+        _services: Vec<Service>,
+    }
+
+    impl SimControlServices {
+        // none before rosrust is initialised, as SimServices
+        pub fn try_new(control: SimControlHandle) -> Option<SimControlServices> {
+            if !rosrust::is_initialized() {
+                return None;
+            }
+            let mut services = Vec::new();
+
+            let pause_control = control.clone();
+            services.push(rosrust::service::<msg::std_srvs::SetBool, _>("/roadsim2d/pause", move |req| {
+                pause_control.lock().unwrap().paused = req.data;
+                Ok(msg::std_srvs::SetBoolRes {success: true, message: String::new()})
+            }).ok()?);
+
+            let step_control = control.clone();
+            services.push(rosrust::service::<msg::roadsim2d_msgs::Step, _>("/roadsim2d/step", move |req| {
+                let (reply, done) = channel();
+                step_control.lock().unwrap().request_steps(req.steps, reply);
+                // the sender is dropped if the simulation stops or a newer request replaces this one
+                match done.recv() {
+                    Ok(sim_time) => Ok(msg::roadsim2d_msgs::StepRes {success: true, sim_time: sim_time}),
+                    Err(_) => Ok(msg::roadsim2d_msgs::StepRes {success: false, sim_time: 0.0})
+                }
+            }).ok()?);
+
+            let factor_control = control.clone();
+            services.push(rosrust::service::<msg::roadsim2d_msgs::SetRealTimeFactor, _>("/roadsim2d/set_real_time_factor", move |req| {
+                if !(req.factor > 0.0) {
+                    return Ok(msg::roadsim2d_msgs::SetRealTimeFactorRes {success: false, message: String::from("the factor has to be positive")});
+                }
+                factor_control.lock().unwrap().real_time_factor = req.factor;
+                Ok(msg::roadsim2d_msgs::SetRealTimeFactorRes {success: true, message: String::new()})
+            }).ok()?);
+
+            let load_control = control.clone();
+            services.push(rosrust::service::<msg::roadsim2d_msgs::LoadScenario, _>("/roadsim2d/load_scenario", move |req| {
+                let (reply, loaded) = channel();
+                let path = if req.path.is_empty() { None } else { Some(req.path.clone()) };
+                load_control.lock().unwrap().load_request = Some(ScenarioLoadRequest {path: path, reply: reply});
+                let result = loaded.recv().unwrap_or_else(|_| Err(String::from("the simulation stopped")));
+                Ok(msg::roadsim2d_msgs::LoadScenarioRes {success: result.is_ok(), message: result.err().unwrap_or_default()})
+            }).ok()?);
+
+            Some(SimControlServices {_services: services})
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn real_time_factor_sets_the_steps_per_update() {
+        let mut control = SimControl::new();
+        control.real_time_factor = 0.5;
+        let steps : Vec<u32> = (0..4).map(|_| control.steps_for_update()).collect();
+        assert_eq!(vec![0, 1, 0, 1], steps);
+        control.real_time_factor = 2.5;
+        let steps : u32 = (0..4).map(|_| control.steps_for_update()).sum();
+        assert_eq!(10, steps);
+    }
+
+    #[test]
+    fn stepping_pauses_and_answers_when_done() {
+        let mut control = SimControl::new();
+        let (reply, done) = channel();
+        control.request_steps(150, reply);
+        assert_eq!(MAX_STEPS_PER_UPDATE, control.steps_for_update());
+        control.on_steps_done(1.0);
+        assert!(done.try_recv().is_err());
+        assert_eq!(50, control.steps_for_update());
+        control.on_steps_done(2.5);
+        assert_eq!(Ok(2.5), done.try_recv());
+        assert!(control.paused);
+        assert_eq!(0, control.steps_for_update());
+    }
+}
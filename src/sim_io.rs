@@ -0,0 +1,202 @@
+use specs::{System, ReadStorage, ReadExpect, Join};
+
+use super::primitives::*;
+use super::car::*;
+use super::node::*;
+use super::physics::*;
+use super::protagonist::*;
+use super::protagonist_commands::*;
+use super::global_resources::*;
+use super::traffic::*;
+use super::speed_limit::*;
+use super::odometry::*;
+use super::town::*;
+use super::ibeo::*;
+use super::lidar::*;
+use super::radar::*;
+use super::gnss::*;
+use super::imu::*;
+use super::cost_map_publisher::*;
+use super::ground_truth_objects::*;
+use super::sensor_rig::*;
+use super::sensor_output::*;
+use super::sim_commands::*;
+use super::sim_control::*;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PoseState {
+    pub x: f64,
+    pub y: f64,
+    pub yaw: f64,
+}
+
+impl<'a> From<&'a Pose2DF64> for PoseState {
+    fn from(pose: &'a Pose2DF64) -> PoseState {
+        PoseState {x: pose.center.x, y: pose.center.y, yaw: pose.yaw}
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ProtagonistSnapshot {
+    pub id: u64,
+    // map frame
+    pub pose: PoseState,
+    pub speed: f64,
+    pub yaw_rate: f64,
+    // estimated by the wheel odometry, in the odom frame
+    pub odometry_pose: PoseState,
+    pub odometry_speed: f64,
+    pub speed_limit: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ObjectSnapshot {
+    pub id: u64,
+    pub pose: PoseState,
+    pub length: f64,
+    pub width: f64,
+    pub velocity_x: f64,
+    pub velocity_y: f64,
+    pub yaw_rate: f64,
+}
+
+impl<'a> From<&'a GroundTruthObject> for ObjectSnapshot {
+    fn from(object: &'a GroundTruthObject) -> ObjectSnapshot {
+        ObjectSnapshot {
+            id: object.id,
+            pose: PoseState::from(&object.pose),
+            length: object.bb_size.height,
+            width: object.bb_size.width,
+            velocity_x: object.velocity.x,
+            velocity_y: object.velocity.y,
+            yaw_rate: object.yaw_rate,
+        }
+    }
+}
+
+// the ground truth of the simulation, independent of the transport it is sent on
+#[derive(Debug, Clone, Serialize)]
+pub struct SimState {
+    pub sim_time: f64,
+    pub protagonist: Option<ProtagonistSnapshot>,
+    // the other vehicles and the traffic agents, in the map frame
+    pub objects: Vec<ObjectSnapshot>,
+}
+
+pub trait StateSink {
+    // a scenario was loaded, the town and the sensor mounts may have changed
+    fn on_scenario(&mut self, _gridmap: &TownGridMap, _sensor_rig: &SensorRig) {}
+    // called once per window update, while paused as well
+    fn on_state(&mut self, state: &SimState);
+}
+
+pub trait ClockListener {
+    // called at the start of every step, before the outputs measured during it
+    fn on_sim_time(&mut self, sim_time: f64);
+}
+
+// where the commands of the command sources end up
+#[derive(Clone)]
+pub struct CommandTargets {
+    pub protagonist: ProtagonistCommandHandle,
+    pub sim_commands: SimCommandQueue,
+    pub sim_control: SimControlHandle,
+}
+
+// the ROS topics and services, or the clients of the socket bridge
+pub trait CommandSource {
+    // called once per window update by the main loop, sources connect and accept clients here
+    fn update(&mut self, targets: &CommandTargets);
+}
+
+// the outputs of the simulation, every backend adds its listeners and sinks
+#[derive(Default)]
+pub struct SimOutputs {
+    pub vehicle_states: Vec<Box<VehicleStatesListener>>,
+    pub lidar_scans: Vec<Box<SensorListener<LidarScan>>>,
+    pub radar_scans: Vec<Box<SensorListener<RadarScan>>>,
+    pub gnss_fixes: Vec<Box<SensorListener<GnssFix>>>,
+    pub imu_samples: Vec<Box<SensorListener<ImuSample>>>,
+    pub ground_truth: Vec<Box<GroundTruthListener>>,
+    pub local_costmaps: Vec<Box<LocalCostmapListener>>,
+    pub state_sinks: Vec<Box<StateSink>>,
+    pub clocks: Vec<Box<ClockListener>>,
+}
+
+// the first thread local system of the update dispatcher
+pub struct SimClockSys {
+    pub listeners: Vec<Box<ClockListener>>,
+}
+
+impl SimClockSys {
+    pub fn new(listeners: Vec<Box<ClockListener>>) -> SimClockSys {
+        SimClockSys {listeners: listeners}
+    }
+}
+
+impl <'a> System<'a> for SimClockSys {
+    type SystemData = ReadExpect<'a, UpdateDeltaTime>;
+
+    fn run(&mut self, update_delta_time: Self::SystemData) {
+        for listener in self.listeners.iter_mut() {
+            listener.on_sim_time(update_delta_time.sim_time);
+        }
+    }
+}
+
+// sinks are not Send, the system is run by the main loop after the simulation steps
+pub struct StateSinkSys {
+    pub sinks: Vec<Box<StateSink>>,
+}
+
+impl StateSinkSys {
+    pub fn new(sinks: Vec<Box<StateSink>>) -> StateSinkSys {
+        StateSinkSys {sinks: sinks}
+    }
+
+    pub fn on_scenario(&mut self, gridmap: &TownGridMap, sensor_rig: &SensorRig) {
+        for sink in self.sinks.iter_mut() {
+            sink.on_scenario(gridmap, sensor_rig);
+        }
+    }
+}
+
+impl <'a> System<'a> for StateSinkSys {
+    type SystemData = (
+        ReadExpect<'a, UpdateDeltaTime>,
+        ReadExpect<'a, PhysicsWorld>,
+        ReadExpect<'a, TrafficState>,
+        ReadExpect<'a, SpeedLimitMap>,
+        ReadExpect<'a, WheelOdometry>,
+        ReadStorage<'a, Car>,
+        ReadStorage<'a, Node>,
+        ReadStorage<'a, PhysicsComponent>,
+        ReadStorage<'a, ProtagonistTag>,
+    );
+
+    fn run(&mut self, (update_delta_time, physics_world, traffic, speed_limit_map, odometry, cars, nodes, physics_components, protagonists): Self::SystemData) {
+        let protagonist = (&cars, &nodes, &physics_components, &protagonists).join().next()
+            .map(|(car, node, physics_component, _protagonist)| {
+                let velocity = physics_world.rigid_body(physics_component.body_handle).expect("protagonist rigid body not found").velocity();
+                ProtagonistSnapshot {
+                    id: car.id,
+                    pose: PoseState::from(&node.pose),
+                    speed: velocity.linear.x * node.pose.yaw.cos() + velocity.linear.y * node.pose.yaw.sin(),
+                    yaw_rate: velocity.angular,
+                    odometry_pose: PoseState::from(&odometry.estimate.pose),
+                    odometry_speed: odometry.estimate.speed,
+                    speed_limit: speed_limit_map.speed_limit_at(&node.pose),
+                }
+            });
+
+        let objects = collect_ground_truth_objects(&physics_world, &traffic, &cars, &nodes, &physics_components, &protagonists);
+        let state = SimState {
+            sim_time: update_delta_time.sim_time,
+            protagonist: protagonist,
+            objects: objects.iter().map(ObjectSnapshot::from).collect(),
+        };
+        for sink in self.sinks.iter_mut() {
+            sink.on_state(&state);
+        }
+    }
+}
@@ -0,0 +1,622 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, sync_channel, Receiver, SyncSender};
+use std::thread;
+use std::time;
+
+use super::primitives::*;
+use super::protagonist::*;
+use super::protagonist_commands::*;
+use super::sim_commands::*;
+use super::sim_control::*;
+use super::sim_io::*;
+use super::sensor_output::*;
+use super::sensor_rig::*;
+use super::lidar::*;
+use super::radar::*;
+use super::gnss::*;
+use super::imu::*;
+use super::ibeo::*;
+use super::odometry::*;
+use super::raycast::*;
+use super::cost_map_publisher::*;
+use super::town::*;
+
+// a client that does not read its messages is dropped once this many are queued for it
+const CLIENT_QUEUE_LENGTH : usize = 256;
+// a write blocked for longer ends the writer thread of the client
+const CLIENT_WRITE_TIMEOUT_MS : u64 = 200;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct PathPointMessage {
+    pub x: f64,
+    pub y: f64,
+    // seconds, the speed between two stamped points is the one needed to reach them in time
+    #[serde(default)]
+    pub stamp: Option<f64>,
+}
+
+// the clients send one JSON object per line, tagged by "type"
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    Twist {linear: f64, angular: f64},
+    Ackermann {
+        steering_angle: f64,
+        speed: f64,
+        #[serde(default)]
+        acceleration: f64
+    },
+    // in the map frame
+    Path {points: Vec<PathPointMessage>},
+    Pause {paused: bool},
+    // answered with stepped once the steps have run
+    Step {steps: u32},
+    SetRealTimeFactor {factor: f64},
+    Reset,
+    // the same requests as the /roadsim2d services, answered with a message of the same type
+    SpawnVehicle {
+        x: f64,
+        y: f64,
+        yaw: f64,
+        #[serde(default)]
+        profile: String,
+        #[serde(default)]
+        behaviour: String,
+        #[serde(default)]
+        target_speed: f64
+    },
+    DeleteVehicle {id: u64},
+    // no id moves the protagonist
+    SetPose {
+        #[serde(default)]
+        id: Option<u64>,
+        x: f64,
+        y: f64,
+        yaw: f64
+    },
+    // no path reloads the current scenario
+    LoadScenario {
+        #[serde(default)]
+        path: Option<String>
+    },
+}
+
+// an object of the Ibeo list in the sensor frame, as ibeo_msgs/ObjectListEcuObj
+#[derive(Debug, Clone, Serialize)]
+pub struct IbeoObjectMessage {
+    pub id: i32,
+    pub classification: IbeoClassification,
+    pub classification_certainty: f32,
+    pub age: i32,
+    pub x: f64,
+    pub y: f64,
+    pub box_orientation: f64,
+    pub width: f64,
+    pub height: f64,
+    pub abs_vel_x: f64,
+    pub abs_vel_y: f64,
+    pub rel_vel_x: f64,
+    pub rel_vel_y: f64,
+    pub accel_x: f64,
+    pub accel_y: f64,
+    pub yaw_rate: f64,
+    pub velocity_sigma: f64,
+    pub acceleration_sigma: f64,
+    pub yaw_rate_sigma: f64,
+}
+
+impl IbeoObjectMessage {
+    fn new(sensor_mount: &SensorMount, ego: &IbeoEgoState, state: &IbeoVehicleState) -> IbeoObjectMessage {
+        let object = to_sensor_frame(sensor_mount, ego, state);
+        IbeoObjectMessage {
+            id: state.id,
+            classification: state.classification,
+            classification_certainty: state.classification_certainty,
+            age: state.age,
+            x: object.center.x,
+            y: object.center.y,
+            box_orientation: object.box_orientation,
+            width: state.bb_size.width,
+            height: state.bb_size.height,
+            abs_vel_x: object.abs_vel.x,
+            abs_vel_y: object.abs_vel.y,
+            rel_vel_x: object.rel_vel.x,
+            rel_vel_y: object.rel_vel.y,
+            accel_x: object.acceleration.x,
+            accel_y: object.acceleration.y,
+            yaw_rate: state.yaw_rate,
+            velocity_sigma: state.velocity_sigma,
+            acceleration_sigma: state.acceleration_sigma,
+            yaw_rate_sigma: state.yaw_rate_sigma,
+        }
+    }
+}
+
+// the town in the map frame, as nav_msgs/OccupancyGrid
+#[derive(Debug, Clone, Serialize)]
+pub struct MapMessage {
+    pub resolution: f64,
+    pub origin_x: f64,
+    pub origin_y: f64,
+    pub width: usize,
+    pub height: usize,
+    // row major from the cell at the origin, -1 unknown, 0 free and 100 occupied
+    pub data: Vec<i8>,
+}
+
+impl MapMessage {
+    fn new(gridmap: &TownGridMap) -> MapMessage {
+        let info = &gridmap.info;
+        MapMessage {
+            resolution: info.resolution,
+            origin_x: info.origin.x,
+            origin_y: info.origin.y,
+            width: info.width,
+            height: info.height,
+            data: town_occupancy_data(gridmap),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage<'a> {
+    State(&'a SimState),
+    Stepped {sim_time: f64},
+    Reset {success: bool, message: String},
+    SpawnVehicle {success: bool, id: u64, message: String},
+    DeleteVehicle {success: bool, message: String},
+    SetPose {success: bool, message: String},
+    LoadScenario {success: bool, message: String},
+    // beams without return are null
+    LidarScan(&'a LidarScan),
+    RadarScan(&'a RadarScan),
+    GnssFix(&'a GnssFix),
+    ImuSample(&'a ImuSample),
+    // the noisy object list of the Ibeo `frame_id`, measured at sim_time
+    IbeoObjects {frame_id: &'a str, sim_time: f64, objects: Vec<IbeoObjectMessage>},
+    // in the map frame, values as in `map`
+    LocalCostmap(&'a LocalCostmap),
+    // sent when a scenario is loaded and to every new client
+    Map(&'a MapMessage),
+    Error {message: String},
+}
+
+fn spawn_vehicle(targets: &CommandTargets, pose: Pose2DF64, profile: &str, behaviour: &str, target_speed: f64) -> Result<u64, String> {
+    let profile = VehicleProfile::parse(profile).ok_or(format!("unknown profile {}", profile))?;
+    let behaviour = VehicleBehaviour::parse(behaviour).ok_or(format!("unknown behaviour {}", behaviour))?;
+    submit_sim_command(&targets.sim_commands, |reply| SimCommand::SpawnVehicle {
+        pose: pose,
+        profile: profile,
+        behaviour: behaviour,
+        target_speed: target_speed,
+        reply: reply
+    })
+}
+
+// executes a message of a client, the reply if it has one
+pub fn handle_client_message(message: ClientMessage, targets: &CommandTargets) -> Option<ServerMessage<'static>> {
+    match message {
+        ClientMessage::Twist {linear, angular} => {
+            targets.protagonist.lock().unwrap().set(ProtagonistCommand::Twist(Twist2D {x: linear, y: 0.0, z_rot: angular}));
+            None
+        },
+        ClientMessage::Ackermann {steering_angle, speed, acceleration} => {
+            targets.protagonist.lock().unwrap().set(ProtagonistCommand::Ackermann(AckermannTarget {
+                steering_angle: steering_angle,
+                speed: speed,
+                acceleration: acceleration.abs(),
+            }));
+            None
+        },
+        ClientMessage::Path {points} => {
+            let points = points.into_iter().map(|point| TrajectoryPoint {
+                position: Point2f64::new(point.x, point.y),
+                stamp: point.stamp,
+            }).collect();
+            targets.protagonist.lock().unwrap().set(ProtagonistCommand::Trajectory(Trajectory::new(points)));
+            None
+        },
+        ClientMessage::Pause {paused} => {
+            targets.sim_control.lock().unwrap().paused = paused;
+            None
+        },
+        ClientMessage::Step {steps} => {
+            let (reply, done) = channel();
+            targets.sim_control.lock().unwrap().request_steps(steps, reply);
+            // the sender is dropped if the simulation stops or a newer request replaces this one
+            Some(match done.recv() {
+                Ok(sim_time) => ServerMessage::Stepped {sim_time: sim_time},
+                Err(_) => ServerMessage::Error {message: String::from("the steps were not run")}
+            })
+        },
+        ClientMessage::SetRealTimeFactor {factor} => {
+            if !(factor > 0.0) {
+                return Some(ServerMessage::Error {message: String::from("the factor has to be positive")});
+            }
+            targets.sim_control.lock().unwrap().real_time_factor = factor;
+            None
+        },
+        ClientMessage::Reset => {
+            let result = submit_sim_command(&targets.sim_commands, |reply| SimCommand::Reset {reply: reply});
+            Some(ServerMessage::Reset {success: result.is_ok(), message: result.err().unwrap_or_default()})
+        },
+        ClientMessage::SpawnVehicle {x, y, yaw, profile, behaviour, target_speed} => {
+            let pose = Pose2DF64 {center: Point2f64::new(x, y), yaw: yaw};
+            let result = spawn_vehicle(targets, pose, &profile, &behaviour, target_speed);
+            Some(ServerMessage::SpawnVehicle {
+                success: result.is_ok(),
+                id: *result.as_ref().unwrap_or(&0),
+                message: result.err().unwrap_or_default(),
+            })
+        },
+        ClientMessage::DeleteVehicle {id} => {
+            let result = submit_sim_command(&targets.sim_commands, |reply| SimCommand::DeleteVehicle {id: id, reply: reply});
+            Some(ServerMessage::DeleteVehicle {success: result.is_ok(), message: result.err().unwrap_or_default()})
+        },
+        ClientMessage::SetPose {id, x, y, yaw} => {
+            let pose = Pose2DF64 {center: Point2f64::new(x, y), yaw: yaw};
+            let result = submit_sim_command(&targets.sim_commands, |reply| SimCommand::SetPose {id: id, pose: pose, reply: reply});
+            Some(ServerMessage::SetPose {success: result.is_ok(), message: result.err().unwrap_or_default()})
+        },
+        ClientMessage::LoadScenario {path} => {
+            let (reply, loaded) = channel();
+            targets.sim_control.lock().unwrap().load_request = Some(ScenarioLoadRequest {path: path, reply: reply});
+            let result = loaded.recv().unwrap_or_else(|_| Err(String::from("the simulation stopped")));
+            Some(ServerMessage::LoadScenario {success: result.is_ok(), message: result.err().unwrap_or_default()})
+        },
+    }
+}
+
+fn message_line(message: &ServerMessage) -> String {
+    let mut line = serde_json::to_string(message).expect("server messages are always serializable");
+    line.push('\n');
+    line
+}
+
+// the message queue of every connected client, by client id
+type ClientQueues = Arc<Mutex<HashMap<usize, SyncSender<String>>>>;
+
+// queues the line without waiting for the client, a full queue drops the client
+fn queue_line(queues: &mut HashMap<usize, SyncSender<String>>, client: usize, line: String) {
+    let failed = match queues.get(&client) {
+        Some(queue) => queue.try_send(line).is_err(),
+        None => false
+    };
+    if failed {
+        println!("Socket client {} does not read its messages, dropping it", client);
+        queues.remove(&client);
+    }
+}
+
+fn send_to_client(queues: &ClientQueues, client: usize, message: &ServerMessage) {
+    queue_line(&mut queues.lock().unwrap(), client, message_line(message));
+}
+
+fn send_to_all(queues: &ClientQueues, message: &ServerMessage) {
+    send_line_to_all(queues, message_line(message));
+}
+
+fn send_line_to_all(queues: &ClientQueues, line: String) {
+    let mut queues = queues.lock().unwrap();
+    let clients : Vec<usize> = queues.keys().cloned().collect();
+    for client in clients {
+        queue_line(&mut queues, client, line.clone());
+    }
+}
+
+// writes the queued messages of a client, stops when the client is dropped or a write fails
+fn write_to_client(lines: Receiver<String>, mut writer: Box<Write + Send>) {
+    for line in lines {
+        if writer.write_all(line.as_bytes()).and_then(|()| writer.flush()).is_err() {
+            break;
+        }
+    }
+}
+
+// reads the messages of a client until it disconnects
+fn serve_client(client: usize, reader: Box<Read + Send>, queues: ClientQueues, targets: CommandTargets) {
+    for line in BufReader::new(reader).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let reply = match serde_json::from_str::<ClientMessage>(&line) {
+            Ok(message) => handle_client_message(message, &targets),
+            Err(error) => Some(ServerMessage::Error {message: format!("invalid message: {}", error)})
+        };
+        if let Some(reply) = reply {
+            send_to_client(&queues, client, &reply);
+        }
+    }
+    queues.lock().unwrap().remove(&client);
+    println!("Socket client {} disconnected", client);
+}
+
+enum SocketListener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+#[cfg(unix)]
+fn bind_unix(path: &str) -> io::Result<SocketListener> {
+    // the socket file of a previous run
+    std::fs::remove_file(path).ok();
+    Ok(SocketListener::Unix(UnixListener::bind(path)?))
+}
+
+#[cfg(not(unix))]
+fn bind_unix(_path: &str) -> io::Result<SocketListener> {
+    Err(io::Error::new(io::ErrorKind::Other, "unix sockets are not available on this platform"))
+}
+
+// accepts clients on host:port or unix:/path and gives their messages to the simulation
+pub struct SocketCommandSource {
+    listener: SocketListener,
+    queues: ClientQueues,
+    // the map message of the loaded scenario, as the latched /map
+    map_line: Arc<Mutex<Option<String>>>,
+    next_client: usize,
+}
+
+impl SocketCommandSource {
+    pub fn bind(address: &str) -> io::Result<SocketCommandSource> {
+        let listener = if address.starts_with("unix:") {
+            bind_unix(&address["unix:".len()..])?
+        } else {
+            SocketListener::Tcp(TcpListener::bind(address)?)
+        };
+        // clients are accepted by update, from the main loop
+        match listener {
+            SocketListener::Tcp(ref listener) => listener.set_nonblocking(true)?,
+            #[cfg(unix)]
+            SocketListener::Unix(ref listener) => listener.set_nonblocking(true)?,
+        }
+        println!("Waiting for socket clients on {}", address);
+        Ok(SocketCommandSource {listener: listener, queues: Arc::new(Mutex::new(HashMap::new())), map_line: Arc::new(Mutex::new(None)),
+                                next_client: 0})
+    }
+
+    // sends the state to the clients of this source
+    pub fn state_sink(&self) -> SocketStateSink {
+        SocketStateSink {queues: self.queues.clone(), map_line: self.map_line.clone(), last_sim_time: None}
+    }
+
+    // sends the sensor measurements, the Ibeo objects and the local costmap to the clients of this source
+    pub fn sensor_sink(&self) -> SocketSensorSink {
+        SocketSensorSink {queues: self.queues.clone()}
+    }
+
+    // the reading and writing halves of a new client, none if nobody is waiting
+    fn accept(&self) -> io::Result<Option<(Box<Read + Send>, Box<Write + Send>)>> {
+        let write_timeout = Some(time::Duration::from_millis(CLIENT_WRITE_TIMEOUT_MS));
+        let accepted = match self.listener {
+            SocketListener::Tcp(ref listener) => listener.accept().and_then(|(stream, _)| {
+                // the accepted stream may inherit the non blocking mode of the listener
+                stream.set_nonblocking(false)?;
+                stream.set_nodelay(true)?;
+                stream.set_write_timeout(write_timeout)?;
+                Ok((Box::new(stream.try_clone()?) as Box<Read + Send>, Box::new(stream) as Box<Write + Send>))
+            }),
+            #[cfg(unix)]
+            SocketListener::Unix(ref listener) => listener.accept().and_then(|(stream, _)| {
+                stream.set_nonblocking(false)?;
+                stream.set_write_timeout(write_timeout)?;
+                Ok((Box::new(stream.try_clone()?) as Box<Read + Send>, Box::new(stream) as Box<Write + Send>))
+            }),
+        };
+        match accepted {
+            Ok(halves) => Ok(Some(halves)),
+            Err(ref error) if error.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(error) => Err(error)
+        }
+    }
+}
+
+impl CommandSource for SocketCommandSource {
+    fn update(&mut self, targets: &CommandTargets) {
+        loop {
+            match self.accept() {
+                Ok(Some((reader, writer))) => {
+                    let client = self.next_client;
+                    self.next_client += 1;
+                    let (queue, lines) = sync_channel(CLIENT_QUEUE_LENGTH);
+                    {
+                        let mut queues = self.queues.lock().unwrap();
+                        queues.insert(client, queue);
+                        if let Some(ref map_line) = *self.map_line.lock().unwrap() {
+                            queue_line(&mut queues, client, map_line.clone());
+                        }
+                    }
+                    thread::spawn(move || write_to_client(lines, writer));
+                    let queues = self.queues.clone();
+                    let targets = targets.clone();
+                    thread::spawn(move || serve_client(client, reader, queues, targets));
+                    println!("Socket client {} connected", client);
+                },
+                Ok(None) => break,
+                Err(error) => {
+                    println!("Accepting a socket client failed: {}", error);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+// sends a state message to every client whenever the simulation time changes, and the map of every scenario
+pub struct SocketStateSink {
+    queues: ClientQueues,
+    map_line: Arc<Mutex<Option<String>>>,
+    last_sim_time: Option<f64>,
+}
+
+impl StateSink for SocketStateSink {
+    fn on_scenario(&mut self, gridmap: &TownGridMap, _sensor_rig: &SensorRig) {
+        // the simulation time starts again from zero
+        self.last_sim_time = None;
+        let map_line = message_line(&ServerMessage::Map(&MapMessage::new(gridmap)));
+        *self.map_line.lock().unwrap() = Some(map_line.clone());
+        send_line_to_all(&self.queues, map_line);
+    }
+
+    fn on_state(&mut self, state: &SimState) {
+        if self.last_sim_time == Some(state.sim_time) {
+            return;
+        }
+        self.last_sim_time = Some(state.sim_time);
+        send_to_all(&self.queues, &ServerMessage::State(state));
+    }
+}
+
+// sends every lidar scan, radar scan, GNSS fix, IMU sample, Ibeo object list and local costmap to every client
+pub struct SocketSensorSink {
+    queues: ClientQueues,
+}
+
+impl SensorListener<LidarScan> for SocketSensorSink {
+    fn on_measurement(&mut self, scan: &LidarScan) {
+        send_to_all(&self.queues, &ServerMessage::LidarScan(scan));
+    }
+}
+
+impl SensorListener<RadarScan> for SocketSensorSink {
+    fn on_measurement(&mut self, scan: &RadarScan) {
+        send_to_all(&self.queues, &ServerMessage::RadarScan(scan));
+    }
+}
+
+impl SensorListener<GnssFix> for SocketSensorSink {
+    fn on_measurement(&mut self, fix: &GnssFix) {
+        send_to_all(&self.queues, &ServerMessage::GnssFix(fix));
+    }
+}
+
+impl SensorListener<ImuSample> for SocketSensorSink {
+    fn on_measurement(&mut self, sample: &ImuSample) {
+        send_to_all(&self.queues, &ServerMessage::ImuSample(sample));
+    }
+}
+
+impl VehicleStatesListener for SocketSensorSink {
+    // part of the state messages
+    fn on_protagonist_state<'a>(&'a mut self, _protagonist_pose: &'a Pose2DF64, _protagonist_speed : f64, _protagonist_yaw_rate: f64,
+                                _odometry: &'a OdometryEstimate) {}
+
+    fn on_vehicle_states<'a>(&'a mut self, frame_id: &'a str, sensor_mount: &'a SensorMount, ego: &'a IbeoEgoState, vehicle_states : &'a Vec<IbeoVehicleState>) {
+        let objects = vehicle_states.iter().map(|state| IbeoObjectMessage::new(sensor_mount, ego, state)).collect();
+        send_to_all(&self.queues, &ServerMessage::IbeoObjects {frame_id: frame_id, sim_time: ego.sim_time, objects: objects});
+    }
+
+    fn on_speed_limit(&mut self, _speed_limit: Option<f64>) {}
+}
+
+impl LocalCostmapListener for SocketSensorSink {
+    fn on_local_costmap(&mut self, costmap: &LocalCostmap) {
+        send_to_all(&self.queues, &ServerMessage::LocalCostmap(costmap));
+    }
+}
+
+// the socket clients, their commands and the state and sensor messages sent to them
+pub fn add_socket_backend(source: SocketCommandSource, outputs: &mut SimOutputs, command_sources: &mut Vec<Box<CommandSource>>) {
+    outputs.state_sinks.push(Box::new(source.state_sink()));
+    outputs.lidar_scans.push(Box::new(source.sensor_sink()));
+    outputs.radar_scans.push(Box::new(source.sensor_sink()));
+    outputs.gnss_fixes.push(Box::new(source.sensor_sink()));
+    outputs.imu_samples.push(Box::new(source.sensor_sink()));
+    outputs.vehicle_states.push(Box::new(source.sensor_sink()));
+    outputs.local_costmaps.push(Box::new(source.sensor_sink()));
+    command_sources.push(Box::new(source));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim_control::SimControl;
+    use std::collections::VecDeque;
+
+    fn targets() -> CommandTargets {
+        CommandTargets {
+            protagonist: Arc::new(Mutex::new(ProtagonistCommandState::new())),
+            sim_commands: Arc::new(Mutex::new(VecDeque::new())),
+            sim_control: Arc::new(Mutex::new(SimControl::new())),
+        }
+    }
+
+    #[test]
+    fn client_messages_from_json() {
+        let message : ClientMessage = serde_json::from_str(r#"{"type": "ackermann", "steering_angle": 0.1, "speed": 3.0}"#).unwrap();
+        assert_eq!(ClientMessage::Ackermann {steering_angle: 0.1, speed: 3.0, acceleration: 0.0}, message);
+        let message : ClientMessage = serde_json::from_str(r#"{"type": "path", "points": [{"x": 1.0, "y": 2.0}, {"x": 3.0, "y": 2.0, "stamp": 1.5}]}"#).unwrap();
+        assert_eq!(ClientMessage::Path {points: vec![
+            PathPointMessage {x: 1.0, y: 2.0, stamp: None},
+            PathPointMessage {x: 3.0, y: 2.0, stamp: Some(1.5)},
+        ]}, message);
+        assert_eq!(ClientMessage::Reset, serde_json::from_str(r#"{"type": "reset"}"#).unwrap());
+        assert!(serde_json::from_str::<ClientMessage>(r#"{"type": "fly"}"#).is_err());
+        let message : ClientMessage = serde_json::from_str(r#"{"type": "spawn_vehicle", "x": 1.0, "y": 2.0, "yaw": 0.5, "profile": "van"}"#).unwrap();
+        assert_eq!(ClientMessage::SpawnVehicle {x: 1.0, y: 2.0, yaw: 0.5, profile: String::from("van"), behaviour: String::new(), target_speed: 0.0}, message);
+        let message : ClientMessage = serde_json::from_str(r#"{"type": "set_pose", "x": 1.0, "y": 2.0, "yaw": 0.0}"#).unwrap();
+        assert_eq!(ClientMessage::SetPose {id: None, x: 1.0, y: 2.0, yaw: 0.0}, message);
+        assert_eq!(ClientMessage::LoadScenario {path: None}, serde_json::from_str(r#"{"type": "load_scenario"}"#).unwrap());
+    }
+
+    #[test]
+    fn sensor_messages_to_json() {
+        let fix = GnssFix {frame_id: String::from("gps"), sim_time: 1.5, fix: true, latitude: 45.0, longitude: 9.0, altitude: 0.0, position_stddev: 1.0};
+        let line = message_line(&ServerMessage::GnssFix(&fix));
+        assert!(line.ends_with('\n'));
+        let json : serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!("gnss_fix", json["type"]);
+        assert_eq!("gps", json["frame_id"]);
+        assert_eq!(45.0, json["latitude"]);
+
+        let mut gridmap = TownGridMap::new(TownMapInfo::centered(3, 2, 0.5));
+        gridmap.set_cell(1, 0, TownCell::Free);
+        gridmap.set_cell(2, 1, TownCell::Unknown);
+        let json : serde_json::Value = serde_json::from_str(&message_line(&ServerMessage::Map(&MapMessage::new(&gridmap)))).unwrap();
+        assert_eq!("map", json["type"]);
+        assert_eq!(3, json["width"]);
+        assert_eq!(json!([100, 0, 100, 100, 100, -1]), json["data"]);
+    }
+
+    #[test]
+    fn full_queue_drops_the_client() {
+        let queues : ClientQueues = Arc::new(Mutex::new(HashMap::new()));
+        let (queue, lines) = sync_channel(CLIENT_QUEUE_LENGTH);
+        queues.lock().unwrap().insert(7, queue);
+        let message = ServerMessage::Stepped {sim_time: 1.0};
+        for _ in 0..CLIENT_QUEUE_LENGTH {
+            send_to_all(&queues, &message);
+        }
+        assert!(queues.lock().unwrap().contains_key(&7));
+        send_to_all(&queues, &message);
+        assert!(queues.lock().unwrap().is_empty());
+        assert_eq!(CLIENT_QUEUE_LENGTH, lines.iter().count());
+    }
+
+    #[test]
+    fn client_messages_reach_the_targets() {
+        let targets = targets();
+        assert!(handle_client_message(ClientMessage::Twist {linear: 2.0, angular: 0.5}, &targets).is_none());
+        match targets.protagonist.lock().unwrap().command {
+            ProtagonistCommand::Twist(ref twist) => assert_eq!((2.0, 0.5), (twist.x, twist.z_rot)),
+            _ => panic!("expected a twist command")
+        }
+
+        handle_client_message(ClientMessage::Pause {paused: true}, &targets);
+        assert!(targets.sim_control.lock().unwrap().paused);
+        let reply = handle_client_message(ClientMessage::SetRealTimeFactor {factor: -1.0}, &targets);
+        assert!(match reply { Some(ServerMessage::Error {..}) => true, _ => false });
+        assert_eq!(1.0, targets.sim_control.lock().unwrap().real_time_factor);
+    }
+}
@@ -0,0 +1,114 @@
+use specs::{System, Entities, ReadStorage, WriteStorage, ReadExpect, Join, VecStorage, Component};
+use std::f64::consts::PI;
+
+use super::primitives::*;
+use super::node::*;
+use super::car_controller::CarController;
+
+const SPEED_LIMIT_HEADING_TOLERANCE : f64 = PI / 4.0;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SpeedLimitZone {
+    pub min: (f64, f64),
+    pub max: (f64, f64),
+    // if set, the limit only applies to cars driving along this direction (one lane direction)
+    pub yaw: Option<f64>,
+    pub speed_limit: f64,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SpeedLimitMap {
+    pub default_speed_limit: Option<f64>,
+    #[serde(default)]
+    pub zones: Vec<SpeedLimitZone>,
+}
+
+impl SpeedLimitZone {
+    pub fn applies_to(&self, pose: &Pose2DF64) -> bool {
+        let center = pose.center;
+        let inside = center.x >= self.min.0 && center.x <= self.max.0 &&
+                     center.y >= self.min.1 && center.y <= self.max.1;
+        if !inside {
+            return false;
+        }
+        match self.yaw {
+            Some(yaw) => {
+                let diff = (pose.yaw - yaw).sin().atan2((pose.yaw - yaw).cos());
+                diff.abs() < SPEED_LIMIT_HEADING_TOLERANCE
+            },
+            None => true
+        }
+    }
+}
+
+impl SpeedLimitMap {
+    // the most restrictive limit among the zones containing the pose
+    pub fn speed_limit_at(&self, pose: &Pose2DF64) -> Option<f64> {
+        let zone_limit = self.zones.iter()
+            .filter(|zone| zone.applies_to(pose))
+            .map(|zone| zone.speed_limit)
+            .fold(None, |acc: Option<f64>, limit| Some(acc.map_or(limit, |acc| acc.min(limit))));
+        zone_limit.or(self.default_speed_limit)
+    }
+}
+
+// the limit at the current position of a car, in m/s
+#[derive(Component, Debug, Default)]
+#[storage(VecStorage)]
+pub struct CarSpeedLimit {
+    pub speed_limit: Option<f32>,
+}
+
+pub struct SpeedLimitSys;
+
+impl <'a> System<'a> for SpeedLimitSys {
+    type SystemData = (
+        Entities<'a>,
+        ReadExpect<'a, SpeedLimitMap>,
+        ReadStorage<'a, Node>,
+        ReadStorage<'a, CarController>,
+        WriteStorage<'a, CarSpeedLimit>,
+    );
+
+    fn run(&mut self, (entities, speed_limit_map, nodes, car_controllers, mut speed_limits): Self::SystemData) {
+        for (entity, node, _car_controller) in (&entities, &nodes, &car_controllers).join() {
+            let speed_limit = speed_limit_map.speed_limit_at(&node.pose).map(|limit| limit as f32);
+            speed_limits.insert(entity, CarSpeedLimit {speed_limit: speed_limit}).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pose(x: f64, y: f64, yaw: f64) -> Pose2DF64 {
+        Pose2DF64{center: Point2f64::new(x, y), yaw: yaw}
+    }
+
+    #[test]
+    fn most_restrictive_zone_wins() {
+        let map = SpeedLimitMap {
+            default_speed_limit: Some(13.9),
+            zones: vec![
+                SpeedLimitZone{min: (-10.0, -10.0), max: (10.0, 10.0), yaw: None, speed_limit: 8.3},
+                SpeedLimitZone{min: (0.0, 0.0), max: (5.0, 5.0), yaw: None, speed_limit: 2.7},
+            ]
+        };
+        assert_eq!(Some(13.9), map.speed_limit_at(&pose(50.0, 0.0, 0.0)));
+        assert_eq!(Some(8.3), map.speed_limit_at(&pose(-5.0, 0.0, 0.0)));
+        assert_eq!(Some(2.7), map.speed_limit_at(&pose(1.0, 1.0, 0.0)));
+    }
+
+    #[test]
+    fn lane_direction_zone() {
+        let map = SpeedLimitMap {
+            default_speed_limit: None,
+            zones: vec![
+                SpeedLimitZone{min: (-10.0, -10.0), max: (10.0, 10.0), yaw: Some(PI), speed_limit: 5.0},
+            ]
+        };
+        assert_eq!(Some(5.0), map.speed_limit_at(&pose(0.0, 0.0, -PI + 0.1)));
+        assert_eq!(None, map.speed_limit_at(&pose(0.0, 0.0, 0.0)));
+    }
+}
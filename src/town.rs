@@ -3,7 +3,7 @@ use glium::*;
 use cgmath;
 use euclid;
 use rand;
-use rand::Rng;
+use rand::{Rng, SeedableRng, StdRng};
 use piston_window::*;
 use ::image::{GenericImageView, ImageBuffer};
 use ::image;
@@ -11,6 +11,9 @@ use super::camera::Camera;
 use super::primitives::*;
 use num::clamp;
 use std::path::Path;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::ops::Deref;
 
 use std::ops::Index;
 use std::ops::IndexMut;
@@ -20,40 +23,251 @@ use cgmath::Rotation;
 use specs::{System, ReadStorage, Component, ReadExpect};
 use pathfinding::grid;
 
-pub type TownGridMap = grid::Grid;
 use num::traits::Pow;
 
-const TOWN_SIZE : usize = 1000usize;
-const TOWN_ZOOM : f64 = 1.0;
+const DEFAULT_TOWN_SIZE : usize = 1000usize;
+const DEFAULT_TOWN_RESOLUTION : f64 = 1.0;
 const TURTLE_DRAW_RADIUS : i32 = 4;
 
-struct TownTurtle {
-    pos: Vec2i32,
-    theta: f32
+// size and placement of the gridmap in the world, same meaning as in a ROS map_server yaml:
+// `resolution` is in m/cell and `origin` is the world position of the lower-left corner of cell (0, 0)
+#[derive(Debug, Clone, Copy)]
+pub struct TownMapInfo {
+    pub resolution: f64,
+    pub origin: Vec2f64,
+    pub width: usize,
+    pub height: usize,
 }
 
-pub fn vec2f32_2_vec2i32(v: Vec2f32) -> Vec2i32 {
-    Vec2i32::new(v.x as i32, v.y as i32)
+impl TownMapInfo {
+    // map centered on the world origin
+    pub fn centered(width: usize, height: usize, resolution: f64) -> TownMapInfo {
+        TownMapInfo {
+            resolution: resolution,
+            origin: Vec2f64::new(-(width as f64) * resolution / 2.0, -(height as f64) * resolution / 2.0),
+            width: width,
+            height: height,
+        }
+    }
 }
 
-pub fn vec2i32_2_vec2f32_center(v: Vec2i32) -> Vec2f32 {
-    Vec2f32::new(v.x as f32 + 0.5f32, v.y as f32 + 0.5f32)
+impl Default for TownMapInfo {
+    fn default() -> Self {
+        TownMapInfo::centered(DEFAULT_TOWN_SIZE, DEFAULT_TOWN_SIZE, DEFAULT_TOWN_RESOLUTION)
+    }
+}
+
+// ROS map_server map description, the yaml next to a town image only needs resolution and origin
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MapServerYaml {
+    #[serde(default)]
+    pub image: String,
+    pub resolution: f64,
+    pub origin: [f64; 3],
+    #[serde(default)]
+    pub negate: i32,
+    #[serde(default = "default_occupied_thresh")]
+    pub occupied_thresh: f64,
+    #[serde(default = "default_free_thresh")]
+    pub free_thresh: f64,
+}
+
+fn default_occupied_thresh() -> f64 {
+    MAP_SERVER_OCCUPIED_THRESH
+}
+
+fn default_free_thresh() -> f64 {
+    MAP_SERVER_FREE_THRESH
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TownCell {
+    Free,
+    Occupied,
+    Unknown,
+}
+
+const NO_FREE_CELL : u32 = std::u32::MAX;
+
+// for every cell the index of the closest free cell, computed with an exact
+// euclidean distance transform (Felzenszwalb and Huttenlocher) so that queries are a single lookup
+#[derive(Clone)]
+pub struct NearestFreeIndex {
+    width: usize,
+    nearest: Vec<u32>,
+}
+
+impl NearestFreeIndex {
+    pub fn build(grid: &grid::Grid, width: usize, height: usize) -> NearestFreeIndex {
+        // closest free row in the same column
+        let mut column_nearest = vec![NO_FREE_CELL; width * height];
+        for (x, y) in grid.iter() {
+            column_nearest[y * width + x] = y as u32;
+        }
+        for x in 0..width {
+            let mut last_free = NO_FREE_CELL;
+            for y in 0..height {
+                let index = y * width + x;
+                if column_nearest[index] == y as u32 {
+                    last_free = y as u32;
+                } else {
+                    column_nearest[index] = last_free;
+                }
+            }
+            let mut next_free = NO_FREE_CELL;
+            for y in (0..height).rev() {
+                let index = y * width + x;
+                if column_nearest[index] == y as u32 {
+                    next_free = y as u32;
+                } else if next_free != NO_FREE_CELL &&
+                        (column_nearest[index] == NO_FREE_CELL || next_free - (y as u32) < (y as u32) - column_nearest[index]) {
+                    column_nearest[index] = next_free;
+                }
+            }
+        }
+
+        // lower envelope, along every row, of the parabolas rooted at the closest free cell of each column
+        let mut nearest = vec![NO_FREE_CELL; width * height];
+        let mut sites : Vec<usize> = Vec::with_capacity(width);
+        let mut bounds : Vec<f64> = Vec::with_capacity(width + 1);
+        for y in 0..height {
+            let cost = |x: usize| {
+                let row = column_nearest[y * width + x] as f64;
+                (row - y as f64).powi(2) + (x as f64).powi(2)
+            };
+            // bounds[i] is where the parabola of sites[i] starts to be the lowest one
+            sites.clear();
+            bounds.clear();
+            for x in 0..width {
+                if column_nearest[y * width + x] == NO_FREE_CELL {
+                    continue;
+                }
+                let mut intersection = std::f64::NEG_INFINITY;
+                while let Some(&site) = sites.last() {
+                    intersection = (cost(x) - cost(site)) / (2.0 * (x as f64 - site as f64));
+                    if intersection <= bounds[sites.len() - 1] {
+                        sites.pop();
+                        bounds.pop();
+                        intersection = std::f64::NEG_INFINITY;
+                    } else {
+                        break;
+                    }
+                }
+                sites.push(x);
+                bounds.push(intersection);
+            }
+            if sites.is_empty() {
+                continue;
+            }
+            let mut k = 0;
+            for x in 0..width {
+                while k + 1 < sites.len() && bounds[k + 1] < x as f64 {
+                    k += 1;
+                }
+                let site = sites[k];
+                nearest[y * width + x] = column_nearest[y * width + site] * width as u32 + site as u32;
+            }
+        }
+
+        NearestFreeIndex {width: width, nearest: nearest}
+    }
+
+    pub fn nearest_free(&self, x: usize, y: usize) -> Option<(usize, usize)> {
+        match self.nearest[y * self.width + x] {
+            NO_FREE_CELL => None,
+            index => Some((index as usize % self.width, index as usize / self.width))
+        }
+    }
+}
+
+// drivable cells are the vertices of `grid`, cells of unknown state the vertices of `unknown`
+#[derive(Clone)]
+pub struct TownGridMap {
+    pub grid: grid::Grid,
+    pub unknown: grid::Grid,
+    pub info: TownMapInfo,
+    nearest_free_index: Option<NearestFreeIndex>,
+}
+
+impl Deref for TownGridMap {
+    type Target = grid::Grid;
+
+    fn deref(&self) -> &grid::Grid {
+        &self.grid
+    }
 }
 
-fn gridmap_xy_to_world(pos: Vec2i32) -> Vec2f32 {
-    (vec2i32_2_vec2f32_center(pos)  - Vec2f32::new(TOWN_SIZE as f32 /2.0f32, TOWN_SIZE as f32 /2.0f32) ) / TOWN_ZOOM as f32
+impl TownGridMap {
+    pub fn new(info: TownMapInfo) -> TownGridMap {
+        let mut grid = grid::Grid::new(info.width, info.height);
+        grid.enable_diagonal_mode();
+        TownGridMap {
+            grid: grid,
+            unknown: grid::Grid::new(info.width, info.height),
+            info: info,
+            nearest_free_index: None,
+        }
+    }
+
+    // to be called once the map is complete, editing the map afterwards drops the index
+    pub fn build_nearest_free_index(&mut self) {
+        self.nearest_free_index = Some(NearestFreeIndex::build(&self.grid, self.info.width, self.info.height));
+    }
+
+    pub fn cell(&self, x: usize, y: usize) -> TownCell {
+        if self.grid.has_vertex(&(x, y)) {
+            TownCell::Free
+        } else if self.unknown.has_vertex(&(x, y)) {
+            TownCell::Unknown
+        } else {
+            TownCell::Occupied
+        }
+    }
+
+    pub fn set_cell(&mut self, x: usize, y: usize, cell: TownCell) {
+        if self.nearest_free_index.take().is_some() {
+            println!("town cell ({}, {}) changed, dropping the nearest free space index", x, y);
+        }
+        self.grid.remove_vertex(&(x, y));
+        self.unknown.remove_vertex(&(x, y));
+        match cell {
+            TownCell::Free => { self.grid.add_vertex((x, y)); },
+            TownCell::Unknown => { self.unknown.add_vertex((x, y)); },
+            TownCell::Occupied => {}
+        }
+    }
+
+    pub fn gridmap_xy_to_world(&self, pos: Vec2i32) -> Vec2f32 {
+        let origin = vec2f64_2_vec2f32(self.info.origin);
+        origin + vec2i32_2_vec2f32_center(pos) * self.info.resolution as f32
+    }
+
+    pub fn world_to_gridmap_xy(&self, pos: Vec2f32) -> Vec2i32 {
+        let origin = vec2f64_2_vec2f32(self.info.origin);
+        let cell = (pos - origin) / self.info.resolution as f32;
+        Vec2i32::new(cell.x.floor() as i32, cell.y.floor() as i32)
+    }
+
+    fn gridmap_enforce_bounds(&self, v: Vec2i32) -> Vec2i32 {
+        Vec2i32::new(clamp(v.x, 0i32, self.info.width as i32 - 1), clamp(v.y, 0, self.info.height as i32 - 1))
+    }
+
+    pub fn world_to_gridmap_xy_enforce_bounds(&self, pos: Vec2f32) -> Vec2i32 {
+        self.gridmap_enforce_bounds(self.world_to_gridmap_xy(pos))
+    }
 }
 
-fn world_to_gridmap_xy(pos: Vec2f32) -> Vec2i32 {
-    vec2f32_2_vec2i32(pos * TOWN_ZOOM as f32 + Vec2f32::new(TOWN_SIZE as f32 /2.0f32, TOWN_SIZE as f32 /2.0f32))
+struct TownTurtle {
+    pos: Vec2i32,
+    theta: f32
 }
 
-fn gridmap_enforce_bounds(v: Vec2i32) -> Vec2i32 {
-    Vec2i32::new(clamp(v.x, 0i32, TOWN_SIZE as i32), clamp(v.y, 0, TOWN_SIZE as i32))
+pub fn vec2f32_2_vec2i32(v: Vec2f32) -> Vec2i32 {
+    Vec2i32::new(v.x as i32, v.y as i32)
 }
 
-fn world_to_gridmap_xy_enforce_bounds(pos: Vec2f32) -> Vec2i32 {
-    gridmap_enforce_bounds(world_to_gridmap_xy(pos))
+pub fn vec2i32_2_vec2f32_center(v: Vec2i32) -> Vec2f32 {
+    Vec2f32::new(v.x as f32 + 0.5f32, v.y as f32 + 0.5f32)
 }
 
 fn vec2i32_distance2(a: Vec2i32, b: Vec2i32) -> i32 {
@@ -72,8 +286,8 @@ fn vec2i32_2_duple(vec: Vec2i32) -> (usize, usize) {
 }
 
 pub fn find_shortest_path(gridmap: &TownGridMap, start_point: Vec2f32, end_point: Vec2f32) -> Option<VecDeque<Vec2f32>> {
-    let start_point_grid = world_to_gridmap_xy_enforce_bounds(start_point);
-    let end_point_grid = world_to_gridmap_xy_enforce_bounds(end_point);
+    let start_point_grid = gridmap.world_to_gridmap_xy_enforce_bounds(start_point);
+    let end_point_grid = gridmap.world_to_gridmap_xy_enforce_bounds(end_point);
 
     let result = pathfinding::directed::astar::astar(&start_point_grid,
                    |&point|  {
@@ -89,77 +303,186 @@ pub fn find_shortest_path(gridmap: &TownGridMap, start_point: Vec2f32, end_point
                    |&point| point == end_point_grid);
     match result {
         Some( (points_and_dists, dist) ) => Some(points_and_dists.iter().map(| point |  {
-            gridmap_xy_to_world(*point)
+            gridmap.gridmap_xy_to_world(*point)
         }).collect()),
         None => None
     }
 }
 
-pub fn find_free_space_close_to(gridmap: &TownGridMap, query_point: Vec2f32) -> Option<Vec2f32> {
-    let query_grid_pos = world_to_gridmap_xy_enforce_bounds(query_point);
-
-    // println!("query world: {:?} query grid: {:?}", query_point, query_grid_pos);
-
-
-    let closest_option = gridmap.iter().min_by_key(| vert | {
+fn find_free_space_close_to_linear(gridmap: &TownGridMap, query_grid_pos: Vec2i32) -> Option<(usize, usize)> {
+    gridmap.iter().min_by_key(| vert | {
         let vert_pos = Vec2i32::new(vert.0 as i32, vert.1 as i32);
         vec2i32_distance2(vert_pos, query_grid_pos)
-    });
+    })
+}
 
-    if closest_option.is_some() {
-        let closest_grid_pos = closest_option.unwrap();
-        let closest_point_world = gridmap_xy_to_world(duple_to_vec2i32(closest_grid_pos));
-        // let closest_point_world = Vec2f32::new(closest_grid_pos.0 as f32 - TOWN_SIZE as f32 / 2.0f32, 
-        //                                        closest_grid_pos.1 as f32 - TOWN_SIZE as f32 / 2.0f32);
-        // println!("found world: {:?} found grid: {:?}", closest_point_world, closest_grid_pos);
-        Some(closest_point_world)
-    } else {
-        None
-    }
+fn grid_pos_to_world(gridmap: &TownGridMap, grid_pos: Option<(usize, usize)>) -> Option<Vec2f32> {
+    grid_pos.map(|grid_pos| gridmap.gridmap_xy_to_world(duple_to_vec2i32(grid_pos)))
 }
 
-pub fn make_square_town_gridmap() -> TownGridMap {
-    let mut gridmap = TownGridMap::new(TOWN_SIZE, TOWN_SIZE);
-    gridmap.enable_diagonal_mode();
+pub fn find_free_space_close_to(gridmap: &TownGridMap, query_point: Vec2f32) -> Option<Vec2f32> {
+    let query_grid_pos = gridmap.world_to_gridmap_xy_enforce_bounds(query_point);
+    let closest = match gridmap.nearest_free_index {
+        Some(ref index) => index.nearest_free(query_grid_pos.x as usize, query_grid_pos.y as usize),
+        None => find_free_space_close_to_linear(gridmap, query_grid_pos)
+    };
+    grid_pos_to_world(gridmap, closest)
+}
+
+// scans every free cell even if the gridmap has an index, to compare against it
+pub fn find_free_space_close_to_unindexed(gridmap: &TownGridMap, query_point: Vec2f32) -> Option<Vec2f32> {
+    let query_grid_pos = gridmap.world_to_gridmap_xy_enforce_bounds(query_point);
+    grid_pos_to_world(gridmap, find_free_space_close_to_linear(gridmap, query_grid_pos))
+}
 
+pub fn make_square_town_gridmap() -> TownGridMap {
+    let mut gridmap = TownGridMap::new(TownMapInfo::default());
+    let (center_x, center_y) = (gridmap.info.width as i32 / 2, gridmap.info.height as i32 / 2);
 
     for x in -0..10 {
         for y in -0..10 {
-            gridmap.add_vertex( ( (TOWN_SIZE as i32 / 2 + x) as usize, (TOWN_SIZE as i32 / 2 + y) as usize) );
+            gridmap.set_cell((center_x + x) as usize, (center_y + y) as usize, TownCell::Free);
         }
     }
 
-
+    gridmap.build_nearest_free_index();
     gridmap
 }
 
-pub fn load_town_from_file(fname: &str) -> TownGridMap {
+fn read_map_server_yaml(fname: &Path) -> Result<MapServerYaml, Box<std::error::Error>> {
+    let mut file = File::open(fname)?;
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)?;
+    let map_yaml: MapServerYaml = serde_yaml::from_str(&contents)
+        .map_err(|error| format!("{}: {}", fname.display(), error))?;
+    Ok(map_yaml)
+}
+
+// the image is expected to have a sibling yaml file with the same name (e.g. town.bmp -> town.yaml)
+// holding resolution and origin; without it the map has 1m cells and is centered on the world origin
+pub fn load_town_from_file(fname: &str) -> Result<TownGridMap, Box<std::error::Error>> {
     println!("trying to load {}", fname);
-    let img = image::open(&Path::new(fname)).ok().expect("Opening image failed");
-    let mut gridmap = TownGridMap::new(TOWN_SIZE, TOWN_SIZE);
+    let img = image::open(&Path::new(fname)).map_err(|error| format!("{}: {}", fname, error))?;
     let luma = img.to_luma();
-    for x in 0..TOWN_SIZE {
-        for y in 0..TOWN_SIZE {
-            let pixel_index = (x, TOWN_SIZE-y);
-            if gridmap.is_inside(&pixel_index) && luma.in_bounds(x as u32, y as u32) {
-                let fill_cell = luma.get_pixel(x as u32, y as u32).data[0] == 0;
-                if fill_cell {
-                    gridmap.add_vertex(pixel_index);
-                }
+    let (width, height) = (luma.width() as usize, luma.height() as usize);
+
+    let meta_fname = Path::new(fname).with_extension("yaml");
+    let info = if meta_fname.exists() {
+        let meta = read_map_server_yaml(&meta_fname)?;
+        println!("town metadata loaded from {:?}", meta_fname);
+        TownMapInfo {
+            resolution: meta.resolution,
+            origin: Vec2f64::new(meta.origin[0], meta.origin[1]),
+            width: width,
+            height: height,
+        }
+    } else {
+        TownMapInfo::centered(width, height, DEFAULT_TOWN_RESOLUTION)
+    };
+
+    let mut gridmap = TownGridMap::new(info);
+    for x in 0..width {
+        for y in 0..height {
+            let fill_cell = luma.get_pixel(x as u32, y as u32).data[0] == 0;
+            if fill_cell {
+                gridmap.set_cell(x, height - 1 - y, TownCell::Free);
             }
         }
     }
-                        // gridmap.add_vertex((mark_point.x as usize, mark_point.y as usize));
-    gridmap
+    gridmap.build_nearest_free_index();
+    Ok(gridmap)
+}
+
+const MAP_SERVER_FREE_VALUE : u8 = 254;
+const MAP_SERVER_OCCUPIED_VALUE : u8 = 0;
+const MAP_SERVER_UNKNOWN_VALUE : u8 = 205;
+const MAP_SERVER_OCCUPIED_THRESH : f64 = 0.65;
+const MAP_SERVER_FREE_THRESH : f64 = 0.196;
+
+// same thresholding as map_server in trinary mode
+pub fn map_server_cell(value: u8, negate: bool, occupied_thresh: f64, free_thresh: f64) -> TownCell {
+    let occupancy = if negate {
+        value as f64 / 255.0
+    } else {
+        (255.0 - value as f64) / 255.0
+    };
+    if occupancy > occupied_thresh {
+        TownCell::Occupied
+    } else if occupancy < free_thresh {
+        TownCell::Free
+    } else {
+        TownCell::Unknown
+    }
+}
+
+pub fn load_town_from_map_server_yaml(fname: &str) -> Result<TownGridMap, Box<std::error::Error>> {
+    println!("trying to load map_server map {}", fname);
+    let map_yaml = read_map_server_yaml(Path::new(fname))?;
+
+    // the image path is relative to the yaml file
+    let image_path = match Path::new(fname).parent() {
+        Some(dir) => dir.join(&map_yaml.image),
+        None => Path::new(&map_yaml.image).to_path_buf()
+    };
+    let luma = image::open(&image_path)?.to_luma();
+    let (width, height) = (luma.width() as usize, luma.height() as usize);
+
+    let mut gridmap = TownGridMap::new(TownMapInfo {
+        resolution: map_yaml.resolution,
+        origin: Vec2f64::new(map_yaml.origin[0], map_yaml.origin[1]),
+        width: width,
+        height: height,
+    });
+
+    for x in 0..width {
+        for y in 0..height {
+            let value = luma.get_pixel(x as u32, y as u32).data[0];
+            let cell = map_server_cell(value, map_yaml.negate != 0, map_yaml.occupied_thresh, map_yaml.free_thresh);
+            gridmap.set_cell(x, height - 1 - y, cell);
+        }
+    }
+    gridmap.build_nearest_free_index();
+    Ok(gridmap)
+}
+
+// occupancy image with map_saver pixel values
+pub fn town_gridmap_to_occupancy_image(gridmap: &TownGridMap) -> ::image::GrayImage {
+    let (width, height) = (gridmap.info.width, gridmap.info.height);
+    ImageBuffer::from_fn(width as u32, height as u32, |x, y| {
+        let value = match gridmap.cell(x as usize, height - 1 - y as usize) {
+            TownCell::Free => MAP_SERVER_FREE_VALUE,
+            TownCell::Occupied => MAP_SERVER_OCCUPIED_VALUE,
+            TownCell::Unknown => MAP_SERVER_UNKNOWN_VALUE,
+        };
+        ::image::Luma([value])
+    })
+}
+
+// writes `fname` and a pgm image next to it, like map_saver does
+pub fn save_town_to_map_server_yaml(gridmap: &TownGridMap, fname: &str) -> Result<(), Box<std::error::Error>> {
+    let image_path = Path::new(fname).with_extension("pgm");
+    town_gridmap_to_occupancy_image(gridmap).save(&image_path)?;
+
+    let map_yaml = MapServerYaml {
+        image: image_path.file_name().unwrap().to_string_lossy().into_owned(),
+        resolution: gridmap.info.resolution,
+        origin: [gridmap.info.origin.x, gridmap.info.origin.y, 0.0],
+        negate: 0,
+        occupied_thresh: MAP_SERVER_OCCUPIED_THRESH,
+        free_thresh: MAP_SERVER_FREE_THRESH,
+    };
+    let mut file = File::create(fname)?;
+    file.write_all(serde_yaml::to_string(&map_yaml)?.as_bytes())?;
+    Ok(())
 }
 
 pub fn make_random_town_gridmap(seed: u32) -> TownGridMap {
-    let mut gridmap = TownGridMap::new(TOWN_SIZE, TOWN_SIZE);
-    gridmap.enable_diagonal_mode();
+    let mut gridmap = TownGridMap::new(TownMapInfo::default());
+    let (width, height) = (gridmap.info.width as i32, gridmap.info.height as i32);
 
-    let start_point = Vec2i32::new(TOWN_SIZE as i32/ 2, TOWN_SIZE as i32/ 2);
+    let start_point = Vec2i32::new(width / 2, height / 2);
 
-    let mut rng = rand::thread_rng();
+    let mut rng : StdRng = SeedableRng::from_seed(&[seed as usize][..]);
     let mut first_turtle = TownTurtle {pos: start_point, theta: rng.gen_range(0f32,  2.0f32 * std::f32::consts::PI) };
 
     let radius = TURTLE_DRAW_RADIUS;
@@ -181,12 +504,12 @@ pub fn make_random_town_gridmap(seed: u32) -> TownGridMap {
                 for dx in -radius..radius {
                     for dy in -radius..radius {
                         let mark_point = turtle.pos + Vec2i32::new(dx, dy);
-                        if (mark_point.x <= 0 || mark_point.x >= TOWN_SIZE as i32 - 1) || 
-                            (mark_point.y <= 0 || mark_point.y >= TOWN_SIZE as i32- 1) {
+                        if (mark_point.x <= 0 || mark_point.x >= width - 1) || 
+                            (mark_point.y <= 0 || mark_point.y >= height - 1) {
                                 break 'turtle_end;
                         }
                         // gridmap[(mark_point.x as usize, mark_point.y as usize)] = 1;
-                        gridmap.add_vertex((mark_point.x as usize, mark_point.y as usize));
+                        gridmap.set_cell(mark_point.x as usize, mark_point.y as usize, TownCell::Free);
                     }
                 } 
                 if rng.gen_range(0,  255) > 250 {
@@ -208,13 +531,15 @@ pub fn make_random_town_gridmap(seed: u32) -> TownGridMap {
     }
 
 
+    gridmap.build_nearest_free_index();
     gridmap
 }
 
-fn town_gridmap_to_image(gridmap : &TownGridMap) -> ::image::RgbaImage {
-    let image = ImageBuffer::from_fn(TOWN_SIZE as u32, TOWN_SIZE as u32, |x, y| {
+pub fn town_gridmap_to_image(gridmap : &TownGridMap) -> ::image::RgbaImage {
+    let height = gridmap.info.height;
+    let image = ImageBuffer::from_fn(gridmap.info.width as u32, height as u32, |x, y| {
         // let v = *gridmap.has_vertex( &(x as usize, y as usize) ) as u8;
-        let v = gridmap.has_vertex( &(x as usize, TOWN_SIZE - 1 - y as usize) ) as u8;
+        let v = gridmap.has_vertex( &(x as usize, height - 1 - y as usize) ) as u8;
         let gray_value = 30u8;
         ::image::Rgba([gray_value, gray_value, gray_value, 255u8*v])
     });
@@ -242,18 +567,97 @@ pub struct RenderTownSys<'a> {
 
 
 impl<'a, 'b> System<'a> for RenderTownSys<'b> {
-    type SystemData = (ReadExpect<'a, Camera>);
+    type SystemData = (ReadExpect<'a, Camera>, ReadExpect<'a, TownGridMap>);
 
-    fn run(&mut self, (camera): Self::SystemData) {
+    fn run(&mut self, (camera, town_gridmap): Self::SystemData) {
         let texture_copy = self.town_gridmap_texture;
+        let info = town_gridmap.info;
 
         self.fps_window.draw_2d(self.render_event, |context, graphics| {
             let mut context = context;
             let new_trans = camera.apply(context.transform);
-            context.transform = new_trans.zoom(TOWN_ZOOM).trans(TOWN_SIZE as f64 / -2.0, TOWN_SIZE as f64 / -2.0);
+            // image row 0 is the top of the map, screen y points down
+            context.transform = new_trans.trans(info.origin.x, -info.origin.y - info.height as f64 * info.resolution)
+                .zoom(info.resolution);
             image(texture_copy, context.transform, graphics);
 
         });
 
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn map_server_thresholds() {
+        assert_eq!(TownCell::Free, map_server_cell(254, false, 0.65, 0.196));
+        assert_eq!(TownCell::Occupied, map_server_cell(0, false, 0.65, 0.196));
+        assert_eq!(TownCell::Unknown, map_server_cell(205, false, 0.65, 0.196));
+        assert_eq!(TownCell::Occupied, map_server_cell(254, true, 0.65, 0.196));
+    }
+
+    #[test]
+    fn nearest_free_index_matches_linear_search() {
+        let mut gridmap = TownGridMap::new(TownMapInfo::centered(40, 30, 1.0));
+        for (x, y) in &[(3, 4), (20, 25), (35, 2), (10, 10), (11, 10), (39, 29)] {
+            gridmap.set_cell(*x, *y, TownCell::Free);
+        }
+        gridmap.build_nearest_free_index();
+        for x in 0..40 {
+            for y in 0..30 {
+                let query = Vec2i32::new(x, y);
+                let indexed = gridmap.nearest_free_index.as_ref().unwrap().nearest_free(x as usize, y as usize).unwrap();
+                let linear = find_free_space_close_to_linear(&gridmap, query).unwrap();
+                // ties may pick different cells at the same distance
+                assert_eq!(vec2i32_distance2(query, duple_to_vec2i32(linear)), vec2i32_distance2(query, duple_to_vec2i32(indexed)));
+            }
+        }
+    }
+
+    #[test]
+    fn map_server_round_trip() {
+        let mut gridmap = TownGridMap::new(TownMapInfo {
+            resolution: 0.1,
+            origin: Vec2f64::new(-1.0, 2.0),
+            width: 20,
+            height: 10,
+        });
+        gridmap.set_cell(1, 2, TownCell::Free);
+        gridmap.set_cell(3, 4, TownCell::Unknown);
+
+        let fname = std::env::temp_dir().join(format!("roadsim2d_town_test_{}.yaml", std::process::id()));
+        save_town_to_map_server_yaml(&gridmap, fname.to_str().unwrap()).unwrap();
+        let loaded = load_town_from_map_server_yaml(fname.to_str().unwrap()).unwrap();
+
+        assert_eq!(20, loaded.info.width);
+        assert_eq!(10, loaded.info.height);
+        assert_eq!(0.1, loaded.info.resolution);
+        assert_eq!(TownCell::Free, loaded.cell(1, 2));
+        assert_eq!(TownCell::Unknown, loaded.cell(3, 4));
+        assert_eq!(TownCell::Occupied, loaded.cell(0, 0));
+    }
+
+    #[test]
+    fn town_image_metadata() {
+        let dir = std::env::temp_dir().join(format!("roadsim2d_town_image_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let image_fname = dir.join("town.png");
+        ::image::GrayImage::from_pixel(8, 4, ::image::Luma([0])).save(&image_fname).unwrap();
+
+        // no yaml next to the image
+        let loaded = load_town_from_file(image_fname.to_str().unwrap()).unwrap();
+        assert_eq!(DEFAULT_TOWN_RESOLUTION, loaded.info.resolution);
+        assert_eq!(TownCell::Free, loaded.cell(3, 2));
+
+        std::fs::write(dir.join("town.yaml"), "resolution: 0.5\norigin: [1.0, 2.0, 0.0]\n").unwrap();
+        let loaded = load_town_from_file(image_fname.to_str().unwrap()).unwrap();
+        assert_eq!(0.5, loaded.info.resolution);
+        assert_eq!(Vec2f64::new(1.0, 2.0), loaded.info.origin);
+
+        std::fs::write(dir.join("town.yaml"), "resolutoin: 0.5\norigin: [1.0, 2.0, 0.0]\n").unwrap();
+        assert!(load_town_from_file(image_fname.to_str().unwrap()).is_err());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
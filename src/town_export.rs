@@ -0,0 +1,185 @@
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::error::Error;
+
+use super::town::*;
+use super::roads::*;
+use super::primitives::*;
+
+const EXPORT_SAMPLING_STEP : f64 = 1.0;
+
+pub fn export_town_occupancy_png(gridmap: &TownGridMap, fname: &Path) -> Result<(), Box<Error>> {
+    town_gridmap_to_occupancy_image(gridmap).save(fname)?;
+    Ok(())
+}
+
+fn point_to_json(point: &Point2f64) -> serde_json::Value {
+    json!([point.x, point.y])
+}
+
+// coordinates are in the simulator world frame (meters), not WGS84
+pub fn road_network_to_geojson(roads: &[Road]) -> serde_json::Value {
+    let mut features = Vec::new();
+    for road in roads {
+        let reference_line : Vec<serde_json::Value> = road.reference_line(EXPORT_SAMPLING_STEP).iter()
+            .map(|pose| point_to_json(&pose.center)).collect();
+        features.push(json!({
+            "type": "Feature",
+            "geometry": {"type": "LineString", "coordinates": reference_line},
+            "properties": {"kind": "reference_line", "road": road.id(), "junction": road.junction()}
+        }));
+
+        for lane_id in road.lane_ids() {
+            let centreline : Vec<serde_json::Value> = road.lane_centreline(lane_id, EXPORT_SAMPLING_STEP).iter()
+                .map(point_to_json).collect();
+            features.push(json!({
+                "type": "Feature",
+                "geometry": {"type": "LineString", "coordinates": centreline},
+                "properties": {"kind": "lane_centreline", "road": road.id(), "lane": lane_id}
+            }));
+        }
+    }
+    json!({"type": "FeatureCollection", "features": features})
+}
+
+pub fn export_road_network_geojson(roads: &[Road], fname: &Path) -> Result<(), Box<Error>> {
+    let mut file = File::create(fname)?;
+    file.write_all(serde_json::to_string_pretty(&road_network_to_geojson(roads))?.as_bytes())?;
+    Ok(())
+}
+
+fn svg_polyline(points: &[Point2f64], color: &str, width: f64) -> String {
+    // svg y axis points down
+    let points_str : Vec<String> = points.iter().map(|p| format!("{:.2},{:.2}", p.x, -p.y)).collect();
+    format!("<polyline points=\"{}\" fill=\"none\" stroke=\"{}\" stroke-width=\"{}\"/>\n",
+        points_str.join(" "), color, width)
+}
+
+pub fn export_road_network_svg(roads: &[Road], info: &TownMapInfo, fname: &Path) -> Result<(), Box<Error>> {
+    let width = info.width as f64 * info.resolution;
+    let height = info.height as f64 * info.resolution;
+    let mut svg = format!("<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"{} {} {} {}\">\n",
+        info.origin.x, -info.origin.y - height, width, height);
+    for road in roads {
+        let reference_line : Vec<Point2f64> = road.reference_line(EXPORT_SAMPLING_STEP).iter()
+            .map(|pose| pose.center).collect();
+        svg.push_str(&svg_polyline(&reference_line, "black", 0.3));
+        for lane_id in road.lane_ids() {
+            let color = if lane_id > 0 { "blue" } else { "red" };
+            svg.push_str(&svg_polyline(&road.lane_centreline(lane_id, EXPORT_SAMPLING_STEP), color, 0.1));
+        }
+    }
+    svg.push_str("</svg>\n");
+
+    let mut file = File::create(fname)?;
+    file.write_all(svg.as_bytes())?;
+    Ok(())
+}
+
+pub fn export_town_seed(seed: u32, fname: &Path) -> Result<(), Box<Error>> {
+    let mut file = File::create(fname)?;
+    writeln!(file, "{}", seed)?;
+    Ok(())
+}
+
+pub fn load_town_seed(fname: &Path) -> Result<u32, Box<Error>> {
+    let mut seed = String::new();
+    File::open(fname)?.read_to_string(&mut seed)?;
+    Ok(seed.trim().parse()?)
+}
+
+// writes town.png, roads.geojson, roads.svg and seed.txt into `out_dir`
+pub fn export_town(out_dir: &Path, gridmap: &TownGridMap, roads: &[Road], seed: Option<u32>) -> Result<(), Box<Error>> {
+    std::fs::create_dir_all(out_dir)?;
+    export_town_occupancy_png(gridmap, &out_dir.join("town.png"))?;
+    export_road_network_geojson(roads, &out_dir.join("roads.geojson"))?;
+    export_road_network_svg(roads, &gridmap.info, &out_dir.join("roads.svg"))?;
+    if let Some(seed) = seed {
+        export_town_seed(seed, &out_dir.join("seed.txt"))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim_id::IdProvider;
+
+    fn test_roads() -> Vec<Road> {
+        let mut id_provider = IdProvider::new();
+        vec![
+            make_straight_road(&mut id_provider, Point2f64::new(-10.0, 0.0), Point2f64::new(10.0, 0.0), 1, 3.0),
+            make_straight_road(&mut id_provider, Point2f64::new(0.0, -10.0), Point2f64::new(0.0, 10.0), 2, 3.0),
+        ]
+    }
+
+    fn coordinates_str(points: &[Point2f64]) -> String {
+        points.iter().map(|p| format!("{:.2},{:.2}", p.x, -p.y)).collect::<Vec<String>>().join(" ")
+    }
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("roadsim2d_{}_{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn occupancy_png_matches_the_grid() {
+        let mut gridmap = TownGridMap::new(TownMapInfo::centered(12, 7, 1.0));
+        gridmap.set_cell(2, 1, TownCell::Free);
+        gridmap.set_cell(5, 6, TownCell::Unknown);
+
+        let fname = temp_path("occupancy_png_matches_the_grid.png");
+        export_town_occupancy_png(&gridmap, &fname).unwrap();
+        let image = ::image::open(&fname).unwrap().to_luma();
+
+        assert_eq!((12, 7), image.dimensions());
+        // the image rows go down, the grid rows go up
+        assert_eq!(254, image.get_pixel(2, 5).data[0]);
+        assert_eq!(205, image.get_pixel(5, 0).data[0]);
+        assert_eq!(0, image.get_pixel(0, 0).data[0]);
+    }
+
+    #[test]
+    fn geojson_has_every_lane_centreline() {
+        let roads = test_roads();
+        let geojson = road_network_to_geojson(&roads);
+        let features = geojson["features"].as_array().unwrap();
+        for road in &roads {
+            for lane_id in road.lane_ids() {
+                let expected : Vec<serde_json::Value> = road.lane_centreline(lane_id, EXPORT_SAMPLING_STEP).iter()
+                    .map(point_to_json).collect();
+                assert!(features.iter().any(|feature|
+                    feature["properties"]["kind"] == "lane_centreline" &&
+                    feature["properties"]["road"] == road.id() &&
+                    feature["properties"]["lane"] == lane_id &&
+                    feature["geometry"]["coordinates"] == json!(expected)));
+            }
+        }
+        // a reference line for every road, two lanes on the first road and four on the second
+        assert_eq!(2 + 2 + 4, features.len());
+    }
+
+    #[test]
+    fn svg_has_every_lane_centreline() {
+        let roads = test_roads();
+        let fname = temp_path("svg_has_every_lane_centreline.svg");
+        export_road_network_svg(&roads, &TownMapInfo::centered(40, 40, 1.0), &fname).unwrap();
+        let mut svg = String::new();
+        File::open(&fname).unwrap().read_to_string(&mut svg).unwrap();
+
+        for road in &roads {
+            for lane_id in road.lane_ids() {
+                let points = coordinates_str(&road.lane_centreline(lane_id, EXPORT_SAMPLING_STEP));
+                assert!(svg.contains(&format!("points=\"{}\"", points)));
+            }
+        }
+        assert_eq!(2 + 2 + 4, svg.matches("<polyline").count());
+    }
+
+    #[test]
+    fn seed_round_trip() {
+        let fname = temp_path("seed_round_trip.txt");
+        export_town_seed(4242, &fname).unwrap();
+        assert_eq!(4242, load_town_seed(&fname).unwrap());
+    }
+}
@@ -0,0 +1,407 @@
+use rand::{Rng, SeedableRng, StdRng};
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+
+use super::primitives::*;
+use super::roads::*;
+use super::junction::*;
+use super::sim_id::*;
+use super::town::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum TownLayout {
+    Grid,
+    Radial,
+    Organic,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TownGeneratorParams {
+    pub layout: TownLayout,
+    // side of the square town, in meters
+    pub size: f64,
+    pub resolution: f64,
+    pub block_size: f64,
+    pub lanes_per_direction: u32,
+    pub lane_width: f64,
+    // 0..1, how many of the possible extra road segments (and so junctions) are kept
+    pub junction_density: f64,
+}
+
+impl Default for TownGeneratorParams {
+    fn default() -> Self {
+        TownGeneratorParams {
+            layout: TownLayout::Grid,
+            size: 500.0,
+            resolution: 0.5,
+            block_size: 80.0,
+            lanes_per_direction: 1,
+            lane_width: 3.5,
+            junction_density: 0.7,
+        }
+    }
+}
+
+pub struct RoadNetwork {
+    pub roads: Vec<Road>,
+    pub junctions: Vec<Junction>,
+}
+
+impl RoadNetwork {
+    pub fn empty() -> RoadNetwork {
+        RoadNetwork {roads: Vec::new(), junctions: Vec::new()}
+    }
+}
+
+const RADIAL_SPOKES : usize = 8;
+const ORGANIC_MAX_YAW_DEVIATION : f64 = 0.3;
+const JUNCTION_MARGIN : f64 = 1.0;
+
+// abstract road layout: nodes are junctions or bends, edges are road segments
+struct RoadGraph {
+    nodes: Vec<Point2f64>,
+    edges: Vec<(usize, usize)>,
+}
+
+impl RoadGraph {
+    fn new() -> RoadGraph {
+        RoadGraph {nodes: Vec::new(), edges: Vec::new()}
+    }
+
+    fn add_node(&mut self, point: Point2f64) -> usize {
+        self.nodes.push(point);
+        self.nodes.len() - 1
+    }
+
+    fn add_edge(&mut self, a: usize, b: usize) {
+        if a != b && !self.edges.iter().any(|e| *e == (a, b) || *e == (b, a)) {
+            self.edges.push((a, b));
+        }
+    }
+
+    fn incident_edges(&self, node: usize) -> Vec<usize> {
+        self.edges.iter().enumerate()
+            .filter(|(_, (a, b))| *a == node || *b == node)
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    fn closest_node(&self, point: Point2f64, max_dist: f64) -> Option<usize> {
+        self.nodes.iter().enumerate()
+            .map(|(i, node)| (i, (node.x - point.x).hypot(node.y - point.y)))
+            .filter(|(_, dist)| *dist < max_dist)
+            .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap())
+            .map(|(i, _)| i)
+    }
+
+    // keeps a random spanning tree (so that the town stays connected) and
+    // every other edge with probability `keep_probability`
+    fn prune(&mut self, keep_probability: f64, rng: &mut StdRng) {
+        if self.nodes.is_empty() {
+            return;
+        }
+        let mut visited = vec![false; self.nodes.len()];
+        let mut tree_edges = vec![false; self.edges.len()];
+        let mut queue = VecDeque::new();
+        visited[0] = true;
+        queue.push_back(0);
+        while let Some(node) = queue.pop_front() {
+            let mut incident = self.incident_edges(node);
+            rng.shuffle(&mut incident);
+            for edge in incident {
+                let (a, b) = self.edges[edge];
+                let other = if a == node { b } else { a };
+                if !visited[other] {
+                    visited[other] = true;
+                    tree_edges[edge] = true;
+                    queue.push_back(other);
+                }
+            }
+        }
+
+        let edges = std::mem::replace(&mut self.edges, Vec::new());
+        self.edges = edges.into_iter().enumerate()
+            .filter(|(i, _)| tree_edges[*i] || rng.gen_range(0.0, 1.0) < keep_probability)
+            .map(|(_, edge)| edge)
+            .collect();
+    }
+}
+
+fn make_grid_graph(params: &TownGeneratorParams) -> RoadGraph {
+    let mut graph = RoadGraph::new();
+    let n = ((params.size / 2.0 - params.block_size / 2.0) / params.block_size).floor().max(0.0) as i32;
+    let side = (2 * n + 1) as usize;
+    for i in -n..n + 1 {
+        for j in -n..n + 1 {
+            graph.add_node(Point2f64::new(i as f64 * params.block_size, j as f64 * params.block_size));
+        }
+    }
+    for i in 0..side {
+        for j in 0..side {
+            let node = i * side + j;
+            if i + 1 < side {
+                graph.add_edge(node, node + side);
+            }
+            if j + 1 < side {
+                graph.add_edge(node, node + 1);
+            }
+        }
+    }
+    graph
+}
+
+fn make_radial_graph(params: &TownGeneratorParams) -> RoadGraph {
+    let mut graph = RoadGraph::new();
+    let center = graph.add_node(Point2f64::new(0.0, 0.0));
+    let rings = ((params.size / 2.0 - params.block_size / 2.0) / params.block_size).floor().max(1.0) as usize;
+    for ring in 0..rings {
+        let radius = (ring + 1) as f64 * params.block_size;
+        let first = graph.nodes.len();
+        for spoke in 0..RADIAL_SPOKES {
+            let yaw = spoke as f64 * 2.0 * PI / RADIAL_SPOKES as f64;
+            let node = graph.add_node(Point2f64::new(radius * yaw.cos(), radius * yaw.sin()));
+            let inner = if ring == 0 { center } else { node - RADIAL_SPOKES };
+            graph.add_edge(inner, node);
+        }
+        for spoke in 0..RADIAL_SPOKES {
+            graph.add_edge(first + spoke, first + (spoke + 1) % RADIAL_SPOKES);
+        }
+    }
+    graph
+}
+
+// L-system like growth: every tip extends by one block and may branch sideways,
+// tips reaching an existing node close a loop and stop growing
+fn make_organic_graph(params: &TownGeneratorParams, rng: &mut StdRng) -> RoadGraph {
+    let mut graph = RoadGraph::new();
+    let half_size = params.size / 2.0 - params.block_size / 2.0;
+    let max_nodes = ((params.size / params.block_size).powi(2) as usize).max(1);
+    let center = graph.add_node(Point2f64::new(0.0, 0.0));
+
+    let mut tips : VecDeque<(usize, f64)> = VecDeque::new();
+    let first_yaw = rng.gen_range(0.0, 2.0 * PI);
+    for i in 0..4 {
+        tips.push_back((center, first_yaw + i as f64 * PI / 2.0));
+    }
+
+    while let Some((node, yaw)) = tips.pop_front() {
+        if graph.nodes.len() >= max_nodes {
+            break;
+        }
+        let yaw = yaw + rng.gen_range(-ORGANIC_MAX_YAW_DEVIATION, ORGANIC_MAX_YAW_DEVIATION);
+        let from = graph.nodes[node];
+        let to = Point2f64::new(from.x + params.block_size * yaw.cos(), from.y + params.block_size * yaw.sin());
+        if to.x.abs() > half_size || to.y.abs() > half_size {
+            continue;
+        }
+
+        match graph.closest_node(to, params.block_size / 2.0) {
+            Some(existing) => graph.add_edge(node, existing),
+            None => {
+                let new_node = graph.add_node(to);
+                graph.add_edge(node, new_node);
+                tips.push_back((new_node, yaw));
+                if rng.gen_range(0.0, 1.0) < params.junction_density {
+                    let side = if rng.gen() { PI / 2.0 } else { -PI / 2.0 };
+                    tips.push_back((new_node, yaw + side));
+                }
+            }
+        }
+    }
+    graph
+}
+
+fn road_network_from_graph(graph: &RoadGraph, params: &TownGeneratorParams) -> RoadNetwork {
+    let mut id_provider = IdProvider::new();
+    let road_width = 2.0 * params.lanes_per_direction as f64 * params.lane_width;
+    let junction_radius = road_width / 2.0 + JUNCTION_MARGIN;
+
+    let mut junction_of_node = vec![None; graph.nodes.len()];
+    let mut junctions = Vec::new();
+    for (node, center) in graph.nodes.iter().enumerate() {
+        let incident = graph.incident_edges(node);
+        if incident.len() < 3 {
+            continue;
+        }
+        let arms = incident.iter().map(|edge| {
+            let (a, b) = graph.edges[*edge];
+            let other = graph.nodes[if a == node { b } else { a }];
+            JunctionArm {yaw: (other.y - center.y).atan2(other.x - center.x), sign: JunctionSign::None}
+        }).collect();
+        junction_of_node[node] = Some(junctions.len());
+        junctions.push(Junction {center: (center.x, center.y), radius: junction_radius, arms: arms});
+    }
+
+    // roads end at the border of the junctions
+    let mut roads = Vec::new();
+    for (a, b) in &graph.edges {
+        let (start, end) = (graph.nodes[*a], graph.nodes[*b]);
+        let diff = end - start;
+        let length = diff.x.hypot(diff.y);
+        let dir = diff / length;
+        let start_trim = if junction_of_node[*a].is_some() { junction_radius } else { 0.0 };
+        let end_trim = if junction_of_node[*b].is_some() { junction_radius } else { 0.0 };
+        if start_trim + end_trim >= length {
+            continue;
+        }
+        roads.push(make_straight_road(&mut id_provider, start + dir * start_trim, end - dir * end_trim,
+            params.lanes_per_direction, params.lane_width));
+    }
+
+    RoadNetwork {roads: roads, junctions: junctions}
+}
+
+fn paint_disc(gridmap: &mut TownGridMap, center: Point2f64, radius: f64) {
+    let resolution = gridmap.info.resolution as f32;
+    let radius_cells = (radius as f32 / resolution).ceil() as i32;
+    let center_cell = gridmap.world_to_gridmap_xy(Vec2f32::new(center.x as f32, center.y as f32));
+    for dx in -radius_cells..radius_cells + 1 {
+        for dy in -radius_cells..radius_cells + 1 {
+            let cell = center_cell + Vec2i32::new(dx, dy);
+            if cell.x < 0 || cell.y < 0 || cell.x >= gridmap.info.width as i32 || cell.y >= gridmap.info.height as i32 {
+                continue;
+            }
+            let cell_center = gridmap.gridmap_xy_to_world(cell);
+            let dist = (cell_center.x as f64 - center.x).hypot(cell_center.y as f64 - center.y);
+            if dist <= radius {
+                gridmap.set_cell(cell.x as usize, cell.y as usize, TownCell::Free);
+            }
+        }
+    }
+}
+
+pub fn rasterise_road_network(network: &RoadNetwork, info: TownMapInfo) -> TownGridMap {
+    let mut gridmap = TownGridMap::new(info);
+    let step = info.resolution;
+    for road in &network.roads {
+        let half_width = road.width_at(0.0) / 2.0;
+        let offset = road.center_offset_at(0.0);
+        for pose in road.reference_line(step) {
+            let center = Point2f64::new(pose.center.x - offset * pose.yaw.sin(), pose.center.y + offset * pose.yaw.cos());
+            paint_disc(&mut gridmap, center, half_width);
+        }
+    }
+    for junction in &network.junctions {
+        paint_disc(&mut gridmap, Point2f64::new(junction.center.0, junction.center.1), junction.radius);
+    }
+    gridmap.build_nearest_free_index();
+    gridmap
+}
+
+pub fn generate_town(params: &TownGeneratorParams, seed: u32) -> (RoadNetwork, TownGridMap) {
+    let mut rng : StdRng = SeedableRng::from_seed(&[seed as usize][..]);
+    let mut graph = match params.layout {
+        TownLayout::Grid => make_grid_graph(params),
+        TownLayout::Radial => make_radial_graph(params),
+        TownLayout::Organic => make_organic_graph(params, &mut rng),
+    };
+    if params.layout != TownLayout::Organic {
+        graph.prune(params.junction_density, &mut rng);
+    }
+
+    let network = road_network_from_graph(&graph, params);
+    let cells = (params.size / params.resolution).ceil() as usize;
+    let gridmap = rasterise_road_network(&network, TownMapInfo::centered(cells, cells, params.resolution));
+    (network, gridmap)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_params(layout: TownLayout) -> TownGeneratorParams {
+        TownGeneratorParams {
+            layout: layout,
+            size: 200.0,
+            resolution: 1.0,
+            block_size: 40.0,
+            .. TownGeneratorParams::default()
+        }
+    }
+
+    fn world_cell(gridmap: &TownGridMap, point: Point2f64) -> (usize, usize) {
+        let cell = gridmap.world_to_gridmap_xy(Vec2f32::new(point.x as f32, point.y as f32));
+        (cell.x as usize, cell.y as usize)
+    }
+
+    fn free_cells(gridmap: &TownGridMap) -> Vec<(usize, usize)> {
+        let mut cells = Vec::new();
+        for x in 0..gridmap.info.width {
+            for y in 0..gridmap.info.height {
+                if gridmap.cell(x, y) == TownCell::Free {
+                    cells.push((x, y));
+                }
+            }
+        }
+        cells
+    }
+
+    // free cells reachable from `start` through 4-connected free cells
+    fn flood_fill_count(gridmap: &TownGridMap, start: (usize, usize)) -> usize {
+        let (width, height) = (gridmap.info.width, gridmap.info.height);
+        let mut visited = vec![false; width * height];
+        let mut queue = VecDeque::new();
+        visited[start.1 * width + start.0] = true;
+        queue.push_back(start);
+        let mut count = 0;
+        while let Some((x, y)) = queue.pop_front() {
+            count += 1;
+            let neighbours = [(x.wrapping_sub(1), y), (x + 1, y), (x, y.wrapping_sub(1)), (x, y + 1)];
+            for &(nx, ny) in &neighbours {
+                if nx < width && ny < height && !visited[ny * width + nx] && gridmap.cell(nx, ny) == TownCell::Free {
+                    visited[ny * width + nx] = true;
+                    queue.push_back((nx, ny));
+                }
+            }
+        }
+        count
+    }
+
+    #[test]
+    fn layouts_are_connected_and_have_junctions() {
+        for layout in &[TownLayout::Grid, TownLayout::Radial, TownLayout::Organic] {
+            let (network, gridmap) = generate_town(&test_params(*layout), 7);
+            assert!(!network.roads.is_empty(), "{:?} has no roads", layout);
+            assert!(!network.junctions.is_empty(), "{:?} has no junctions", layout);
+            let free = free_cells(&gridmap);
+            assert_eq!(free.len(), flood_fill_count(&gridmap, free[0]), "{:?} is not connected", layout);
+        }
+    }
+
+    #[test]
+    fn same_seed_same_town() {
+        for layout in &[TownLayout::Grid, TownLayout::Radial, TownLayout::Organic] {
+            let params = test_params(*layout);
+            let (network_a, gridmap_a) = generate_town(&params, 11);
+            let (network_b, gridmap_b) = generate_town(&params, 11);
+            let centers = |network: &RoadNetwork| network.junctions.iter().map(|junction| junction.center).collect::<Vec<_>>();
+            assert_eq!(centers(&network_a), centers(&network_b));
+            assert_eq!(network_a.roads.len(), network_b.roads.len());
+            for (road_a, road_b) in network_a.roads.iter().zip(network_b.roads.iter()) {
+                let line = |road: &Road| road.reference_line(1.0).iter().map(|pose| (pose.center.x, pose.center.y)).collect::<Vec<_>>();
+                assert_eq!(line(road_a), line(road_b));
+            }
+            assert_eq!(free_cells(&gridmap_a), free_cells(&gridmap_b));
+        }
+    }
+
+    #[test]
+    fn rasterisation_frees_the_roads() {
+        let (network, gridmap) = generate_town(&test_params(TownLayout::Grid), 3);
+        for road in &network.roads {
+            for lane_id in road.lane_ids() {
+                for point in road.lane_centreline(lane_id, gridmap.info.resolution) {
+                    let (x, y) = world_cell(&gridmap, point);
+                    assert_eq!(TownCell::Free, gridmap.cell(x, y));
+                }
+            }
+        }
+        for junction in &network.junctions {
+            let (x, y) = world_cell(&gridmap, Point2f64::new(junction.center.0, junction.center.1));
+            assert_eq!(TownCell::Free, gridmap.cell(x, y));
+        }
+        // the corners are far from every road
+        assert_eq!(TownCell::Occupied, gridmap.cell(0, 0));
+    }
+}
@@ -0,0 +1,636 @@
+use specs::{System, ReadExpect, WriteExpect};
+use rand::{Rng, SeedableRng, StdRng};
+use piston_window::*;
+use conrod::color::*;
+use std::collections::{HashMap, HashSet, VecDeque};
+use nphysics2d::object::BodyHandle;
+use nphysics2d::math::Velocity;
+use nalgebra::{Isometry2, Vector2};
+
+use super::primitives::*;
+use super::global_resources::*;
+use super::physics::*;
+use super::town_generator::*;
+use super::camera::Camera;
+use super::car::draw_car;
+
+const LANE_SAMPLING_STEP : f64 = 2.0;
+const CONNECTOR_SAMPLES : usize = 8;
+// lane ends closer than this (plus the junction size) are connected
+const LANE_CONNECTION_MARGIN : f64 = 5.0;
+const IDM_LOOKAHEAD : f64 = 60.0;
+const IDM_DELTA : i32 = 4;
+// number of lanes chosen in advance, the leader is searched along them
+const ROUTE_LENGTH : usize = 2;
+const SPAWN_ATTEMPTS : usize = 10;
+const TRAFFIC_VEHICLE_WIDTH : f64 = 1.8;
+// traffic agents are numbered on their own, their ids are moved away from the car ids
+const TRAFFIC_FIRST_OBJECT_ID : u64 = 500_000;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TrafficParams {
+    pub vehicles: usize,
+    pub desired_speed: f64,
+    // desired speeds are drawn uniformly in desired_speed +- desired_speed_spread
+    pub desired_speed_spread: f64,
+    pub max_acceleration: f64,
+    pub comfortable_deceleration: f64,
+    pub min_gap: f64,
+    pub time_headway: f64,
+    pub vehicle_length: f64,
+}
+
+impl Default for TrafficParams {
+    fn default() -> Self {
+        TrafficParams {
+            vehicles: 500,
+            desired_speed: 12.0,
+            desired_speed_spread: 3.0,
+            max_acceleration: 1.5,
+            comfortable_deceleration: 2.0,
+            min_gap: 2.0,
+            time_headway: 1.2,
+            vehicle_length: 4.5,
+        }
+    }
+}
+
+// a lane (or a junction connector) as a polyline in driving direction
+pub struct TrafficLane {
+    pub points: Vec<Point2f64>,
+    // arc length at each point
+    pub stations: Vec<f64>,
+    pub successors: Vec<usize>,
+    // None for the connectors inside junctions
+    pub road: Option<u64>,
+}
+
+impl TrafficLane {
+    fn new(points: Vec<Point2f64>, road: Option<u64>) -> TrafficLane {
+        let mut stations = Vec::with_capacity(points.len());
+        let mut length = 0.0;
+        for (i, point) in points.iter().enumerate() {
+            if i > 0 {
+                length += (point.x - points[i - 1].x).hypot(point.y - points[i - 1].y);
+            }
+            stations.push(length);
+        }
+        TrafficLane {points: points, stations: stations, successors: Vec::new(), road: road}
+    }
+
+    pub fn length(&self) -> f64 {
+        *self.stations.last().unwrap_or(&0.0)
+    }
+
+    pub fn pose_at(&self, s: f64) -> Pose2DF64 {
+        let last = self.points.len() - 1;
+        let i = match self.stations.binary_search_by(|station| station.partial_cmp(&s).unwrap()) {
+            Ok(i) => i,
+            Err(i) => i.max(1) - 1
+        }.min(last - 1);
+        let (a, b) = (self.points[i], self.points[i + 1]);
+        let segment_length = self.stations[i + 1] - self.stations[i];
+        let t = if segment_length > 0.0 { ((s - self.stations[i]) / segment_length).max(0.0).min(1.0) } else { 0.0 };
+        Pose2DF64 {
+            center: Point2f64::new(a.x + (b.x - a.x) * t, a.y + (b.y - a.y) * t),
+            yaw: (b.y - a.y).atan2(b.x - a.x)
+        }
+    }
+
+    fn start_yaw(&self) -> f64 {
+        self.pose_at(0.0).yaw
+    }
+
+    fn end_yaw(&self) -> f64 {
+        self.pose_at(self.length()).yaw
+    }
+}
+
+pub struct LaneGraph {
+    pub lanes: Vec<TrafficLane>,
+}
+
+// quadratic bezier from `start` to `end`, the control point lies on the start heading
+fn make_connector(start: Point2f64, start_yaw: f64, end: Point2f64) -> Vec<Point2f64> {
+    let distance = (end.x - start.x).hypot(end.y - start.y);
+    let control = Point2f64::new(start.x + start_yaw.cos() * distance / 2.0, start.y + start_yaw.sin() * distance / 2.0);
+    (0..CONNECTOR_SAMPLES + 1).map(|i| {
+        let t = i as f64 / CONNECTOR_SAMPLES as f64;
+        let (k0, k1, k2) = ((1.0 - t) * (1.0 - t), 2.0 * (1.0 - t) * t, t * t);
+        Point2f64::new(k0 * start.x + k1 * control.x + k2 * end.x, k0 * start.y + k1 * control.y + k2 * end.y)
+    }).collect()
+}
+
+impl LaneGraph {
+    // right lanes drive along the road reference line, left lanes against it.
+    // Lane ends close to each other are joined with connectors, u-turns are only
+    // used at dead ends
+    pub fn from_road_network(network: &RoadNetwork) -> LaneGraph {
+        let mut lanes = Vec::new();
+        for road in &network.roads {
+            for lane_id in road.lane_ids() {
+                let mut points = road.lane_centreline(lane_id, LANE_SAMPLING_STEP);
+                if lane_id > 0 {
+                    points.reverse();
+                }
+                if points.len() >= 2 {
+                    lanes.push(TrafficLane::new(points, Some(road.id())));
+                }
+            }
+        }
+
+        let max_connection_distance = network.junctions.iter()
+            .map(|junction| 2.0 * junction.radius)
+            .fold(0.0, f64::max) + LANE_CONNECTION_MARGIN;
+
+        let road_lanes = lanes.len();
+        for from in 0..road_lanes {
+            let end = *lanes[from].points.last().unwrap();
+            let reachable : Vec<usize> = (0..road_lanes).filter(|to| {
+                let start = lanes[*to].points[0];
+                *to != from && (start.x - end.x).hypot(start.y - end.y) < max_connection_distance
+            }).collect();
+            let turns : Vec<usize> = reachable.iter().cloned()
+                .filter(|to| lanes[*to].road != lanes[from].road).collect();
+            let targets = if turns.is_empty() { reachable } else { turns };
+
+            for to in targets {
+                let connector = make_connector(end, lanes[from].end_yaw(), lanes[to].points[0]);
+                let mut connector = TrafficLane::new(connector, None);
+                connector.successors.push(to);
+                lanes.push(connector);
+                let connector_index = lanes.len() - 1;
+                lanes[from].successors.push(connector_index);
+            }
+        }
+
+        LaneGraph {lanes: lanes}
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lanes.is_empty()
+    }
+}
+
+// uniform grid of buckets for neighbour queries
+pub struct SpatialHash {
+    cell_size: f64,
+    cells: HashMap<(i32, i32), Vec<usize>>,
+}
+
+impl SpatialHash {
+    pub fn new(cell_size: f64) -> SpatialHash {
+        SpatialHash {cell_size: cell_size, cells: HashMap::new()}
+    }
+
+    fn cell_of(&self, point: Point2f64) -> (i32, i32) {
+        ((point.x / self.cell_size).floor() as i32, (point.y / self.cell_size).floor() as i32)
+    }
+
+    // keeps the buckets allocated, the same cells are reused at every step
+    pub fn clear(&mut self) {
+        for bucket in self.cells.values_mut() {
+            bucket.clear();
+        }
+    }
+
+    pub fn insert(&mut self, point: Point2f64, item: usize) {
+        let cell = self.cell_of(point);
+        self.cells.entry(cell).or_insert_with(Vec::new).push(item);
+    }
+
+    // items in the cells overlapping the square of half side `radius`, may contain items farther than `radius`
+    pub fn query(&self, point: Point2f64, radius: f64, result: &mut Vec<usize>) {
+        result.clear();
+        let min = self.cell_of(Point2f64::new(point.x - radius, point.y - radius));
+        let max = self.cell_of(Point2f64::new(point.x + radius, point.y + radius));
+        for x in min.0..max.0 + 1 {
+            for y in min.1..max.1 + 1 {
+                if let Some(bucket) = self.cells.get(&(x, y)) {
+                    result.extend_from_slice(bucket);
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TrafficAgent {
+    pub id: u64,
+    pub lane: usize,
+    pub s: f64,
+    pub speed: f64,
+    pub desired_speed: f64,
+    pub route: VecDeque<usize>,
+}
+
+// background vehicles moved kinematically along the lane graph with the
+// intelligent driver model, they do not see the crossing traffic inside junctions.
+// Each agent has a kinematic body following it, seen by the sensors and hit by the cars
+pub struct TrafficState {
+    pub lane_graph: LaneGraph,
+    pub agents: Vec<TrafficAgent>,
+    proxies: HashMap<u64, BodyHandle>,
+    params: TrafficParams,
+    spatial_hash: SpatialHash,
+    rng: StdRng,
+    seed: u32,
+    next_agent_id: u64,
+}
+
+fn extend_route(lane_graph: &LaneGraph, agent: &mut TrafficAgent, rng: &mut StdRng) {
+    while agent.route.len() < ROUTE_LENGTH {
+        let last = *agent.route.back().unwrap_or(&agent.lane);
+        let successors = &lane_graph.lanes[last].successors;
+        if successors.is_empty() {
+            break;
+        }
+        agent.route.push_back(*rng.choose(successors).unwrap());
+    }
+}
+
+impl TrafficState {
+    pub fn new(lane_graph: LaneGraph, params: TrafficParams, seed: u32) -> TrafficState {
+        let mut traffic = TrafficState {
+            lane_graph: lane_graph,
+            agents: Vec::new(),
+            proxies: HashMap::new(),
+            params: params,
+            spatial_hash: SpatialHash::new(IDM_LOOKAHEAD),
+            rng: SeedableRng::from_seed(&[seed as usize][..]),
+            seed: seed,
+            next_agent_id: 0,
+        };
+        traffic.reset();
+        traffic
+    }
+
+    // replaces the agents with the ones spawned by `new` for the same seed
+    pub fn reset(&mut self) {
+        self.agents.clear();
+        self.spatial_hash = SpatialHash::new(IDM_LOOKAHEAD);
+        self.rng = SeedableRng::from_seed(&[self.seed as usize][..]);
+        self.next_agent_id = 0;
+        if !self.lane_graph.is_empty() {
+            for _ in 0..self.params.vehicles {
+                self.spawn_agent();
+            }
+        }
+    }
+
+    pub fn empty() -> TrafficState {
+        TrafficState::new(LaneGraph {lanes: Vec::new()}, TrafficParams {vehicles: 0, ..TrafficParams::default()}, 0)
+    }
+
+    fn random_road_lane(&mut self) -> Option<usize> {
+        let road_lanes : Vec<usize> = (0..self.lane_graph.lanes.len())
+            .filter(|lane| self.lane_graph.lanes[*lane].road.is_some())
+            .collect();
+        self.rng.choose(&road_lanes).cloned()
+    }
+
+    // places a new agent on a random lane, away from the agents already there
+    pub fn spawn_agent(&mut self) -> bool {
+        for _ in 0..SPAWN_ATTEMPTS {
+            let lane = match self.random_road_lane() {
+                Some(lane) => lane,
+                None => return false
+            };
+            let s = self.rng.gen_range(0.0, self.lane_graph.lanes[lane].length().max(0.1));
+            let min_distance = self.params.vehicle_length + self.params.min_gap;
+            let free = !self.agents.iter().any(|agent| agent.lane == lane && (agent.s - s).abs() < min_distance);
+            if !free {
+                continue;
+            }
+
+            let spread = self.params.desired_speed_spread;
+            let desired_speed = self.params.desired_speed + self.rng.gen_range(-spread, spread + 1e-6);
+            let mut agent = TrafficAgent {
+                id: self.next_agent_id,
+                lane: lane,
+                s: s,
+                speed: desired_speed / 2.0,
+                desired_speed: desired_speed.max(0.1),
+                route: VecDeque::new(),
+            };
+            extend_route(&self.lane_graph, &mut agent, &mut self.rng);
+            self.next_agent_id += 1;
+            self.agents.push(agent);
+            return true;
+        }
+        false
+    }
+
+    pub fn agent_pose(&self, agent: &TrafficAgent) -> Pose2DF64 {
+        self.lane_graph.lanes[agent.lane].pose_at(agent.s)
+    }
+
+    pub fn vehicle_size(&self) -> Size2f64 {
+        Size2f64::new(TRAFFIC_VEHICLE_WIDTH, self.params.vehicle_length)
+    }
+
+    pub fn agent_velocity(&self, agent: &TrafficAgent) -> Vec2f64 {
+        let yaw = self.agent_pose(agent).yaw;
+        Vec2f64::new(agent.speed * yaw.cos(), agent.speed * yaw.sin())
+    }
+
+    pub fn object_id(&self, agent: &TrafficAgent) -> u64 {
+        TRAFFIC_FIRST_OBJECT_ID + agent.id
+    }
+
+    pub fn proxy_body(&self, agent: &TrafficAgent) -> Option<BodyHandle> {
+        self.proxies.get(&agent.id).cloned()
+    }
+
+    // moves the bodies to the agents, adding bodies for the new agents and removing the ones left behind
+    pub fn update_proxies(&mut self, physics_world: &mut PhysicsWorld) {
+        let alive : HashSet<u64> = self.agents.iter().map(|agent| agent.id).collect();
+        let removed : Vec<BodyHandle> = self.proxies.iter()
+            .filter(|(id, _)| !alive.contains(id))
+            .map(|(_, body)| *body)
+            .collect();
+        if !removed.is_empty() {
+            physics_world.remove_bodies(&removed);
+            self.proxies.retain(|id, _| alive.contains(id));
+        }
+
+        let size = self.vehicle_size();
+        for agent in &self.agents {
+            let pose = self.lane_graph.lanes[agent.lane].pose_at(agent.s);
+            let body = *self.proxies.entry(agent.id).or_insert_with(|| make_kinematic_body(physics_world, size));
+            let rigid_body = physics_world.rigid_body_mut(body).expect("traffic rigid body not found");
+            rigid_body.set_position(Isometry2::new(Vector2::new(pose.center.x, pose.center.y), pose.yaw));
+            rigid_body.set_velocity(Velocity::linear(agent.speed * pose.yaw.cos(), agent.speed * pose.yaw.sin()));
+        }
+    }
+
+    // gap and speed of the closest agent ahead along the lane and the route
+    fn find_leader(&self, index: usize, candidates: &[usize]) -> Option<(f64, f64)> {
+        let agent = &self.agents[index];
+        let to_lane_end = self.lane_graph.lanes[agent.lane].length() - agent.s;
+        let mut leader : Option<(f64, f64)> = None;
+        for &other_index in candidates {
+            if other_index == index {
+                continue;
+            }
+            let other = &self.agents[other_index];
+            let mut distance = None;
+            if other.lane == agent.lane {
+                if other.s > agent.s {
+                    distance = Some(other.s - agent.s);
+                }
+            } else {
+                let mut offset = to_lane_end;
+                for lane in &agent.route {
+                    if other.lane == *lane {
+                        distance = Some(offset + other.s);
+                        break;
+                    }
+                    offset += self.lane_graph.lanes[*lane].length();
+                }
+            }
+            if let Some(distance) = distance {
+                let gap = distance - self.params.vehicle_length;
+                if leader.map_or(true, |(leader_gap, _)| gap < leader_gap) {
+                    leader = Some((gap, other.speed));
+                }
+            }
+        }
+        leader
+    }
+
+    fn idm_acceleration(&self, agent: &TrafficAgent, leader: Option<(f64, f64)>) -> f64 {
+        let params = &self.params;
+        let free_road = 1.0 - (agent.speed / agent.desired_speed).powi(IDM_DELTA);
+        let interaction = match leader {
+            Some((gap, leader_speed)) => {
+                let dv = agent.speed - leader_speed;
+                let desired_gap = params.min_gap + agent.speed * params.time_headway +
+                    agent.speed * dv / (2.0 * (params.max_acceleration * params.comfortable_deceleration).sqrt());
+                (desired_gap.max(0.0) / gap.max(0.1)).powi(2)
+            },
+            None => 0.0
+        };
+        params.max_acceleration * (free_road - interaction)
+    }
+
+    pub fn step(&mut self, dt: f64) {
+        self.spatial_hash.clear();
+        for (i, agent) in self.agents.iter().enumerate() {
+            let position = self.lane_graph.lanes[agent.lane].pose_at(agent.s).center;
+            self.spatial_hash.insert(position, i);
+        }
+
+        let mut candidates = Vec::new();
+        let accelerations : Vec<f64> = (0..self.agents.len()).map(|i| {
+            let agent = &self.agents[i];
+            let position = self.lane_graph.lanes[agent.lane].pose_at(agent.s).center;
+            self.spatial_hash.query(position, IDM_LOOKAHEAD, &mut candidates);
+            self.idm_acceleration(agent, self.find_leader(i, &candidates))
+        }).collect();
+
+        let lane_graph = &self.lane_graph;
+        let rng = &mut self.rng;
+        let mut dead_ends = Vec::new();
+        for (i, (agent, acceleration)) in self.agents.iter_mut().zip(accelerations).enumerate() {
+            agent.speed = (agent.speed + acceleration * dt).max(0.0);
+            agent.s += agent.speed * dt;
+            while agent.s > lane_graph.lanes[agent.lane].length() {
+                agent.s -= lane_graph.lanes[agent.lane].length();
+                match agent.route.pop_front() {
+                    Some(next) => agent.lane = next,
+                    None => {
+                        dead_ends.push(i);
+                        break;
+                    }
+                }
+                extend_route(lane_graph, agent, rng);
+            }
+        }
+
+        // agents reaching a lane without successors are moved elsewhere
+        for i in dead_ends.into_iter().rev() {
+            self.agents.swap_remove(i);
+            self.spawn_agent();
+        }
+    }
+}
+
+pub struct TrafficSys;
+
+impl <'a> System<'a> for TrafficSys {
+    type SystemData = (
+        ReadExpect<'a, UpdateDeltaTime>,
+        WriteExpect<'a, PhysicsWorld>,
+        WriteExpect<'a, TrafficState>,
+    );
+
+    fn run(&mut self, (update_delta_time, mut physics_world, mut traffic): Self::SystemData) {
+        traffic.step(update_delta_time.dt);
+        traffic.update_proxies(&mut physics_world);
+    }
+}
+
+pub struct RenderTrafficSys<'a> {
+    pub fps_window: &'a mut PistonWindow,
+    pub render_event: &'a Event,
+    pub render_args:  RenderArgs,
+}
+
+impl<'a, 'b> System<'a> for RenderTrafficSys<'b> {
+    type SystemData = (ReadExpect<'a, TrafficState>, ReadExpect<'a, Camera>);
+
+    fn run(&mut self, (traffic, camera): Self::SystemData) {
+        if traffic.agents.is_empty() {
+            return;
+        }
+        let size = traffic.vehicle_size();
+        let color = rgb(0.5, 0.5, 0.5);
+        self.fps_window.draw_2d(self.render_event, |context, graphics| {
+            let mut context = context;
+            context.transform = camera.apply(context.transform);
+            for agent in &traffic.agents {
+                let pose = traffic.agent_pose(agent);
+                draw_car(context, graphics, pose.center, pose.yaw, size, 0.0, color, size.height * 0.6);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::junction::*;
+    use crate::roads::make_straight_road;
+    use crate::sim_id::IdProvider;
+    use std::collections::HashSet;
+
+    fn agent(id: u64, s: f64, speed: f64, desired_speed: f64) -> TrafficAgent {
+        TrafficAgent {id: id, lane: 0, s: s, speed: speed, desired_speed: desired_speed, route: VecDeque::new()}
+    }
+
+    // one long straight lane, agents are added by the tests
+    fn straight_lane_traffic() -> TrafficState {
+        let lane = TrafficLane::new(vec![Point2f64::new(0.0, 0.0), Point2f64::new(2000.0, 0.0)], Some(0));
+        TrafficState::new(LaneGraph {lanes: vec![lane]}, TrafficParams {vehicles: 0, ..TrafficParams::default()}, 0)
+    }
+
+    fn gap(traffic: &TrafficState) -> f64 {
+        traffic.agents[1].s - traffic.agents[0].s - traffic.params.vehicle_length
+    }
+
+    #[test]
+    fn idm_keeps_the_min_gap_at_rest() {
+        let traffic = straight_lane_traffic();
+        let stopped = agent(0, 0.0, 0.0, 12.0);
+        let min_gap = traffic.params.min_gap;
+        assert!(traffic.idm_acceleration(&stopped, Some((min_gap, 0.0))).abs() < 1e-9);
+        assert!(traffic.idm_acceleration(&stopped, Some((min_gap / 2.0, 0.0))) < 0.0);
+        assert_eq!(traffic.params.max_acceleration, traffic.idm_acceleration(&stopped, None));
+    }
+
+    #[test]
+    fn idm_stops_behind_a_stopped_leader() {
+        let mut traffic = straight_lane_traffic();
+        traffic.agents.push(agent(0, 0.0, 10.0, 12.0));
+        traffic.agents.push(agent(1, 150.0, 0.0, 0.1));
+        for _ in 0..600 {
+            traffic.step(0.1);
+            assert!(gap(&traffic) > 0.0);
+        }
+        assert!(traffic.agents[0].speed < 0.5);
+        assert!(gap(&traffic) < 2.0 * traffic.params.min_gap + 1.0);
+    }
+
+    #[test]
+    fn idm_follows_a_slower_leader() {
+        let mut traffic = straight_lane_traffic();
+        traffic.agents.push(agent(0, 0.0, 15.0, 15.0));
+        traffic.agents.push(agent(1, 100.0, 8.0, 8.0));
+        for _ in 0..1000 {
+            traffic.step(0.1);
+        }
+        let params = &traffic.params;
+        let equilibrium_gap = (params.min_gap + 8.0 * params.time_headway) / (1.0 - (8.0f64 / 15.0).powi(IDM_DELTA)).sqrt();
+        assert!((traffic.agents[0].speed - 8.0).abs() < 0.1);
+        assert!((gap(&traffic) - equilibrium_gap).abs() < 0.5);
+    }
+
+    // four roads leaving a junction at the origin, the outer ends are dead ends
+    fn crossing() -> RoadNetwork {
+        let mut id_provider = IdProvider::new();
+        let radius = 4.5;
+        let roads = (0..4).map(|i| {
+            let yaw = i as f64 * std::f64::consts::PI / 2.0;
+            make_straight_road(&mut id_provider,
+                Point2f64::new(radius * yaw.cos(), radius * yaw.sin()),
+                Point2f64::new(50.0 * yaw.cos(), 50.0 * yaw.sin()), 1, 3.5)
+        }).collect();
+        let arms = (0..4).map(|i| JunctionArm {yaw: i as f64 * std::f64::consts::PI / 2.0, sign: JunctionSign::None}).collect();
+        RoadNetwork {roads: roads, junctions: vec![Junction {center: (0.0, 0.0), radius: radius, arms: arms}]}
+    }
+
+    #[test]
+    fn lane_graph_of_a_crossing() {
+        let lane_graph = LaneGraph::from_road_network(&crossing());
+        let road_lanes : Vec<usize> = (0..lane_graph.lanes.len()).filter(|lane| lane_graph.lanes[*lane].road.is_some()).collect();
+        assert_eq!(8, road_lanes.len());
+
+        for &lane in &road_lanes {
+            let successors = &lane_graph.lanes[lane].successors;
+            // the lanes towards the junction turn into the three other roads, the others make a u-turn
+            let leaving = successors.iter()
+                .map(|connector| lane_graph.lanes[*connector].successors[0])
+                .map(|next| lane_graph.lanes[next].road)
+                .collect::<Vec<Option<u64>>>();
+            match successors.len() {
+                3 => assert!(leaving.iter().all(|road| *road != lane_graph.lanes[lane].road)),
+                1 => assert_eq!(lane_graph.lanes[lane].road, leaving[0]),
+                count => panic!("lane {} has {} successors", lane, count)
+            }
+        }
+
+        // every lane can be reached from every other one
+        for &start in &road_lanes {
+            let mut reached = HashSet::new();
+            let mut queue = VecDeque::new();
+            queue.push_back(start);
+            while let Some(lane) = queue.pop_front() {
+                for &next in &lane_graph.lanes[lane].successors {
+                    if reached.insert(next) {
+                        queue.push_back(next);
+                    }
+                }
+            }
+            assert!(road_lanes.iter().all(|lane| reached.contains(lane)));
+        }
+    }
+
+    #[test]
+    fn spatial_hash_queries_across_cell_borders() {
+        let mut spatial_hash = SpatialHash::new(10.0);
+        spatial_hash.insert(Point2f64::new(9.9, 0.5), 0);
+        spatial_hash.insert(Point2f64::new(10.1, 0.5), 1);
+        spatial_hash.insert(Point2f64::new(-0.1, -0.1), 2);
+        spatial_hash.insert(Point2f64::new(35.0, 0.5), 3);
+
+        let mut result = Vec::new();
+        spatial_hash.query(Point2f64::new(10.0, 0.5), 1.0, &mut result);
+        result.sort();
+        assert_eq!(vec![0, 1], result);
+
+        spatial_hash.query(Point2f64::new(0.05, 0.05), 0.5, &mut result);
+        result.sort();
+        assert_eq!(vec![0, 2], result);
+
+        // the cells from x = 10 to x = 30, (9.9, 0.5) is just outside
+        spatial_hash.query(Point2f64::new(20.0, 0.5), 10.0, &mut result);
+        result.sort();
+        assert_eq!(vec![1, 3], result);
+
+        spatial_hash.clear();
+        spatial_hash.query(Point2f64::new(10.0, 0.5), 1.0, &mut result);
+        assert!(result.is_empty());
+    }
+}
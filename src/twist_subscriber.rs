@@ -1,5 +1,6 @@
 use super::msg;
-use rosrust::api::raii::Publisher;
+use super::protagonist::*;
+use super::protagonist_commands::*;
 use rosrust::api::raii::Subscriber;
 
 pub struct TwistSubscriber {
@@ -9,9 +10,9 @@ pub struct TwistSubscriber {
 
 impl TwistSubscriber {
 
-    pub fn new<F>(mut callback:  F) -> Option<TwistSubscriber> where F : Fn(f64, f64) -> () + Send + 'static {
+    pub fn new(commands: ProtagonistCommandHandle) -> Option<TwistSubscriber> {
         let twist_sub = rosrust::subscribe("roadsim2d/protagonist_twist", move |v: msg::geometry_msgs::Twist| {
-            callback(v.linear.x as f64, v.angular.z as f64)
+            commands.lock().unwrap().set(ProtagonistCommand::Twist(Twist2D {x: v.linear.x as f64, y: 0.0, z_rot: v.angular.z as f64}));
         });
         if twist_sub.is_ok() {
             Some(TwistSubscriber {
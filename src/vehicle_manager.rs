@@ -14,6 +14,7 @@ use super::primitives::*;
 use super::car_controller::*;
 use super::car_hl_controller::*;
 use super::town::*;
+use super::junction::*;
 use std::collections::HashSet;
 use std::rc::Rc;
 use std::cell::RefCell;
@@ -71,6 +72,16 @@ pub struct VehicleManager {
 //     }
 // }
 
+pub fn protagonist_car(id: u64) -> Car {
+    Car {
+        id: id,
+        wheel_yaw: 0.0,
+        wheel_base: 2.5,
+        bb_size: Size2f64::new(1.5, 3.0),
+        color: rgb(1.0, 0.0, 1.0),
+    }
+}
+
 impl VehicleManager {
     pub fn get_non_playable_vehicles(&self) -> &Vec<Car> {
         &self.non_playable_vehicles
@@ -81,13 +92,7 @@ impl VehicleManager {
     // }
 
     pub fn make_protagonist_car(&mut self) -> Car {
-        Car {
-            id: self.id_provider.borrow_mut().next(),
-            wheel_yaw: 0.0,
-            wheel_base: 2.5,
-            bb_size: Size2f64::new(1.5, 3.0),
-            color: rgb(1.0, 0.0, 1.0),
-        }
+        protagonist_car(self.id_provider.borrow_mut().next())
     }
 
     pub fn new(mut id_provider: Rc<RefCell<IdProvider>>) -> VehicleManager {
@@ -151,15 +156,16 @@ mod tests {
 
 
 
-pub struct SpawnNewCarSys<'a> {
-    pub vehicle_mgr: &'a mut VehicleManager,
-    pub physics_world: &'a mut PWorld<f64>
+// the vehicle manager shares the id provider through an Rc, the system has to be run as thread local
+pub struct SpawnNewCarSys {
+    pub vehicle_mgr: VehicleManager,
 }
 
 
-impl <'a, 'b> System<'a> for SpawnNewCarSys<'b> {
+impl <'a> System<'a> for SpawnNewCarSys {
     type SystemData = (
         Entities<'a>,
+        WriteExpect<'a, PhysicsWorld>,
         WriteExpect<'a, InputState>,
         WriteStorage<'a, Car>,
         WriteStorage<'a, Node>,
@@ -171,7 +177,7 @@ impl <'a, 'b> System<'a> for SpawnNewCarSys<'b> {
         Read<'a, LazyUpdate>
     );
 
-    fn run(&mut self, (entities, mut input_state, mut cars, mut nodes, mut physics_components, 
+    fn run(&mut self, (entities, mut physics_world, mut input_state, mut cars, mut nodes, mut physics_components, 
                              mut car_controllers, mut car_hl_controller_states,
                              protagonist_tags, town_gridmap, updater): Self::SystemData) {
 
@@ -210,9 +216,9 @@ impl <'a, 'b> System<'a> for SpawnNewCarSys<'b> {
 
                 let new_node = Node { pose: new_car_pose };
 
-                let new_physics = make_physics_for_car(&mut self.physics_world, &new_car, &new_node.pose);
+                let new_physics = make_physics_for_car(&mut physics_world, &new_car, &new_node.pose);
 
-                let mut rigid_body = self.physics_world.rigid_body_mut(new_physics.body_handle).expect("protagonist rigid body not found");
+                let mut rigid_body = physics_world.rigid_body_mut(new_physics.body_handle).expect("protagonist rigid body not found");
 
 
                 let mut car_high_level_controller_state = CarHighLevelControllerState::new();
@@ -244,6 +250,10 @@ impl <'a, 'b> System<'a> for SpawnNewCarSys<'b> {
                     new_entity,
                     car_path_controller_state
                 );
+                updater.insert(
+                    new_entity,
+                    CarJunctionState::new()
+                );
 
             }
 
@@ -282,9 +292,9 @@ impl <'a, 'b> System<'a> for SpawnNewCarSys<'b> {
 
                 let new_node = Node { pose: new_car_pose };
 
-                let new_physics = make_physics_for_car(&mut self.physics_world, &new_car, &new_node.pose);
+                let new_physics = make_physics_for_car(&mut physics_world, &new_car, &new_node.pose);
 
-                let mut rigid_body = self.physics_world.rigid_body_mut(new_physics.body_handle).expect("protagonist rigid body not found");
+                let mut rigid_body = physics_world.rigid_body_mut(new_physics.body_handle).expect("protagonist rigid body not found");
 
 
                 let mut car_high_level_controller_state = CarHighLevelControllerState::new();
@@ -316,6 +326,10 @@ impl <'a, 'b> System<'a> for SpawnNewCarSys<'b> {
                     new_entity,
                     car_path_controller_state
                 );
+                updater.insert(
+                    new_entity,
+                    CarJunctionState::new()
+                );
 
             }
 
@@ -354,9 +368,9 @@ impl <'a, 'b> System<'a> for SpawnNewCarSys<'b> {
 
                 let new_node = Node { pose: new_car_pose };
 
-                let new_physics = make_physics_for_car(&mut self.physics_world, &new_car, &new_node.pose);
+                let new_physics = make_physics_for_car(&mut physics_world, &new_car, &new_node.pose);
 
-                let mut rigid_body = self.physics_world.rigid_body_mut(new_physics.body_handle).expect("protagonist rigid body not found");
+                let mut rigid_body = physics_world.rigid_body_mut(new_physics.body_handle).expect("protagonist rigid body not found");
 
 
                 let mut car_high_level_controller_state = CarHighLevelControllerState::new();
@@ -388,6 +402,10 @@ impl <'a, 'b> System<'a> for SpawnNewCarSys<'b> {
                     new_entity,
                     car_path_controller_state
                 );
+                updater.insert(
+                    new_entity,
+                    CarJunctionState::new()
+                );
 
             }
 